@@ -152,8 +152,8 @@ mod tests {
         let post = "super post!";
         let captcha_file = get_captcha_file();
         let captcha = read_captcha(CAPTCHA_OFFSET, captcha_file);
-        let signature = captcha.try_sign(CAPTCHA_ANSWER, &post).unwrap();
-        let verification = captcha.signature_correct(&post, &signature);
+        let signature = captcha.try_sign(CAPTCHA_ANSWER, post).unwrap();
+        let verification = captcha.signature_correct(post, &signature);
 
         assert!(verification.is_ok())
     }
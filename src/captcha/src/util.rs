@@ -35,6 +35,6 @@ mod tests {
         let expected_result: [u8; 4] = [1, 254, 78, 93];
         let input = "01fe4e5d";
 
-        assert_eq!(hex_string_to_byte_array(&input).unwrap(), expected_result)
+        assert_eq!(hex_string_to_byte_array(input).unwrap(), expected_result)
     }
 }
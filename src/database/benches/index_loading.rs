@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use database::legacy_database::{
+    chunk::chunk_index_to_name,
+    config::IndexFormat,
+    index::{
+        db_post_ref::DbPostRef,
+        diff::{Diff, DiffResult},
+        serialized::{DbPostRefSerialized, IndexCollection, PostHashes},
+        DbRefCollection,
+    },
+};
+
+/// Posts in the synthetic index
+const POSTS: u64 = 1_000_000;
+
+/// Replies in every thread, including the thread itself
+const THREAD_LENGTH: u64 = 50;
+
+/// Every n-th post is deleted
+const DELETED_EVERY: u64 = 20;
+
+const CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Diff which holds nothing, so only the index is measured
+struct NoDiff;
+
+impl Diff for NoDiff {
+    fn append(&mut self, _hashes: &PostHashes, _db_ref: &DbPostRef) -> DiffResult<()> {
+        Ok(())
+    }
+
+    fn append_batch(&mut self, _refs: &[DbPostRefSerialized]) -> DiffResult<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> DiffResult<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> DiffResult<u64> {
+        Ok(0)
+    }
+
+    fn drain() -> DiffResult<(Self, Vec<DbPostRefSerialized>)> {
+        Ok((NoDiff, Vec::new()))
+    }
+}
+
+/// Hex hash looking like a digest, spread over the whole range the same way
+fn hash(i: u64) -> String {
+    let mixed = i.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    format!("{:016x}{:016x}", mixed, mixed.rotate_left(29) ^ i)
+}
+
+/// Threads of the same length under a few categories, with messages of 100 to 1100 bytes appended one after another
+fn synthetic_index() -> IndexCollection {
+    let mut offset = 0;
+    let indexes = (0..POSTS)
+        .map(|i| {
+            let position = i % THREAD_LENGTH;
+            let reply_to = match position {
+                0 => hash(POSTS + i % 8),
+                _ => hash(i - 1 - (i * 7) % position),
+            };
+            let length = 100 + (i * 31) % 1000;
+            let chunk_index = offset / CHUNK_SIZE;
+            let db_ref = DbPostRefSerialized {
+                hash: hash(i),
+                reply_to,
+                offset: offset % CHUNK_SIZE,
+                length,
+                deleted: i % DELETED_EVERY == 0,
+                chunk_name: Some(chunk_index_to_name(chunk_index)),
+            };
+            offset += length;
+            db_ref
+        })
+        .collect();
+
+    IndexCollection { indexes }
+}
+
+fn index_loading(c: &mut Criterion) {
+    let json = serde_json::to_vec(&synthetic_index()).unwrap();
+    let collection: DbRefCollection<NoDiff> =
+        DbRefCollection::from_reader(json.as_slice()).unwrap();
+    let mut binary = Vec::new();
+    collection
+        .write_index(&mut binary, IndexFormat::Binary)
+        .unwrap();
+    drop(collection);
+    println!(
+        "Index of {} posts: {} bytes of JSON, {} bytes of binary",
+        POSTS,
+        json.len(),
+        binary.len()
+    );
+
+    let mut group = c.benchmark_group("index_loading");
+    group.throughput(Throughput::Elements(POSTS));
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(30));
+
+    // Collections are returned from the routines, so they are dropped outside of the measurement.
+    // Whole index is deserialized into `IndexCollection` before the collection is built
+    group.bench_function("json_collection", |b| {
+        b.iter_batched(
+            || (),
+            |_| {
+                let index: IndexCollection = serde_json::from_slice(&json).unwrap();
+                DbRefCollection::from_parts(NoDiff, index, Vec::new())
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("json_streaming", |b| {
+        b.iter_batched(
+            || (),
+            |_| DbRefCollection::<NoDiff>::from_reader(json.as_slice()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("binary_streaming", |b| {
+        b.iter_batched(
+            || (),
+            |_| DbRefCollection::<NoDiff>::from_reader(binary.as_slice()).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, index_loading);
+criterion_main!(benches);
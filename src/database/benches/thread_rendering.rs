@@ -0,0 +1,81 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use database::{
+    legacy_database::{
+        chunk::{
+            chunk_processor::{
+                ChunkCollectionProcessor, OnDiskChunkCollectionProcessor, ReadCacheConfig,
+            },
+            Chunk,
+        },
+        config::StorageConfig,
+        index::db_post_ref::ChunkSettings,
+    },
+    post::PostMessage,
+};
+use std::env::set_current_dir;
+use tempdir::TempDir;
+
+/// Posts in the rendered thread
+const THREAD_LENGTH: usize = 300;
+
+/// Small chunks, so the thread is spread over many chunk files as on a long-living board
+const CHUNK_SIZE: u64 = 4 * 1024;
+
+type Processor = OnDiskChunkCollectionProcessor<Chunk>;
+
+fn write_thread(read_cache: ReadCacheConfig) -> (Processor, Vec<(ChunkSettings, u64)>) {
+    let mut processor =
+        Processor::with_read_cache(StorageConfig::with_chunk_size(CHUNK_SIZE), read_cache).unwrap();
+    let posts = (0..THREAD_LENGTH)
+        .map(|i| {
+            let message =
+                PostMessage::new(format!("[g]Post number {}[/g] {}", i, "text ".repeat(60)));
+            let settings = processor.insert(message.as_bytes()).unwrap();
+            (settings, message.as_bytes().len() as u64)
+        })
+        .collect();
+
+    (processor, posts)
+}
+
+fn render_thread(processor: &Processor, posts: &[(ChunkSettings, u64)]) {
+    for (settings, len) in posts {
+        processor.get_message(settings, *len).unwrap();
+    }
+}
+
+fn thread_rendering(c: &mut Criterion) {
+    let dir = TempDir::new("thread_rendering").unwrap();
+    set_current_dir(&dir).unwrap();
+
+    let mut group = c.benchmark_group("thread_rendering");
+    group.throughput(Throughput::Elements(THREAD_LENGTH as u64));
+
+    let caches = [
+        (
+            "no_cache",
+            ReadCacheConfig {
+                open_chunks: 0,
+                messages: 0,
+            },
+        ),
+        (
+            "open_chunks_only",
+            ReadCacheConfig {
+                open_chunks: 64,
+                messages: 0,
+            },
+        ),
+        ("default_cache", ReadCacheConfig::default()),
+    ];
+
+    for (name, read_cache) in caches {
+        let (processor, posts) = write_thread(read_cache);
+        group.bench_function(name, |b| b.iter(|| render_thread(&processor, &posts)));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, thread_rendering);
+criterion_main!(benches);
@@ -0,0 +1,313 @@
+//! Command line tool for inspecting the legacy database.
+//! The database is opened read-only, so it must not be used by another process at the same time.
+//! Encrypted database is unlocked with the passphrase from the `RUSTYBOARD_PASSPHRASE` environment variable.
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io::Read,
+    path::Path,
+    process,
+};
+
+use database::legacy_database::{
+    chunk::{
+        chunk_index_to_name,
+        chunk_processor::{
+            ChunkCollectionProcessor, OnDiskChunkCollectionProcessor,
+            OnDiskChunkCollectionProcessorError,
+        },
+        compression::{Compressor, DEFAULT_LEVEL},
+        Chunk,
+    },
+    config::{LegacyDatabaseConfig, StorageConfig},
+    crypto::{Cipher, KEY_FILENAME},
+    database::{snapshot::SnapshotReport, stats::DatabaseStats, LegacyDatabase},
+    index::{
+        ban_list::BAN_LIST_FILENAME,
+        db_post_ref::DbPostRef,
+        diff::ReadOnlyDiffFile,
+        index_file,
+        metadata::METADATA_FILENAME,
+        search::SEARCH_INDEX_FILENAME,
+        serialized::{DbPostRefSerialized, IndexCollection, PostHashes, INDEX_FILENAME},
+        trash::TRASH_FILENAME,
+        DbRefCollection,
+    },
+};
+
+const PASSPHRASE_VARIABLE: &str = "RUSTYBOARD_PASSPHRASE";
+
+const USAGE: &str = "Usage: rustyboard-db [--dir <database directory>] <command>
+
+Commands:
+    stats                  Print post counts, space usage and fragmentation
+    compress <directory>   Write a compressed copy of the database into the empty directory
+    snapshot <directory> [--incremental]
+                           Copy the database into the directory, or only the changes since the last snapshot in it";
+
+type Processor = OnDiskChunkCollectionProcessor<Chunk>;
+
+type ReadOnlyDatabase = LegacyDatabase<Processor, ReadOnlyDiffFile>;
+
+/// Files which don't depend on the chunk layout, so they are copied as is
+const SIDECAR_FILES: &[&str] = &[
+    KEY_FILENAME,
+    METADATA_FILENAME,
+    BAN_LIST_FILENAME,
+    SEARCH_INDEX_FILENAME,
+    TRASH_FILENAME,
+];
+
+/// Total size of the messages the compression dictionary is trained on
+const DICTIONARY_SAMPLE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Total size of the messages which are read before they are written into the compressed copy
+const COMPRESS_BATCH_BYTES: usize = 64 * 1024 * 1024;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("Error: {}", err);
+        let mut source = err.source();
+        while let Some(err) = source {
+            eprintln!("Caused by: {}", err);
+            source = err.source();
+        }
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let command = match args {
+        [flag, dir, rest @ ..] if flag == "--dir" => {
+            env::set_current_dir(dir)?;
+            rest
+        }
+        rest => rest,
+    };
+
+    match command {
+        [command] if command == "stats" => print_stats(&open()?.stats()?),
+        [command, destination] if command == "compress" => compress(Path::new(destination))?,
+        [command, destination] if command == "snapshot" => {
+            print_snapshot_report(&open()?.snapshot(Path::new(destination))?)
+        }
+        [command, destination, flag] if command == "snapshot" && flag == "--incremental" => {
+            print_snapshot_report(&open()?.snapshot_incremental(Path::new(destination))?)
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    Ok(())
+}
+
+fn open() -> Result<ReadOnlyDatabase, Box<dyn Error>> {
+    let cipher = unlock()?;
+    let reference = open_reference(cipher.as_ref())?;
+    let config = LegacyDatabaseConfig {
+        cipher: cipher.clone(),
+        ..Default::default()
+    };
+    let chunk_processor = open_processor(OnDiskChunkCollectionProcessor::open_existing, cipher)?;
+    let chunk_processor = match Compressor::load(DEFAULT_LEVEL)? {
+        Some(compressor) => chunk_processor.with_compressor(compressor),
+        None => chunk_processor,
+    };
+
+    Ok(LegacyDatabase::with_config(
+        reference,
+        chunk_processor,
+        config,
+    ))
+}
+
+/// Returns the cipher if the database is encrypted
+fn unlock() -> Result<Option<Cipher>, Box<dyn Error>> {
+    if !Cipher::key_file_exists() {
+        return Ok(None);
+    }
+
+    let passphrase = env::var(PASSPHRASE_VARIABLE)
+        .map_err(|_| format!("Database is encrypted, set {}", PASSPHRASE_VARIABLE))?;
+    Ok(Some(Cipher::unlock(&passphrase)?))
+}
+
+fn open_reference(
+    cipher: Option<&Cipher>,
+) -> Result<DbRefCollection<ReadOnlyDiffFile>, Box<dyn Error>> {
+    let mut index = File::open(INDEX_FILENAME)
+        .map_err(|err| format!("Can't open {}: {}", INDEX_FILENAME, err))?;
+    match cipher {
+        Some(cipher) => {
+            let mut sealed = Vec::new();
+            index.read_to_end(&mut sealed)?;
+            let index = index_file::open_index(&sealed, cipher)?;
+            Ok(DbRefCollection::load(index.as_slice(), || {
+                ReadOnlyDiffFile::read_encrypted(cipher)
+            })?)
+        }
+        None => Ok(DbRefCollection::from_reader(index)?),
+    }
+}
+
+/// Opens the chunks with `open`: the source database is only read, so its chunks must not be created,
+/// while the chunks of the destination are
+fn open_processor(
+    open: fn(StorageConfig) -> Result<Processor, OnDiskChunkCollectionProcessorError>,
+    cipher: Option<Cipher>,
+) -> Result<Processor, Box<dyn Error>> {
+    let chunk_processor = open(Default::default())?;
+    Ok(match cipher {
+        Some(cipher) => chunk_processor.with_cipher(cipher),
+        None => chunk_processor,
+    })
+}
+
+/// Copies the database into `destination` compressing every live message.
+/// Deleted posts are kept without their messages, so their space is not copied.
+///
+/// Chunk files are opened relative to the current directory, so it's switched between the databases,
+/// while messages are copied in batches.
+fn compress(destination: &Path) -> Result<(), Box<dyn Error>> {
+    if Compressor::load(DEFAULT_LEVEL)?.is_some() {
+        return Err("Database is already compressed".into());
+    }
+
+    let source = env::current_dir()?;
+    fs::create_dir_all(destination)?;
+    let destination = destination.canonicalize()?;
+    if fs::read_dir(&destination)?.next().is_some() {
+        return Err(format!("{} is not empty", destination.display()).into());
+    }
+
+    let cipher = unlock()?;
+    let reference = open_reference(cipher.as_ref())?;
+    let source_processor = open_processor(
+        OnDiskChunkCollectionProcessor::open_existing,
+        cipher.clone(),
+    )?;
+    let refs: Vec<(PostHashes, DbPostRef)> = reference
+        .iter()
+        .map(|(hash, db_ref)| {
+            let hashes = PostHashes {
+                hash: hash.clone(),
+                parent: db_ref.parent_hash.clone(),
+            };
+            (hashes, db_ref.clone())
+        })
+        .collect();
+
+    let dictionary = train_dictionary(&refs, &source_processor)?;
+    let compressor = Compressor::new(&dictionary, DEFAULT_LEVEL);
+
+    env::set_current_dir(&destination)?;
+    let mut processor = open_processor(OnDiskChunkCollectionProcessor::new, cipher.clone())?
+        .with_compressor(compressor);
+    let mut index = IndexCollection {
+        indexes: Vec::with_capacity(refs.len()),
+    };
+
+    let mut next = 0;
+    while next < refs.len() {
+        env::set_current_dir(&source)?;
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        while next < refs.len() && batch_bytes < COMPRESS_BATCH_BYTES {
+            let (hashes, db_ref) = &refs[next];
+            next += 1;
+            let message = match (&db_ref.chunk_settings, db_ref.deleted) {
+                (Some(settings), false) => {
+                    Some(source_processor.get_bytes(settings, db_ref.length)?)
+                }
+                _ => None,
+            };
+            batch_bytes += message.as_ref().map_or(0, Vec::len);
+            batch.push((hashes, message));
+        }
+
+        env::set_current_dir(&destination)?;
+        for (hashes, message) in batch {
+            let mut db_ref = DbPostRef {
+                chunk_settings: None,
+                length: 0,
+                deleted: true,
+                parent_hash: hashes.parent.clone(),
+            };
+            if let Some(message) = message {
+                let stored = processor.encode(&message)?;
+                db_ref.chunk_settings = Some(processor.insert(&stored)?);
+                db_ref.length = stored.len() as u64;
+                db_ref.deleted = false;
+            }
+
+            index
+                .indexes
+                .push(DbPostRefSerialized::new(hashes, &db_ref));
+        }
+    }
+
+    processor.sync()?;
+    for file in SIDECAR_FILES {
+        let path = source.join(file);
+        if path.exists() {
+            fs::copy(path, file)?;
+        }
+    }
+
+    let index = match &cipher {
+        Some(cipher) => index.to_encrypted(cipher)?,
+        None => serde_json::to_vec(&index)?,
+    };
+    fs::write(INDEX_FILENAME, index)?;
+    Compressor::save_dictionary(&dictionary)?;
+
+    println!(
+        "Compressed {} posts into {}",
+        refs.len(),
+        destination.display()
+    );
+    Ok(())
+}
+
+/// Trains the dictionary on the first live messages.
+/// Returns an empty dictionary if there are too few messages to train on.
+fn train_dictionary(
+    refs: &[(PostHashes, DbPostRef)],
+    processor: &Processor,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut samples = Vec::new();
+    let mut sample_bytes = 0;
+    for (_, db_ref) in refs {
+        if sample_bytes >= DICTIONARY_SAMPLE_BYTES {
+            break;
+        }
+
+        if let (Some(settings), false) = (&db_ref.chunk_settings, db_ref.deleted) {
+            let message = processor.get_bytes(settings, db_ref.length)?;
+            sample_bytes += message.len();
+            samples.push(message);
+        }
+    }
+
+    Ok(Compressor::train(&samples).unwrap_or_default())
+}
+
+fn print_stats(stats: &DatabaseStats) {
+    println!("Posts:          {}", stats.posts);
+    println!("  live:         {}", stats.live_posts());
+    println!("  deleted:      {}", stats.deleted_posts);
+    println!("Live bytes:     {}", stats.live_bytes);
+    println!("Free bytes:     {}", stats.free_bytes);
+    println!("Fragmentation:  {:.1}%", stats.fragmentation() * 100.0);
+    println!("Chunk bytes:    {}", stats.chunk_bytes());
+    for (index, size) in &stats.chunk_sizes {
+        println!("  {}: {}", chunk_index_to_name(*index), size);
+    }
+    println!("Diff bytes:     {}", stats.diff_bytes);
+}
+
+fn print_snapshot_report(report: &SnapshotReport) {
+    println!("References:     {}", report.refs);
+    println!("Chunk bytes:    {}", report.chunk_bytes);
+}
@@ -1,17 +1,12 @@
 #[cfg(test)]
 use mockall::automock;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io;
-use std::io::BufReader;
-use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
-use std::io::Write;
-use std::os::unix::prelude::FileExt;
-use std::path::Path;
+use std::cell::OnceCell;
+use std::marker::PhantomData;
 use thiserror::Error;
 
+use super::storage::{DiskStorage, FileMode, Storage, StorageFile};
+use crate::legacy_database::config::StorageConfig;
+
 pub const CHUNK_EXT: &str = ".db3";
 
 #[allow(clippy::identity_op)] // For better readability
@@ -33,19 +28,35 @@ pub trait ChunkTrait {
     where
         Self: Sized;
 
-    fn try_new(max_chunk_size: Option<u64>) -> ChunkResult<Self>
+    fn try_new(config: &StorageConfig) -> ChunkResult<Self>
+    where
+        Self: Sized;
+
+    fn open_last(config: &StorageConfig) -> ChunkResult<Self>
     where
         Self: Sized;
 
     fn index(&self) -> ChunkIndex;
 
     fn read_data(&self, offset: Offset, length: u64) -> ChunkResult<Vec<u8>>;
+
+    fn sync(&self) -> ChunkResult<()>;
+
+    fn size(&self) -> ChunkResult<u64>;
 }
+/// Chunk file, stored in the [DiskStorage] unless other [Storage] is specified
 #[derive(Debug)]
-pub struct Chunk {
+pub struct Chunk<TStorage: Storage = DiskStorage> {
     pub index: ChunkIndex,
     max_chunk_size: u64,
+
+    /// See [StorageConfig::preallocate]
+    preallocate: u64,
     filename: String,
+
+    /// File handle reused by all reads, opened on the first read
+    reader: OnceCell<TStorage::File>,
+    storage: PhantomData<TStorage>,
 }
 #[derive(Debug, Error)]
 pub enum ChunkError {
@@ -63,17 +74,11 @@ pub enum ChunkError {
 }
 pub type ChunkResult<T> = std::result::Result<T, ChunkError>;
 
-enum FileMode {
-    Write,
-    Append,
-    Read,
-}
-
-impl ChunkTrait for Chunk {
+impl<TStorage: Storage> ChunkTrait for Chunk<TStorage> {
     /// Creates a new chunk with incremented index
     fn create_extended(&self) -> ChunkResult<Self> {
         let new_index = self.index + 1;
-        let chunk = Self::try_create(new_index, Some(self.max_chunk_size))?;
+        let chunk = Self::try_create(new_index, &self.config())?;
 
         Ok(chunk)
     }
@@ -86,8 +91,15 @@ impl ChunkTrait for Chunk {
     fn try_append_data(&mut self, data: &[u8]) -> ChunkResult<Offset> {
         self.validate_chunk_size()?;
         let mut file = self.get_file(FileMode::Append)?;
-        let pos = file.seek(SeekFrom::End(0))?;
-        file.write_all(data)?;
+        let pos = file.append(data)?;
+
+        // Space is reserved in steps, so it's done once per many appends
+        let end = pos + data.len() as u64;
+        let step = self.preallocate;
+        if step > 0 && (pos == 0 || pos / step != end / step) {
+            let reserved = (end / step + 1) * step;
+            file.preallocate(reserved.min(self.max_chunk_size.max(end)))?;
+        }
 
         Ok(pos)
     }
@@ -113,16 +125,25 @@ impl ChunkTrait for Chunk {
     /// # Arguments
     /// * `index` - chunk's index ('0.db3', '1.db3'...)
     fn open_without_sizecheck(index: ChunkIndex) -> ChunkResult<Self> {
-        let chunk = Chunk::new(index, None);
+        let chunk = Chunk::new(index, &StorageConfig::default());
         chunk.file_exists()?;
         Ok(chunk)
     }
 
-    /// Tries to open already existing chunk, starting from `0.db3`. If chunk is larger than the limit, tries to open the next one.
+    /// Tries to open the last existing chunk. If chunk is larger than the limit, creates the next one.
     /// # Errors
     /// If any IO error (except [`NotFound`]) is encountered the function will return immediately
-    fn try_new(max_chunk_size: Option<u64>) -> ChunkResult<Self> {
-        Self::try_new_from(0, max_chunk_size)
+    fn try_new(config: &StorageConfig) -> ChunkResult<Self> {
+        Self::try_new_from(Self::last_index(), config)
+    }
+
+    /// Opens the last existing chunk without creating any and regardless of its size.
+    /// # Errors
+    /// [`ChunkError::ChunkFileDoesNotExist`] if there are no chunks
+    fn open_last(config: &StorageConfig) -> ChunkResult<Self> {
+        let chunk = Chunk::new(Self::last_index(), config);
+        chunk.file_exists()?;
+        Ok(chunk)
     }
 
     /// Returns chunk index (0 - for "0.db3", 1 - for "1.db3", etc...)
@@ -132,57 +153,74 @@ impl ChunkTrait for Chunk {
 
     /// Reads byte array specified via offset from the start of the file and length
     fn read_data(&self, offset: Offset, length: u64) -> ChunkResult<Vec<u8>> {
-        let file = self.get_file(FileMode::Read)?;
+        let file = match self.reader.get() {
+            Some(file) => file,
+            None => {
+                let file = self.get_file(FileMode::Read)?;
+                self.reader.get_or_init(|| file)
+            }
+        };
         let mut buffer = vec![0; length as usize];
-        let mut reader = BufReader::new(file);
-        reader.seek(SeekFrom::Start(offset))?;
-        reader.read_exact(&mut buffer)?;
+        file.read_exact_at(&mut buffer, offset)?;
 
         Ok(buffer)
     }
+
+    /// Flushes written data to the disk
+    fn sync(&self) -> ChunkResult<()> {
+        self.get_file(FileMode::Write)?.sync_data()?;
+        Ok(())
+    }
+
+    /// Returns chunk file size in bytes
+    fn size(&self) -> ChunkResult<u64> {
+        let file = self.get_file(FileMode::Read)?;
+        Ok(file.size()?)
+    }
 }
 
-impl Chunk {
-    fn new(index: ChunkIndex, max_chunk_size: Option<u64>) -> Self {
+impl<TStorage: Storage> Chunk<TStorage> {
+    fn new(index: ChunkIndex, config: &StorageConfig) -> Self {
         Chunk {
             index,
-            max_chunk_size: Self::get_chunk_size(max_chunk_size),
-            filename: Self::index_to_name(index),
+            max_chunk_size: Self::get_chunk_size(config.chunk_size),
+            preallocate: config.preallocate,
+            filename: index_to_name(index),
+            reader: OnceCell::new(),
+            storage: PhantomData,
         }
     }
     /// Tries to open existing chunk with specified index.
     /// Returns an error when the chunk with such index does not exist.
     /// # Arguments
     /// * `index` - an index of the chunk (`0.db3`, `1.db3`, etc.)
-    /// * `max_chunk_size` - max chunk size in bytes. Default is 1GB.
+    /// * `config` - max chunk size in bytes (default is 1GB) and preallocation
     /// # Errors
     /// If any IO error is encountered, its variant will be returned. The most common error should be non-existing file.
     /// If chunk with specified index is too big, error will be returned.
-    pub fn try_open(index: ChunkIndex, max_chunk_size: Option<u64>) -> ChunkResult<Self> {
-        let chunk = Chunk::new(index, max_chunk_size);
+    pub fn try_open(index: ChunkIndex, config: &StorageConfig) -> ChunkResult<Self> {
+        let chunk = Chunk::new(index, config);
         chunk.validate_chunk_size()?;
 
         Ok(chunk)
     }
 
-    pub fn try_create(index: ChunkIndex, max_chunk_size: Option<u64>) -> ChunkResult<Self> {
-        let chunk = Chunk::new(index, max_chunk_size);
-        File::create(&chunk.filename)?;
+    pub fn try_create(index: ChunkIndex, config: &StorageConfig) -> ChunkResult<Self> {
+        let chunk = Chunk::new(index, config);
+        TStorage::create(&chunk.filename)?;
         Ok(chunk)
     }
 
-    /// Tries to open already existing chunk starting from `index`. If chunk is larger than the `max_chunk_size`, tries to open the next one.
+    /// Tries to open already existing chunk starting from `index`. If chunk is larger than the chunk size, tries to open the next one.
     /// # Errors
     /// If any IO error (except [`NotFound`]) is encountered the function will return immediately
-    pub fn try_new_from(index: ChunkIndex, max_chunk_size: Option<u64>) -> ChunkResult<Self> {
+    pub fn try_new_from(index: ChunkIndex, config: &StorageConfig) -> ChunkResult<Self> {
         let mut index = index;
         loop {
-            let chunk = Self::try_open(index, max_chunk_size);
+            let chunk = Self::try_open(index, config);
             match chunk {
                 Err(e) => match e {
-                    ChunkError::ChunkFileDoesNotExist => {
-                        return Self::try_create(index, max_chunk_size)
-                    }
+                    ChunkError::ChunkFileDoesNotExist => return Self::try_create(index, config),
                     ChunkError::ChunkTooLarge => {
                         index += 1;
                         continue;
@@ -194,38 +232,23 @@ impl Chunk {
         }
     }
 
-    /// Converts chunk name (`0.db3`) to the chunk index
-    pub fn name_to_index(chunk_name: String) -> ChunkIndex {
-        let index_str = chunk_name.replace(CHUNK_EXT, "");
-
-        index_str.parse::<ChunkIndex>().unwrap()
-    }
-
-    pub fn index_to_name(chunk_index: ChunkIndex) -> String {
-        format!("{}{}", chunk_index, CHUNK_EXT)
-    }
-
     fn validate_chunk_size(&self) -> ChunkResult<()> {
         self.file_exists()?;
         let file = self.get_file(FileMode::Write)?;
 
-        if file.metadata()?.len() >= self.max_chunk_size {
+        if file.size()? >= self.max_chunk_size {
             Err(ChunkError::ChunkTooLarge)
         } else {
             Ok(())
         }
     }
 
-    fn get_file(&self, mode: FileMode) -> io::Result<File> {
-        match mode {
-            FileMode::Append => OpenOptions::new().append(true).open(&self.filename),
-            FileMode::Write => OpenOptions::new().write(true).open(&self.filename),
-            FileMode::Read => OpenOptions::new().read(true).open(&self.filename),
-        }
+    fn get_file(&self, mode: FileMode) -> std::io::Result<TStorage::File> {
+        TStorage::open(&self.filename, mode)
     }
 
     fn file_exists(&self) -> ChunkResult<()> {
-        if Path::new(&self.filename).exists() {
+        if TStorage::exists(&self.filename) {
             Ok(())
         } else {
             Err(ChunkError::ChunkFileDoesNotExist)
@@ -235,16 +258,73 @@ impl Chunk {
     fn get_chunk_size(chunk_size: Option<u64>) -> u64 {
         chunk_size.unwrap_or(MAX_CHUNK_SIZE)
     }
+
+    fn config(&self) -> StorageConfig {
+        StorageConfig {
+            chunk_size: Some(self.max_chunk_size),
+            preallocate: self.preallocate,
+            ..Default::default()
+        }
+    }
+
+    /// Finds the index of the last existing chunk, `0` if there are none.
+    /// Chunks are numbered without gaps, so it's found by doubling the index and then bisecting,
+    /// without opening every chunk of a database with many small chunks.
+    fn last_index() -> ChunkIndex {
+        let exists = |index: ChunkIndex| TStorage::exists(&index_to_name(index));
+        if !exists(0) {
+            return 0;
+        }
+
+        let mut missing = 1;
+        while exists(missing) {
+            missing *= 2;
+        }
+
+        // Chunk `missing / 2` exists, or it's the `0` one
+        let mut existing = missing / 2;
+        while missing - existing > 1 {
+            let middle = existing + (missing - existing) / 2;
+            if exists(middle) {
+                existing = middle;
+            } else {
+                missing = middle;
+            }
+        }
+
+        existing
+    }
+}
+
+/// Converts chunk name (`0.db3`) to the chunk index
+pub fn name_to_index(chunk_name: String) -> ChunkIndex {
+    let index_str = chunk_name.replace(CHUNK_EXT, "");
+
+    index_str.parse::<ChunkIndex>().unwrap()
+}
+
+pub fn index_to_name(chunk_index: ChunkIndex) -> String {
+    format!("{}{}", chunk_index, CHUNK_EXT)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, path::Path};
+    use std::{
+        fs::{self, File},
+        io::Write,
+        path::Path,
+    };
 
     use super::*;
-    use crate::in_temp_dir;
+    use crate::{
+        in_temp_dir,
+        legacy_database::chunk::memory_storage::{Fault, MemoryStorage},
+    };
     use rusty_fork::rusty_fork_test;
 
+    /// Chunk in the current directory
+    type Chunk = super::Chunk<DiskStorage>;
+
     mod try_new {
 
         use super::*;
@@ -290,12 +370,48 @@ mod tests {
             fn try_new_from_starts_from_provided_index() {
                 in_temp_dir!({
                     File::create("1.db3").unwrap().write_all(b"buf").unwrap();
-                    let chunk = Chunk::try_new_from(1, Some(1)).unwrap();
+                    let chunk = Chunk::try_new_from(1, &StorageConfig::with_chunk_size(1)).unwrap();
                     assert_eq!(chunk.index, 2);
                     assert!(exists_index(2));
                 });
             }
         }
+
+        rusty_fork_test! {
+            #[test]
+            fn try_new_should_open_last_of_many_chunks() {
+                in_temp_dir!({
+                    for index in 0..37 {
+                        File::create(index_to_name(index)).unwrap().write_all(b"buf").unwrap();
+                    }
+                    let chunk = some_chunk(Some(99999));
+                    assert_eq!(chunk.index, 36);
+                });
+            }
+        }
+
+        rusty_fork_test! {
+            #[test]
+            fn open_last_should_open_full_chunk_without_creating_next() {
+                in_temp_dir!({
+                    File::create("0.db3").unwrap().write_all(b"buf").unwrap();
+                    let chunk = Chunk::open_last(&StorageConfig::with_chunk_size(1)).unwrap();
+                    assert_eq!(chunk.index, 0);
+                    assert!(!exists_index(1));
+                });
+            }
+        }
+
+        rusty_fork_test! {
+            #[test]
+            fn open_last_should_fail_without_chunks() {
+                in_temp_dir!({
+                    let err = Chunk::open_last(&StorageConfig::default()).unwrap_err();
+                    assert!(matches!(err, ChunkError::ChunkFileDoesNotExist));
+                    assert!(!exists_index(0));
+                });
+            }
+        }
     }
     mod append {
         use super::*;
@@ -365,7 +481,7 @@ mod tests {
             fn try_open_should_return_error_if_max_size_exceeded() {
                 in_temp_dir!({
                     File::create("0.db3").unwrap().write_all(b"buf").unwrap();
-                    let chunk = Chunk::try_open(0, Some(1));
+                    let chunk = Chunk::try_open(0, &StorageConfig::with_chunk_size(1));
                     assert!(matches!(chunk.unwrap_err(), ChunkError::ChunkTooLarge))
                 });
             }
@@ -377,7 +493,7 @@ mod tests {
                 in_temp_dir!({
                     File::create("0.db3").unwrap().write_all(b"buf").unwrap();
 
-                    let chunk = Chunk::try_open(0, Some(9999)).unwrap();
+                    let chunk = Chunk::try_open(0, &StorageConfig::with_chunk_size(9999)).unwrap();
                     assert_eq!(chunk.index, 0);
                 });
             }
@@ -438,11 +554,43 @@ mod tests {
         }
     }
 
+    mod memory {
+        use super::*;
+
+        type MemoryChunk = super::super::Chunk<MemoryStorage>;
+
+        #[test]
+        fn chunk_should_work_over_memory_storage() {
+            let mut chunk = MemoryChunk::try_new(&StorageConfig::with_chunk_size(9999)).unwrap();
+            chunk.try_append_data(b"buffer").unwrap();
+            chunk.try_write_data(b"i", 1).unwrap();
+            chunk.remove_data(4, 2).unwrap();
+
+            assert_eq!(chunk.read_data(0, 4).unwrap(), b"biff");
+            assert_eq!(MemoryStorage::read("0.db3").unwrap(), b"biff\0\0");
+        }
+
+        #[test]
+        fn append_should_return_storage_error() {
+            let mut chunk = MemoryChunk::try_new(&StorageConfig::with_chunk_size(9999)).unwrap();
+            MemoryStorage::inject_fault(Fault::NoSpace { capacity: 3 });
+
+            let err = chunk.try_append_data(b"buffer").unwrap_err();
+
+            assert!(matches!(err, ChunkError::IoError { .. }));
+            assert_eq!(chunk.size().unwrap(), 0);
+        }
+    }
+
     fn exists_index(index: ChunkIndex) -> bool {
-        return Path::new(&format!("{}.db3", index)).exists();
+        Path::new(&format!("{}.db3", index)).exists()
     }
 
     fn some_chunk(max_chunk_size: Option<u64>) -> Chunk {
-        Chunk::try_new(max_chunk_size).unwrap()
+        let config = StorageConfig {
+            chunk_size: max_chunk_size,
+            ..Default::default()
+        };
+        Chunk::try_new(&config).unwrap()
     }
 }
@@ -1,29 +1,171 @@
-use std::{error::Error, string};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    error::Error,
+    hash::{Hash, Hasher},
+    io,
+    num::NonZeroUsize,
+    string,
+};
 
-use crate::{legacy_database::index::db_post_ref::ChunkSettings, post::PostMessage};
+use crate::{
+    legacy_database::{
+        config::{FitStrategy, StorageConfig},
+        crypto::{Cipher, CryptoError},
+        index::db_post_ref::ChunkSettings,
+    },
+    post::PostMessage,
+};
 
-use super::chunk::{
-    ChunkError::{self, ChunkTooLarge},
-    ChunkTrait,
+use super::{
+    chunk::{
+        ChunkError::{self, ChunkTooLarge},
+        ChunkIndex, ChunkResult, ChunkTrait,
+    },
+    compression::Compressor,
 };
+use lru::LruCache;
 use thiserror::Error;
 pub trait ChunkCollectionProcessor {
     type Error: Error;
 
-    fn insert(&mut self, post: &PostMessage) -> Result<ChunkSettings, Self::Error>;
+    /// Converts message bytes into the bytes which are stored, e.g. compresses them.
+    /// Lengths passed to the other methods are lengths of the stored bytes.
+    fn encode<'a>(&self, post: &'a [u8]) -> Result<Cow<'a, [u8]>, Self::Error> {
+        Ok(Cow::Borrowed(post))
+    }
+
+    /// Returns the extent which already holds the same stored bytes, if the processor deduplicates messages.
+    /// The extent may be free already, so the caller must check that it's still referenced by a live post.
+    fn find_duplicate(&self, _post: &[u8]) -> Result<Option<ChunkSettings>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Number of bytes the message of given stored length takes in the chunk
+    fn extent_length(&self, len: u64) -> u64 {
+        len
+    }
+
+    /// How the space of deleted posts must be reused for the new ones
+    fn fit_strategy(&self) -> FitStrategy {
+        FitStrategy::BestFit
+    }
+
+    /// Appends bytes returned by [ChunkCollectionProcessor::encode] to the storage
+    fn insert(&mut self, post: &[u8]) -> Result<ChunkSettings, Self::Error>;
+
+    /// Writes bytes returned by [ChunkCollectionProcessor::encode] into the already allocated space
     fn insert_into_existing(
         &mut self,
         chunk: &ChunkSettings,
-        post: &PostMessage,
+        post: &[u8],
     ) -> Result<(), Self::Error>;
 
     fn remove(&mut self, chunk: &ChunkSettings, len: u64) -> Result<(), Self::Error>;
 
     fn get_message(&self, chunk: &ChunkSettings, len: u64) -> Result<PostMessage, Self::Error>;
+
+    /// Reads message bytes without validating them
+    fn get_bytes(&self, chunk: &ChunkSettings, len: u64) -> Result<Vec<u8>, Self::Error>;
+
+    /// Returns size of the chunk in bytes, or `None` if the chunk does not exist
+    fn chunk_size(&self, chunk_index: ChunkIndex) -> Result<Option<u64>, Self::Error>;
+
+    /// Reads bytes of the chunk as they are stored, without decrypting or decompressing them,
+    /// e.g. for copying the chunk
+    fn read_range(
+        &self,
+        chunk_index: ChunkIndex,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Self::Error>;
+
+    /// Flushes all chunks written since the last sync to the disk
+    fn sync(&mut self) -> Result<(), Self::Error>;
 }
 
 pub struct OnDiskChunkCollectionProcessor<TChunk: ChunkTrait> {
     last_chunk: TChunk,
+
+    /// Indexes of chunks which were written since the last sync
+    unsynced: HashSet<ChunkIndex>,
+
+    /// Recently used chunks, kept open so their files are not reopened on every access.
+    /// `None` if the pool is disabled.
+    open_chunks: RefCell<Option<LruCache<ChunkIndex, TChunk>>>,
+
+    /// Recently read messages with their lengths. `None` if the cache is disabled.
+    messages: RefCell<Option<LruCache<ChunkSettings, (u64, PostMessage)>>>,
+
+    /// Encrypts every extent if set. Encrypted extents are [Cipher::OVERHEAD] bytes longer than the message,
+    /// while the lengths passed to the processor stay the message lengths.
+    cipher: Option<Cipher>,
+
+    /// Compresses every message if set, see [ChunkCollectionProcessor::encode]
+    compressor: Option<Compressor>,
+
+    /// Extents of the live and written messages, `None` if deduplication is disabled
+    extents: RefCell<Option<ExtentDigests>>,
+
+    fit_strategy: FitStrategy,
+}
+
+/// Extents by digests of their stored bytes, see [ChunkCollectionProcessor::find_duplicate].
+/// Digests may collide, so the extent is read back to check it holds the same bytes.
+/// Takes under a hundred bytes per extent, extents are forgotten when they're removed or overwritten.
+#[derive(Default)]
+struct ExtentDigests {
+    by_digest: HashMap<u64, (ChunkSettings, u64)>,
+    by_extent: HashMap<ChunkSettings, u64>,
+}
+
+impl ExtentDigests {
+    fn insert(&mut self, settings: &ChunkSettings, stored: &[u8]) {
+        self.remove(settings);
+        let digest = digest(stored);
+        self.by_digest
+            .insert(digest, (settings.clone(), stored.len() as u64));
+        self.by_extent.insert(settings.clone(), digest);
+    }
+
+    fn remove(&mut self, settings: &ChunkSettings) {
+        if let Some(digest) = self.by_extent.remove(settings) {
+            if self
+                .by_digest
+                .get(&digest)
+                .is_some_and(|(extent, _)| extent == settings)
+            {
+                self.by_digest.remove(&digest);
+            }
+        }
+    }
+
+    fn get(&self, stored: &[u8]) -> Option<(ChunkSettings, u64)> {
+        self.by_digest
+            .get(&digest(stored))
+            .filter(|(_, len)| *len == stored.len() as u64)
+            .cloned()
+    }
+}
+
+/// Capacities of the [OnDiskChunkCollectionProcessor] read caches. Zero capacity disables the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadCacheConfig {
+    /// How many chunk files are kept open
+    pub open_chunks: usize,
+
+    /// How many decoded messages are cached
+    pub messages: usize,
+}
+
+impl Default for ReadCacheConfig {
+    fn default() -> Self {
+        ReadCacheConfig {
+            open_chunks: 64,
+            messages: 4096,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -36,13 +178,148 @@ pub enum OnDiskChunkCollectionProcessorError {
 
     #[error("Error converting message bytes to utf8")]
     Base64Error(#[from] string::FromUtf8Error),
+
+    #[error("Error decrypting message")]
+    CryptoError(#[from] CryptoError),
+
+    #[error("Error (de)compressing message")]
+    CompressionError(#[source] io::Error),
 }
 
 impl<TChunk: ChunkTrait> OnDiskChunkCollectionProcessor<TChunk> {
-    pub fn new(max_chunk_size: Option<u64>) -> Result<Self, OnDiskChunkCollectionProcessorError> {
-        Ok(OnDiskChunkCollectionProcessor {
-            last_chunk: TChunk::try_new(max_chunk_size)?,
-        })
+    /// Opens the last chunk of the database in the current directory, creating it if there are none
+    pub fn new(config: StorageConfig) -> Result<Self, OnDiskChunkCollectionProcessorError> {
+        Self::with_read_cache(config, Default::default())
+    }
+
+    /// Opens the last chunk of the database in the current directory without creating any files,
+    /// so the database can be read without modifying it
+    /// # Errors
+    /// If there are no chunks
+    pub fn open_existing(
+        config: StorageConfig,
+    ) -> Result<Self, OnDiskChunkCollectionProcessorError> {
+        let mut processor = Self::from_last_chunk(TChunk::open_last(&config)?, Default::default());
+        processor.fit_strategy = config.fit_strategy;
+        Ok(processor)
+    }
+
+    pub fn with_read_cache(
+        config: StorageConfig,
+        read_cache: ReadCacheConfig,
+    ) -> Result<Self, OnDiskChunkCollectionProcessorError> {
+        let mut processor = Self::from_last_chunk(TChunk::try_new(&config)?, read_cache);
+        processor.fit_strategy = config.fit_strategy;
+        Ok(processor)
+    }
+
+    fn from_last_chunk(last_chunk: TChunk, read_cache: ReadCacheConfig) -> Self {
+        OnDiskChunkCollectionProcessor {
+            last_chunk,
+            unsynced: HashSet::new(),
+            open_chunks: RefCell::new(lru_cache(read_cache.open_chunks)),
+            messages: RefCell::new(lru_cache(read_cache.messages)),
+            cipher: None,
+            compressor: None,
+            extents: RefCell::new(None),
+            fit_strategy: FitStrategy::default(),
+        }
+    }
+
+    /// Lets identical messages share one extent. Digests of the `live_extents` (see [DbRefCollection::live_extents])
+    /// are built once by reading every extent, then the written messages are added and removed ones are forgotten,
+    /// so the memory is bounded by the number of live messages.
+    /// Extents which can't be read or decoded are skipped, they're never shared.
+    ///
+    /// [DbRefCollection::live_extents]: crate::legacy_database::index::DbRefCollection::live_extents
+    pub fn with_deduplication(
+        mut self,
+        live_extents: impl IntoIterator<Item = (ChunkSettings, u64)>,
+    ) -> Self {
+        let mut extents = ExtentDigests::default();
+        for (settings, len) in live_extents {
+            if let Ok(stored) = self.read_stored(&settings, len) {
+                extents.insert(&settings, &stored);
+            }
+        }
+        *self.extents.get_mut() = Some(extents);
+        self
+    }
+
+    fn remember_extent(&self, settings: &ChunkSettings, stored: &[u8]) {
+        if let Some(extents) = self.extents.borrow_mut().as_mut() {
+            extents.insert(settings, stored);
+        }
+    }
+
+    fn forget_extent(&mut self, settings: &ChunkSettings) {
+        if let Some(extents) = self.extents.get_mut() {
+            extents.remove(settings);
+        }
+    }
+
+    /// Reads the stored bytes of the message, decrypting them if needed
+    fn read_stored(
+        &self,
+        chunk_settings: &ChunkSettings,
+        len: u64,
+    ) -> Result<Vec<u8>, OnDiskChunkCollectionProcessorError> {
+        let offset = chunk_settings.offset;
+        let post_bytes =
+            self.read_range(chunk_settings.chunk_index, offset, self.extent_length(len))?;
+
+        match &self.cipher {
+            Some(cipher) => {
+                let aad = extent_aad(chunk_settings.chunk_index, offset);
+                Ok(cipher.open(&post_bytes, &aad)?)
+            }
+            None => Ok(post_bytes),
+        }
+    }
+
+    /// Compresses written messages and decompresses read ones.
+    /// All messages must be compressed, use the `rustyboard-db compress` to convert the uncompressed database.
+    pub fn with_compressor(mut self, compressor: Compressor) -> Self {
+        self.compressor = Some(compressor);
+        self
+    }
+
+    /// Encrypts written messages and decrypts read ones.
+    /// All chunks must be either encrypted with the same key or not encrypted at all.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Seals the message bound to its place, so extents can't be swapped
+    fn seal(&self, post: &[u8], chunk_index: ChunkIndex, offset: u64) -> Option<Vec<u8>> {
+        self.cipher
+            .as_ref()
+            .map(|cipher| cipher.seal(post, &extent_aad(chunk_index, offset)))
+    }
+
+    /// Runs `action` on the chunk with given index, taking it from the pool of open chunks if possible
+    fn with_chunk<T>(
+        &self,
+        index: ChunkIndex,
+        action: impl FnOnce(&mut TChunk) -> ChunkResult<T>,
+    ) -> ChunkResult<T> {
+        let mut open_chunks = self.open_chunks.borrow_mut();
+        let open_chunks = match open_chunks.as_mut() {
+            Some(open_chunks) => open_chunks,
+            None => return action(&mut TChunk::open_without_sizecheck(index)?),
+        };
+
+        if !open_chunks.contains(&index) {
+            open_chunks.put(index, TChunk::open_without_sizecheck(index)?);
+        }
+        action(open_chunks.get_mut(&index).unwrap())
+    }
+
+    fn forget_message(&mut self, settings: &ChunkSettings) {
+        if let Some(messages) = self.messages.get_mut() {
+            messages.pop(settings);
+        }
     }
 
     fn extend_current_chunk(&mut self) -> Result<(), OnDiskChunkCollectionProcessorError> {
@@ -55,11 +332,50 @@ impl<TChunk: ChunkTrait> OnDiskChunkCollectionProcessor<TChunk> {
 impl<TChunk: ChunkTrait> ChunkCollectionProcessor for OnDiskChunkCollectionProcessor<TChunk> {
     type Error = OnDiskChunkCollectionProcessorError;
 
-    fn insert(&mut self, post: &PostMessage) -> Result<ChunkSettings, Self::Error> {
-        let post_bytes = post.get_bytes();
+    fn encode<'a>(&self, post: &'a [u8]) -> Result<Cow<'a, [u8]>, Self::Error> {
+        match &self.compressor {
+            Some(compressor) => compressor
+                .compress(post)
+                .map(Cow::Owned)
+                .map_err(OnDiskChunkCollectionProcessorError::CompressionError),
+            None => Ok(Cow::Borrowed(post)),
+        }
+    }
+
+    fn extent_length(&self, len: u64) -> u64 {
+        match self.cipher {
+            Some(_) => len + Cipher::OVERHEAD,
+            None => len,
+        }
+    }
+
+    fn fit_strategy(&self) -> FitStrategy {
+        self.fit_strategy
+    }
+
+    fn find_duplicate(&self, post: &[u8]) -> Result<Option<ChunkSettings>, Self::Error> {
+        let candidate = match self.extents.borrow().as_ref() {
+            Some(extents) => extents.get(post),
+            None => return Ok(None),
+        };
+
+        match candidate {
+            Some((settings, len)) => match self.read_stored(&settings, len) {
+                Ok(stored) if stored == post => Ok(Some(settings)),
+                _ => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, post: &[u8]) -> Result<ChunkSettings, Self::Error> {
+        let sealed = match self.cipher {
+            Some(_) => self.seal(post, self.last_chunk.index(), self.last_chunk.size()?),
+            None => None,
+        };
         let result = self
             .last_chunk
-            .try_append_data(&post_bytes)
+            .try_append_data(sealed.as_deref().unwrap_or(post))
             .map(|offset| ChunkSettings {
                 chunk_index: self.last_chunk.index(),
                 offset,
@@ -72,18 +388,28 @@ impl<TChunk: ChunkTrait> ChunkCollectionProcessor for OnDiskChunkCollectionProce
                 }
                 _ => Err(err.into()),
             },
-            Ok(settings) => Ok(settings),
+            Ok(settings) => {
+                self.unsynced.insert(settings.chunk_index);
+                self.remember_extent(&settings, post);
+                Ok(settings)
+            }
         }
     }
 
     fn insert_into_existing(
         &mut self,
         settings: &ChunkSettings,
-        post: &PostMessage,
+        post: &[u8],
     ) -> Result<(), Self::Error> {
-        let post_bytes = post.get_bytes();
-        let mut chunk = TChunk::open_without_sizecheck(settings.chunk_index)?;
-        chunk.try_write_data(&post_bytes, settings.offset)?;
+        self.forget_message(settings);
+        self.forget_extent(settings);
+        let sealed = self.seal(post, settings.chunk_index, settings.offset);
+        let data = sealed.as_deref().unwrap_or(post);
+        self.with_chunk(settings.chunk_index, |chunk| {
+            chunk.try_write_data(data, settings.offset)
+        })?;
+        self.unsynced.insert(settings.chunk_index);
+        self.remember_extent(settings, post);
         Ok(())
     }
 
@@ -92,29 +418,111 @@ impl<TChunk: ChunkTrait> ChunkCollectionProcessor for OnDiskChunkCollectionProce
         chunk_settings: &ChunkSettings,
         len: u64,
     ) -> Result<PostMessage, Self::Error> {
-        let offset = chunk_settings.offset;
-        let post_bytes = if self.last_chunk.index() == chunk_settings.chunk_index {
-            self.last_chunk.read_data(offset, len)?
-        } else {
-            TChunk::open_without_sizecheck(chunk_settings.chunk_index)?.read_data(offset, len)?
-        };
+        if let Some(messages) = self.messages.borrow_mut().as_mut() {
+            match messages.get(chunk_settings) {
+                Some((cached_len, message)) if *cached_len == len => return Ok(message.clone()),
+                _ => {}
+            }
+        }
 
+        let post_bytes = self.get_bytes(chunk_settings, len)?;
         let post_message = PostMessage::from_bytes(post_bytes)?;
 
+        if let Some(messages) = self.messages.borrow_mut().as_mut() {
+            messages.put(chunk_settings.clone(), (len, post_message.clone()));
+        }
+
         Ok(post_message)
     }
 
+    fn get_bytes(&self, chunk_settings: &ChunkSettings, len: u64) -> Result<Vec<u8>, Self::Error> {
+        let post_bytes = self.read_stored(chunk_settings, len)?;
+
+        match &self.compressor {
+            Some(compressor) => compressor
+                .decompress(&post_bytes)
+                .map_err(OnDiskChunkCollectionProcessorError::CompressionError),
+            None => Ok(post_bytes),
+        }
+    }
+
+    fn chunk_size(&self, chunk_index: ChunkIndex) -> Result<Option<u64>, Self::Error> {
+        if self.last_chunk.index() == chunk_index {
+            return Ok(Some(self.last_chunk.size()?));
+        }
+
+        match self.with_chunk(chunk_index, |chunk| chunk.size()) {
+            Ok(size) => Ok(Some(size)),
+            Err(ChunkError::ChunkFileDoesNotExist) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn read_range(
+        &self,
+        chunk_index: ChunkIndex,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Self::Error> {
+        if self.last_chunk.index() == chunk_index {
+            return Ok(self.last_chunk.read_data(offset, length)?);
+        }
+
+        Ok(self.with_chunk(chunk_index, |chunk| chunk.read_data(offset, length))?)
+    }
+
     fn remove(&mut self, chunk: &ChunkSettings, len: u64) -> Result<(), Self::Error> {
-        TChunk::open_without_sizecheck(chunk.chunk_index)?.remove_data(chunk.offset, len)?;
+        self.forget_message(chunk);
+        self.forget_extent(chunk);
+        let len = self.extent_length(len);
+        self.with_chunk(chunk.chunk_index, |chunk_file| {
+            chunk_file.remove_data(chunk.offset, len)
+        })?;
+        self.unsynced.insert(chunk.chunk_index);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        let unsynced: Vec<ChunkIndex> = self.unsynced.drain().collect();
+        for index in unsynced {
+            if index == self.last_chunk.index() {
+                self.last_chunk.sync()?;
+            } else {
+                self.with_chunk(index, |chunk| chunk.sync())?;
+            }
+        }
+
         Ok(())
     }
 }
 
+fn digest(stored: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    stored.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Chunk index and offset of the extent, authenticated along with the encrypted message
+fn extent_aad(chunk_index: ChunkIndex, offset: u64) -> [u8; 16] {
+    let mut aad = [0; 16];
+    aad[..8].copy_from_slice(&chunk_index.to_le_bytes());
+    aad[8..].copy_from_slice(&offset.to_le_bytes());
+    aad
+}
+
+fn lru_cache<K: Hash + Eq, V>(capacity: usize) -> Option<LruCache<K, V>> {
+    NonZeroUsize::new(capacity).map(LruCache::new)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::legacy_database::chunk::chunk::*;
     use mockall::predicate::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Expectations of static mock methods are global, so tests which set them must not run in parallel
+    static STATIC_MOCKS: Mutex<()> = Mutex::new(());
 
     #[test]
     fn extend_assigns_chunk_to_self_last_chunk() {
@@ -127,9 +535,7 @@ mod tests {
             .expect_create_extended()
             .return_once(move || Ok(new_chunk));
 
-        let mut prcsr = OnDiskChunkCollectionProcessor {
-            last_chunk: original,
-        };
+        let mut prcsr = processor(original);
 
         prcsr.extend_current_chunk().unwrap();
         assert_eq!(prcsr.last_chunk.index(), 1)
@@ -141,13 +547,13 @@ mod tests {
         let mut new = mock();
 
         new.expect_try_append_data()
-            .withf_st(move |x| x == post().get_bytes())
+            .withf_st(move |x| x == post().as_bytes())
             .returning(|_| Ok(10));
         new.expect_index().return_const(1u64);
 
         original
             .expect_try_append_data()
-            .withf_st(move |x| x == post().get_bytes())
+            .withf_st(move |x| x == post().as_bytes())
             .returning(|_| Err(ChunkTooLarge));
         original
             .expect_create_extended()
@@ -156,7 +562,7 @@ mod tests {
         original.expect_index().return_const(0u64);
 
         let mut prcsr = processor(original);
-        prcsr.insert(&post()).unwrap();
+        prcsr.insert(post().as_bytes()).unwrap();
     }
 
     #[test]
@@ -167,13 +573,14 @@ mod tests {
 
         let mut prcsr = processor(chunk);
 
-        let res = prcsr.insert(&post()).unwrap();
+        let res = prcsr.insert(post().as_bytes()).unwrap();
         assert_eq!(res.chunk_index, 0);
         assert_eq!(res.offset, 10);
     }
 
     #[test]
     fn insert_into_existsing_should_write_data() {
+        let _lock = lock_static_mocks();
         let ctx = MockChunkTrait::open_without_sizecheck_context();
         let offset = 10u64;
 
@@ -182,7 +589,7 @@ mod tests {
             chunk.expect_index().return_const(0u64);
             chunk
                 .expect_try_write_data()
-                .withf_st(move |x, off| -> bool { (x == post().get_bytes()) && (off == &offset) })
+                .withf_st(move |x, off| -> bool { (x == post().as_bytes()) && (off == &offset) })
                 .returning(|_, _| Ok(()));
 
             Ok(chunk)
@@ -196,7 +603,7 @@ mod tests {
                     chunk_index: 0,
                     offset,
                 },
-                &post(),
+                post().as_bytes(),
             )
             .unwrap();
     }
@@ -238,6 +645,7 @@ mod tests {
             .expect_read_data()
             .with(eq(chunk_settings.offset), eq(len))
             .times(0);
+        let _lock = lock_static_mocks();
         let ctx = MockChunkTrait::open_without_sizecheck_context();
 
         ctx.expect().with(eq(1)).returning(move |_| {
@@ -276,6 +684,284 @@ mod tests {
         assert_eq!(result, expected_result);
     }
 
+    #[test]
+    fn sync_should_sync_written_chunks_once() {
+        let mut chunk = mock();
+        with_index(&mut chunk, 0);
+        chunk.expect_try_append_data().returning(|_| Ok(0));
+        chunk.expect_sync().times(1).returning(|| Ok(()));
+
+        let mut prcsr = processor(chunk);
+        prcsr.insert(post().as_bytes()).unwrap();
+        prcsr.insert(post().as_bytes()).unwrap();
+
+        prcsr.sync().unwrap();
+        prcsr.sync().unwrap();
+    }
+
+    #[test]
+    fn sync_should_open_chunks_other_than_last() {
+        let _lock = lock_static_mocks();
+        let ctx = MockChunkTrait::open_without_sizecheck_context();
+        ctx.expect().with(eq(1)).times(1).returning(|_| {
+            let mut chunk = mock();
+            chunk.expect_remove_data().returning(|_, _| Ok(()));
+            chunk.expect_sync().returning(|| Ok(()));
+            Ok(chunk)
+        });
+        let mut last_chunk = mock();
+        with_index(&mut last_chunk, 0);
+        last_chunk.expect_sync().times(0);
+
+        let mut prcsr = processor(last_chunk);
+        prcsr
+            .remove(
+                &ChunkSettings {
+                    chunk_index: 1,
+                    offset: 0,
+                },
+                4,
+            )
+            .unwrap();
+
+        prcsr.sync().unwrap();
+    }
+
+    #[test]
+    fn chunk_size_returns_none_if_chunk_does_not_exist() {
+        let _lock = lock_static_mocks();
+        let ctx = MockChunkTrait::open_without_sizecheck_context();
+        ctx.expect()
+            .with(eq(5))
+            .returning(|_| Err(ChunkError::ChunkFileDoesNotExist));
+        let mut chunk = mock();
+        with_index(&mut chunk, 0);
+
+        let prcsr = processor(chunk);
+
+        assert!(prcsr.chunk_size(5).unwrap().is_none());
+    }
+
+    #[test]
+    fn get_message_should_reuse_open_chunk() {
+        let _lock = lock_static_mocks();
+        let ctx = MockChunkTrait::open_without_sizecheck_context();
+        ctx.expect().with(eq(1)).times(1).returning(|_| {
+            let mut chunk = mock();
+            chunk
+                .expect_read_data()
+                .times(2)
+                .returning(|_, _| Ok(b"test".to_vec()));
+            Ok(chunk)
+        });
+        let mut last_chunk = mock();
+        with_index(&mut last_chunk, 0);
+        let prcsr = OnDiskChunkCollectionProcessor::from_last_chunk(
+            last_chunk,
+            ReadCacheConfig {
+                open_chunks: 1,
+                messages: 0,
+            },
+        );
+
+        prcsr.get_message(&settings(1, 0), 4).unwrap();
+        prcsr.get_message(&settings(1, 4), 4).unwrap();
+    }
+
+    #[test]
+    fn get_message_should_return_cached_message_until_it_is_removed() {
+        let mut chunk = mock();
+        with_index(&mut chunk, 0);
+        chunk
+            .expect_read_data()
+            .times(2)
+            .returning(|_, _| Ok(b"test".to_vec()));
+        chunk.expect_remove_data().returning(|_, _| Ok(()));
+        let _lock = lock_static_mocks();
+        let ctx = MockChunkTrait::open_without_sizecheck_context();
+        ctx.expect().returning(|_| {
+            let mut chunk = mock();
+            chunk.expect_remove_data().returning(|_, _| Ok(()));
+            Ok(chunk)
+        });
+
+        let mut prcsr = processor(chunk);
+        prcsr.get_message(&settings(0, 0), 4).unwrap();
+        prcsr.get_message(&settings(0, 0), 4).unwrap();
+        prcsr.remove(&settings(0, 0), 4).unwrap();
+        prcsr.get_message(&settings(0, 0), 4).unwrap();
+    }
+
+    mod memory_storage {
+        use super::*;
+        use crate::legacy_database::chunk::{
+            compression::DEFAULT_LEVEL,
+            memory_storage::{Fault, MemoryStorage},
+        };
+
+        type MemoryProcessor = OnDiskChunkCollectionProcessor<Chunk<MemoryStorage>>;
+
+        #[test]
+        fn insert_when_storage_is_full_should_return_error_and_keep_data() {
+            let mut prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(1024)).unwrap();
+            let first = prcsr.insert(b"first").unwrap();
+            MemoryStorage::inject_fault(Fault::NoSpace { capacity: 8 });
+
+            let result = prcsr.insert(b"second");
+
+            assert!(matches!(
+                result,
+                Err(OnDiskChunkCollectionProcessorError::ChunkError { .. })
+            ));
+            assert_eq!(prcsr.get_message(&first, 5).unwrap().as_str(), "first");
+        }
+
+        #[test]
+        fn insert_after_short_write_should_not_overlap_torn_data() {
+            let mut prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(1024)).unwrap();
+            MemoryStorage::inject_fault(Fault::ShortWrite { written: 3 });
+
+            assert!(prcsr.insert(b"torn").is_err());
+            let settings = prcsr.insert(b"whole").unwrap();
+
+            assert_eq!(settings.offset, 3);
+            assert_eq!(prcsr.get_message(&settings, 5).unwrap().as_str(), "whole");
+        }
+
+        #[test]
+        fn remove_when_write_fails_should_not_return_cached_message() {
+            let mut prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(1024)).unwrap();
+            let settings = prcsr.insert(b"message").unwrap();
+            prcsr.get_message(&settings, 7).unwrap();
+            MemoryStorage::inject_fault(Fault::ShortWrite { written: 2 });
+
+            assert!(prcsr.remove(&settings, 7).is_err());
+
+            let message = prcsr.get_message(&settings, 7).unwrap();
+            assert_eq!(message.as_str(), "\0\0ssage");
+        }
+
+        #[test]
+        fn sync_should_sync_written_chunks_only() {
+            let mut prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(4)).unwrap();
+            prcsr.insert(b"first").unwrap();
+            prcsr.insert(b"second").unwrap();
+
+            prcsr.sync().unwrap();
+            prcsr.sync().unwrap();
+
+            assert_eq!(MemoryStorage::syncs(), 2);
+        }
+
+        #[test]
+        fn encrypted_message_should_be_stored_sealed_and_read_back() {
+            let mut prcsr = encrypted_processor();
+
+            let settings = prcsr.insert(b"secret").unwrap();
+
+            let chunk = MemoryStorage::read(&index_to_name(0)).unwrap();
+            assert_eq!(chunk.len() as u64, 6 + Cipher::OVERHEAD);
+            assert!(!chunk.windows(6).any(|window| window == b"secret"));
+            assert_eq!(prcsr.get_message(&settings, 6).unwrap().as_str(), "secret");
+        }
+
+        #[test]
+        fn encrypted_message_should_reuse_space_of_removed_one() {
+            let mut prcsr = encrypted_processor();
+            let first = prcsr.insert(b"first message").unwrap();
+            let second = prcsr.insert(b"second").unwrap();
+            prcsr.remove(&first, 13).unwrap();
+
+            prcsr.insert_into_existing(&first, b"reused").unwrap();
+
+            assert_eq!(prcsr.get_message(&first, 6).unwrap().as_str(), "reused");
+            assert_eq!(prcsr.get_message(&second, 6).unwrap().as_str(), "second");
+        }
+
+        #[test]
+        fn tampered_or_moved_encrypted_message_should_not_be_read() {
+            let mut prcsr = encrypted_processor();
+            let original = prcsr.insert(b"secret").unwrap();
+            let mut chunk = MemoryStorage::read(&index_to_name(0)).unwrap();
+            let moved = settings(0, 1);
+            chunk.insert(0, 0);
+            MemoryStorage::write(&index_to_name(0), &chunk);
+
+            assert!(matches!(
+                prcsr.get_bytes(&moved, 6),
+                Err(OnDiskChunkCollectionProcessorError::CryptoError(_))
+            ));
+            assert!(prcsr.get_bytes(&original, 6).is_err());
+        }
+
+        #[test]
+        fn compressed_message_should_take_stored_length() {
+            let mut prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(1024))
+                .unwrap()
+                .with_compressor(Compressor::new(&[], DEFAULT_LEVEL))
+                .with_cipher(Cipher::from_key([1; 32]));
+            let message = "bump ".repeat(100);
+
+            let stored = prcsr.encode(message.as_bytes()).unwrap().into_owned();
+            let settings = prcsr.insert(&stored).unwrap();
+
+            assert!(stored.len() < message.len());
+            let chunk = MemoryStorage::read(&index_to_name(0)).unwrap();
+            assert_eq!(chunk.len() as u64, stored.len() as u64 + Cipher::OVERHEAD);
+            let read = prcsr.get_message(&settings, stored.len() as u64).unwrap();
+            assert_eq!(read.as_str(), message);
+        }
+
+        #[test]
+        fn find_duplicate_should_return_extent_with_same_bytes_until_removed() {
+            let mut prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(1024))
+                .unwrap()
+                .with_deduplication(vec![]);
+            let settings = prcsr.insert(b"repost").unwrap();
+
+            assert_eq!(
+                prcsr.find_duplicate(b"repost").unwrap(),
+                Some(settings.clone())
+            );
+            assert_eq!(prcsr.find_duplicate(b"report").unwrap(), None);
+
+            prcsr.remove(&settings, 6).unwrap();
+
+            assert_eq!(prcsr.find_duplicate(b"repost").unwrap(), None);
+        }
+
+        #[test]
+        fn find_duplicate_should_return_live_extents_stored_before() {
+            let mut prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(1024)).unwrap();
+            let settings = prcsr.insert(b"old post").unwrap();
+            let deleted = prcsr.insert(b"deleted post").unwrap();
+            let prcsr = MemoryProcessor::new(StorageConfig::with_chunk_size(1024))
+                .unwrap()
+                .with_deduplication(vec![(settings.clone(), 8)]);
+
+            assert_eq!(prcsr.find_duplicate(b"old post").unwrap(), Some(settings));
+            prcsr.get_bytes(&deleted, 12).unwrap();
+            assert_eq!(prcsr.find_duplicate(b"deleted post").unwrap(), None);
+        }
+
+        fn encrypted_processor() -> MemoryProcessor {
+            MemoryProcessor::new(StorageConfig::with_chunk_size(1024))
+                .unwrap()
+                .with_cipher(Cipher::from_key([1; 32]))
+        }
+    }
+
+    fn settings(chunk_index: ChunkIndex, offset: u64) -> ChunkSettings {
+        ChunkSettings {
+            chunk_index,
+            offset,
+        }
+    }
+
+    fn lock_static_mocks() -> MutexGuard<'static, ()> {
+        STATIC_MOCKS.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
     fn post() -> PostMessage {
         PostMessage::new("test".to_string())
     }
@@ -289,6 +975,6 @@ mod tests {
     }
 
     fn processor(c: MockChunkTrait) -> OnDiskChunkCollectionProcessor<MockChunkTrait> {
-        OnDiskChunkCollectionProcessor { last_chunk: c }
+        OnDiskChunkCollectionProcessor::from_last_chunk(c, Default::default())
     }
 }
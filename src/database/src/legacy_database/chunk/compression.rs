@@ -0,0 +1,120 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use zstd::{
+    bulk,
+    dict::{DecoderDictionary, EncoderDictionary},
+    stream,
+};
+
+/// Shared dictionary of the compressed database, stored next to the `index-3.json`.
+/// Database is compressed if the file exists, an empty file means no dictionary is used.
+pub const DICTIONARY_FILENAME: &str = "dictionary-3.zst";
+
+pub const DEFAULT_LEVEL: i32 = 3;
+
+/// Maximum size of the dictionary trained by [Compressor::train]
+pub const MAX_DICTIONARY_SIZE: usize = 112 * 1024;
+
+/// Per-message zstd compression with a shared dictionary.
+/// Short messages don't have enough data to compress on their own, so the dictionary
+/// trained on the existing messages is what makes the compression worth it.
+pub struct Compressor {
+    level: i32,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl std::fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Compressor")
+            .field("level", &self.level)
+            .finish()
+    }
+}
+
+impl Compressor {
+    /// Creates the compressor with the dictionary, which may be empty
+    pub fn new(dictionary: &[u8], level: i32) -> Self {
+        Compressor {
+            level,
+            encoder: EncoderDictionary::copy(dictionary, level),
+            decoder: DecoderDictionary::copy(dictionary),
+        }
+    }
+
+    /// Loads the dictionary of the database in the current directory
+    /// # Returns
+    /// `None` if the database is not compressed
+    pub fn load(level: i32) -> io::Result<Option<Self>> {
+        if !Path::new(DICTIONARY_FILENAME).exists() {
+            return Ok(None);
+        }
+
+        let dictionary = fs::read(DICTIONARY_FILENAME)?;
+        Ok(Some(Self::new(&dictionary, level)))
+    }
+
+    /// Writes the dictionary, making the database in the current directory compressed
+    pub fn save_dictionary(dictionary: &[u8]) -> io::Result<()> {
+        let mut file = File::create(DICTIONARY_FILENAME)?;
+        file.write_all(dictionary)?;
+        file.sync_data()
+    }
+
+    /// Trains the dictionary on the sample messages.
+    /// # Errors
+    /// If there are too few samples to train on
+    pub fn train(samples: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+        zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE)
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut compressor = bulk::Compressor::with_prepared_dictionary(&self.encoder)?;
+        // Dictionary is shared by the whole database and the decoder reads until the end of the frame,
+        // so there's no need to spend bytes of every message on them
+        compressor.include_dictid(false)?;
+        compressor.include_contentsize(false)?;
+        compressor.compress(data)
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoder =
+            stream::Decoder::with_prepared_dictionary(data, &self.decoder)?.single_frame();
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress_should_return_compressed_data() {
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("[g]Reply number {} to the thread about compression[/g]", i).into())
+            .collect();
+        let compressor = Compressor::new(&Compressor::train(&samples).unwrap(), DEFAULT_LEVEL);
+        let message = b"[g]Reply number 1001 to the thread about compression[/g]";
+
+        let compressed = compressor.compress(message).unwrap();
+
+        assert!(compressed.len() < message.len() / 2);
+        assert_eq!(compressor.decompress(&compressed).unwrap(), message);
+    }
+
+    #[test]
+    fn compressor_without_dictionary_should_work() {
+        let compressor = Compressor::new(&[], DEFAULT_LEVEL);
+
+        let compressed = compressor.compress(b"").unwrap();
+
+        assert!(compressor.decompress(&compressed).unwrap().is_empty());
+        assert!(compressor.decompress(&[0; 8]).is_err());
+    }
+}
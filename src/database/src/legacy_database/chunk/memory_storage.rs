@@ -0,0 +1,236 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, ErrorKind},
+    rc::Rc,
+};
+
+use super::storage::{FileMode, Storage, StorageFile};
+
+thread_local! {
+    /// Every thread has its own file system, so tests running in parallel don't see each other's files
+    static FILE_SYSTEM: RefCell<MemoryFileSystem> = RefCell::new(MemoryFileSystem::default());
+}
+
+/// Failure of the next writes made through the [MemoryStorage]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The next write stores only the first `written` bytes and fails. Later writes succeed.
+    ShortWrite { written: usize },
+
+    /// Writes fail without writing anything once the total size of the files would exceed `capacity`
+    NoSpace { capacity: u64 },
+}
+
+#[derive(Default)]
+struct MemoryFileSystem {
+    files: HashMap<String, Rc<RefCell<Vec<u8>>>>,
+    fault: Option<Fault>,
+    syncs: usize,
+}
+
+/// Files kept in memory of the current thread, with optional fault injection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStorage;
+
+#[derive(Debug)]
+pub struct MemoryFile {
+    data: Rc<RefCell<Vec<u8>>>,
+    mode: FileMode,
+}
+
+impl MemoryStorage {
+    /// Removes all files and faults of the current thread
+    pub fn reset() {
+        FILE_SYSTEM.with(|fs| *fs.borrow_mut() = MemoryFileSystem::default());
+    }
+
+    pub fn inject_fault(fault: Fault) {
+        FILE_SYSTEM.with(|fs| fs.borrow_mut().fault = Some(fault));
+    }
+
+    pub fn clear_fault() {
+        FILE_SYSTEM.with(|fs| fs.borrow_mut().fault = None);
+    }
+
+    /// Returns contents of the file, `None` if it doesn't exist
+    pub fn read(name: &str) -> Option<Vec<u8>> {
+        FILE_SYSTEM.with(|fs| {
+            fs.borrow()
+                .files
+                .get(name)
+                .map(|data| data.borrow().clone())
+        })
+    }
+
+    /// Creates the file with given contents, replacing the existing one
+    pub fn write(name: &str, contents: &[u8]) {
+        FILE_SYSTEM.with(|fs| {
+            let data = Rc::new(RefCell::new(contents.to_vec()));
+            fs.borrow_mut().files.insert(name.to_string(), data);
+        });
+    }
+
+    /// Number of `sync_data` calls made on any file
+    pub fn syncs() -> usize {
+        FILE_SYSTEM.with(|fs| fs.borrow().syncs)
+    }
+}
+
+impl Storage for MemoryStorage {
+    type File = MemoryFile;
+
+    fn open(name: &str, mode: FileMode) -> io::Result<MemoryFile> {
+        FILE_SYSTEM.with(|fs| match fs.borrow().files.get(name) {
+            Some(data) => Ok(MemoryFile {
+                data: data.clone(),
+                mode,
+            }),
+            None => Err(ErrorKind::NotFound.into()),
+        })
+    }
+
+    fn create(name: &str) -> io::Result<()> {
+        FILE_SYSTEM.with(|fs| {
+            let mut fs = fs.borrow_mut();
+            match fs.files.get(name) {
+                Some(data) => data.borrow_mut().clear(),
+                None => {
+                    fs.files.insert(name.to_string(), Default::default());
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn exists(name: &str) -> bool {
+        FILE_SYSTEM.with(|fs| fs.borrow().files.contains_key(name))
+    }
+}
+
+impl MemoryFile {
+    /// Writes data at the offset, applying the injected fault
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        if self.mode == FileMode::Read {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        let offset = offset as usize;
+        let fault = FILE_SYSTEM.with(|fs| {
+            let mut fs = fs.borrow_mut();
+            match fs.fault {
+                Some(Fault::ShortWrite { .. }) => fs.fault.take(),
+                fault => fault,
+            }
+        });
+
+        let written = match fault {
+            Some(Fault::ShortWrite { written }) => written.min(data.len()),
+            Some(Fault::NoSpace { capacity }) => {
+                let grows_by = (offset + data.len()).saturating_sub(self.data.borrow().len());
+                if total_size() + grows_by as u64 > capacity {
+                    return Err(io::Error::new(ErrorKind::StorageFull, "No space left"));
+                }
+                data.len()
+            }
+            None => data.len(),
+        };
+
+        let mut contents = self.data.borrow_mut();
+        if contents.len() < offset + written {
+            contents.resize(offset + written, 0);
+        }
+        contents[offset..offset + written].copy_from_slice(&data[..written]);
+
+        if written < data.len() {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        Ok(())
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        let contents = self.data.borrow();
+        let start = offset as usize;
+        let end = start + buffer.len();
+        if end > contents.len() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        buffer.copy_from_slice(&contents[start..end]);
+        Ok(())
+    }
+
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        self.write_at(data, offset)
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<u64> {
+        let offset = self.size()?;
+        self.write_at(data, offset)?;
+        Ok(offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.borrow().len() as u64)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        FILE_SYSTEM.with(|fs| fs.borrow_mut().syncs += 1);
+        Ok(())
+    }
+}
+
+fn total_size() -> u64 {
+    FILE_SYSTEM.with(|fs| {
+        fs.borrow()
+            .files
+            .values()
+            .map(|data| data.borrow().len() as u64)
+            .sum()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_write_should_write_prefix_once() {
+        MemoryStorage::create("file").unwrap();
+        let mut file = MemoryStorage::open("file", FileMode::Append).unwrap();
+        MemoryStorage::inject_fault(Fault::ShortWrite { written: 2 });
+
+        let err = file.append(b"hello").unwrap_err();
+        let offset = file.append(b"world").unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+        assert_eq!(offset, 2);
+        assert_eq!(MemoryStorage::read("file").unwrap(), b"heworld");
+    }
+
+    #[test]
+    fn no_space_should_fail_writes_growing_past_capacity() {
+        MemoryStorage::write("file", b"1234");
+        let file = MemoryStorage::open("file", FileMode::Write).unwrap();
+        MemoryStorage::inject_fault(Fault::NoSpace { capacity: 6 });
+
+        file.write_all_at(b"ab", 0).unwrap();
+        let err = file.write_all_at(b"abcd", 3).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(MemoryStorage::read("file").unwrap(), b"ab34");
+    }
+
+    #[test]
+    fn read_only_file_should_not_be_written() {
+        MemoryStorage::write("file", b"1234");
+        let file = MemoryStorage::open("file", FileMode::Read).unwrap();
+
+        let err = file.write_all_at(b"ab", 0).unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(MemoryStorage::open("other", FileMode::Read).is_err());
+    }
+}
@@ -1,15 +1,20 @@
+#[allow(clippy::module_inception)]
 mod chunk;
 pub mod chunk_processor;
+pub mod compression;
+#[cfg(any(test, feature = "test-storage"))]
+pub mod memory_storage;
+pub mod storage;
 
+pub use chunk::Chunk;
 pub use chunk::ChunkError;
 pub use chunk::ChunkIndex;
-
-use self::chunk::Chunk;
+pub use chunk::CHUNK_EXT;
 
 pub fn chunk_name_to_index(name: String) -> ChunkIndex {
-    Chunk::name_to_index(name)
+    chunk::name_to_index(name)
 }
 
 pub fn chunk_index_to_name(index: ChunkIndex) -> String {
-    Chunk::index_to_name(index)
+    chunk::index_to_name(index)
 }
@@ -0,0 +1,144 @@
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// How a storage file is opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    Write,
+    Append,
+    Read,
+}
+
+/// File system the chunks are stored in.
+/// Methods are static, the same way chunks are opened by their index only.
+pub trait Storage {
+    type File: StorageFile + Debug;
+
+    /// Opens an existing file
+    fn open(name: &str, mode: FileMode) -> io::Result<Self::File>;
+
+    /// Creates an empty file, truncating the existing one
+    fn create(name: &str) -> io::Result<()>;
+
+    fn exists(name: &str) -> bool;
+}
+
+/// File opened by the [Storage]
+pub trait StorageFile {
+    /// Reads exactly `buffer.len()` bytes starting from `offset`
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes the whole buffer starting from `offset`
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Writes the whole buffer at the end of the file
+    /// # Returns
+    /// Offset the data was written at
+    fn append(&mut self, data: &[u8]) -> io::Result<u64>;
+
+    /// File size in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// Flushes written data to the disk
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Reserves disk space for the file to grow up to `length` bytes without changing its size.
+    /// Storages which can't reserve the space ignore the call.
+    fn preallocate(&self, _length: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Files in the current directory
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskStorage;
+
+impl Storage for DiskStorage {
+    type File = File;
+
+    fn open(name: &str, mode: FileMode) -> io::Result<File> {
+        match mode {
+            FileMode::Append => OpenOptions::new().append(true).open(name),
+            FileMode::Write => OpenOptions::new().write(true).open(name),
+            FileMode::Read => OpenOptions::new().read(true).open(name),
+        }
+    }
+
+    fn create(name: &str) -> io::Result<()> {
+        File::create(name)?;
+        Ok(())
+    }
+
+    fn exists(name: &str) -> bool {
+        Path::new(name).exists()
+    }
+}
+
+impl StorageFile for File {
+    #[cfg(unix)]
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buffer, offset)
+    }
+
+    /// Moves the file cursor, so it must not be shared with other readers
+    #[cfg(not(unix))]
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        use std::io::Read;
+
+        let mut file = self;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buffer)
+    }
+
+    #[cfg(unix)]
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, data, offset)
+    }
+
+    #[cfg(not(unix))]
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let mut file = self;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<u64> {
+        let offset = self.seek(SeekFrom::End(0))?;
+        self.write_all(data)?;
+        Ok(offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn preallocate(&self, length: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let result = unsafe {
+            libc::fallocate(
+                self.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                0,
+                length as libc::off_t,
+            )
+        };
+        match result {
+            0 => Ok(()),
+            _ => match io::Error::last_os_error() {
+                // File system doesn't support the reservation, it's only an optimization anyway
+                err if err.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+                err => Err(err),
+            },
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::{collections::HashSet, time::Duration};
+
+use super::crypto::Cipher;
+
+/// Defines when written data is flushed to the disk with `fsync`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Data is never synced explicitly, the OS decides when it lands on the disk
+    None,
+
+    /// Chunk data and diff lines are synced once per database operation (a single post or a whole batch).
+    /// Chunk data is always synced before the diff lines which reference it.
+    #[default]
+    PerBatch,
+
+    /// Every chunk write is synced right after it's made, in addition to syncing the diff once per operation
+    PerWrite,
+}
+
+/// How the space of deleted posts is picked for new posts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FitStrategy {
+    /// Smallest free space the post fits into, which leaves the least unused bytes
+    #[default]
+    BestFit,
+
+    /// Free space closest to the start of the database the post fits into, which keeps the data packed towards
+    /// the first chunks. Every free space is checked, same as for the [`FitStrategy::BestFit`].
+    FirstFit,
+
+    /// Space is never reused, new posts are always appended
+    NeverReuse,
+}
+
+/// Layout of the chunk files, see [`OnDiskChunkCollectionProcessor::new`](super::chunk::chunk_processor::OnDiskChunkCollectionProcessor::new)
+#[derive(Debug, Clone, Default)]
+pub struct StorageConfig {
+    /// Size in bytes after which the next chunk file is started, 1 GB if not set
+    pub chunk_size: Option<u64>,
+
+    pub fit_strategy: FitStrategy,
+
+    /// Disk space in bytes reserved ahead of the chunk data, so the file system can keep the file contiguous.
+    /// Reserved space doesn't change the file size, `0` disables the reservation.
+    pub preallocate: u64,
+}
+
+impl StorageConfig {
+    pub fn with_chunk_size(chunk_size: u64) -> Self {
+        StorageConfig {
+            chunk_size: Some(chunk_size),
+            ..Default::default()
+        }
+    }
+}
+
+/// Layout of `index-3.json` written by checkpoints and snapshots. Index of either layout is read whatever is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexFormat {
+    /// JSON of the legacy database
+    #[default]
+    Json,
+
+    /// Compact binary layout, which is less than half of the JSON size and several times faster to load.
+    /// Legacy clients can't read it.
+    Binary,
+}
+
+/// Order in which threads are evicted when the database exceeds the limits of the [`RetentionPolicy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionOrder {
+    /// Threads without new posts for the longest time go first
+    #[default]
+    Oldest,
+
+    /// Threads with the fewest replies go first, the oldest of them if there's a tie
+    LeastReplied,
+}
+
+/// Limits of the stored posts, enforced by [`LegacyDatabase::enforce_retention`](super::database::LegacyDatabase::enforce_retention)
+/// by deleting whole threads. Every limit is disabled by default.
+///
+/// Limits are enforced after every put once the thread index is enabled, see `enforce_retention`.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Maximum total length of live post messages
+    pub max_bytes: Option<u64>,
+
+    /// Maximum number of live posts
+    pub max_posts: Option<usize>,
+
+    /// Threads without new posts for longer than this are deleted even if the database is within the limits
+    pub max_age: Option<Duration>,
+
+    pub eviction: EvictionOrder,
+
+    /// Threads which are never evicted, in addition to the ones flagged as `pinned` in their metadata
+    pub protected: HashSet<String>,
+}
+
+impl RetentionPolicy {
+    /// Whether any limit is set, so the policy may evict threads
+    pub fn has_limits(&self) -> bool {
+        self.max_bytes.is_some() || self.max_posts.is_some() || self.max_age.is_some()
+    }
+}
+
+/// Settings of the [`LegacyDatabase`](super::database::LegacyDatabase)
+#[derive(Debug, Clone, Default)]
+pub struct LegacyDatabaseConfig {
+    pub durability: Durability,
+
+    /// How long messages of deleted posts are kept in the trash, so the posts can be restored.
+    /// `None` disables the trash, messages are erased right away.
+    pub trash_retention: Option<Duration>,
+
+    /// Cipher `index-3.json` is written with by checkpoints and snapshots.
+    /// Must be set if the chunk processor and the diff are encrypted.
+    pub cipher: Option<Cipher>,
+
+    pub index_format: IndexFormat,
+
+    pub retention: RetentionPolicy,
+
+    /// Posts which have no parent in the database by design, so they're not reported as orphans by
+    /// [`LegacyDatabase::check`](super::database::LegacyDatabase::check)
+    pub roots: HashSet<String>,
+}
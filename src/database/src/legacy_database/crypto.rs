@@ -0,0 +1,244 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Write},
+    path::Path,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, OsRng, Payload},
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Salt and passphrase check, stored next to the `index-3.json`
+pub const KEY_FILENAME: &str = "key-3.json";
+
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+const TAG_LENGTH: usize = 16;
+
+/// Sealed by the derived key to check that the passphrase is correct
+const KEY_CHECK: &[u8] = b"rustyboard key check";
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+
+    #[error("Error (de)serializing key file")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("Key file is corrupt")]
+    Base64Error(#[from] base64::DecodeError),
+
+    #[error("Key derivation failed: {0}")]
+    KeyDerivation(String),
+
+    #[error("Passphrase is wrong")]
+    WrongPassphrase,
+
+    #[error("Data can't be decrypted, it's either corrupt or encrypted with another key")]
+    Decryption,
+}
+
+pub type CryptoResult<T> = Result<T, CryptoError>;
+
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    /// Base64 encoded salt of the key derivation
+    salt: String,
+
+    /// Base64 encoded [KEY_CHECK] sealed by the key
+    check: String,
+}
+
+/// Authenticated encryption of the data at rest with a passphrase derived key.
+///
+/// Every sealed piece of data gets its own random nonce, which is stored in front of it along with the tag at the end,
+/// so sealed data is [Cipher::OVERHEAD] bytes longer than the plain one.
+///
+/// Covers chunk extents, diff lines, `index-3.json` and the sidecar files (search index, trash, metadata).
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cipher")
+    }
+}
+
+impl Cipher {
+    /// Number of bytes sealing adds to the data
+    pub const OVERHEAD: u64 = (NONCE_LENGTH + TAG_LENGTH) as u64;
+
+    pub fn from_key(key: [u8; 32]) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    /// Derives the key from the passphrase with Argon2id
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> CryptoResult<Self> {
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| CryptoError::KeyDerivation(err.to_string()))?;
+
+        Ok(Self::from_key(key))
+    }
+
+    /// Derives the key using the salt from `key-3.json` and checks the passphrase.
+    /// If the key file doesn't exist, it's created with a new random salt.
+    /// # Errors
+    /// [CryptoError::WrongPassphrase] if the passphrase doesn't match the one the key file was created with
+    pub fn unlock(passphrase: &str) -> CryptoResult<Self> {
+        if !Self::key_file_exists() {
+            let mut salt = [0; SALT_LENGTH];
+            OsRng.fill_bytes(&mut salt);
+            let cipher = Self::from_passphrase(passphrase, &salt)?;
+            let key_file = KeyFile {
+                salt: base64::encode(salt),
+                check: base64::encode(cipher.seal(KEY_CHECK, KEY_FILENAME.as_bytes())),
+            };
+
+            let mut file = File::create(KEY_FILENAME)?;
+            file.write_all(&serde_json::to_vec(&key_file)?)?;
+            file.sync_data()?;
+            return Ok(cipher);
+        }
+
+        let key_file: KeyFile = serde_json::from_reader(BufReader::new(File::open(KEY_FILENAME)?))?;
+        let cipher = Self::from_passphrase(passphrase, &base64::decode(key_file.salt)?)?;
+        match cipher.open(&base64::decode(key_file.check)?, KEY_FILENAME.as_bytes()) {
+            Ok(check) if check == KEY_CHECK => Ok(cipher),
+            _ => Err(CryptoError::WrongPassphrase),
+        }
+    }
+
+    /// Is the database in the current directory encrypted
+    pub fn key_file_exists() -> bool {
+        Path::new(KEY_FILENAME).exists()
+    }
+
+    /// Encrypts the data with a new random nonce.
+    /// `aad` is authenticated, but not stored, the same value must be passed to [Cipher::open].
+    /// # Returns
+    /// Nonce, encrypted data and tag
+    pub fn seal(&self, plain: &[u8], aad: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload { msg: plain, aad };
+        let encrypted = self
+            .aead
+            .encrypt(&nonce, payload)
+            .expect("Encryption fails only if the data is too large for the cipher");
+
+        let mut sealed = Vec::with_capacity(plain.len() + Self::OVERHEAD as usize);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&encrypted);
+        sealed
+    }
+
+    /// Decrypts and authenticates the data sealed by [Cipher::seal]
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+        if sealed.len() < Self::OVERHEAD as usize {
+            return Err(CryptoError::Decryption);
+        }
+
+        let (nonce, encrypted) = sealed.split_at(NONCE_LENGTH);
+        let payload = Payload {
+            msg: encrypted,
+            aad,
+        };
+        self.aead
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| CryptoError::Decryption)
+    }
+
+    /// Seals the line of a line-based file, e.g. the diff, base64 encoded so it has no line breaks
+    pub fn seal_line(&self, plain: &[u8], aad: &[u8]) -> String {
+        base64::encode(self.seal(plain, aad))
+    }
+
+    /// Decrypts the line sealed by [Cipher::seal_line]
+    pub fn open_line(&self, line: &[u8], aad: &[u8]) -> CryptoResult<Vec<u8>> {
+        let sealed = base64::decode(line).map_err(|_| CryptoError::Decryption)?;
+        self.open(&sealed, aad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        in_temp_dir,
+        legacy_database::index::serialized::{DbPostRefSerialized, IndexCollection},
+    };
+    use rusty_fork::rusty_fork_test;
+
+    #[test]
+    fn open_should_return_sealed_data() {
+        let cipher = Cipher::from_key([7; 32]);
+
+        let sealed = cipher.seal(b"message", b"aad");
+
+        assert_eq!(sealed.len() as u64, 7 + Cipher::OVERHEAD);
+        assert_eq!(cipher.open(&sealed, b"aad").unwrap(), b"message");
+    }
+
+    #[test]
+    fn open_should_fail_on_tampered_data_aad_or_key() {
+        let cipher = Cipher::from_key([7; 32]);
+        let mut sealed = cipher.seal(b"message", b"aad");
+
+        assert!(cipher.open(&sealed, b"other").is_err());
+        assert!(Cipher::from_key([8; 32]).open(&sealed, b"aad").is_err());
+        sealed[30] ^= 1;
+        assert!(matches!(
+            cipher.open(&sealed, b"aad"),
+            Err(CryptoError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn encrypted_index_should_be_read_back() {
+        let cipher = Cipher::from_key([7; 32]);
+        let index = IndexCollection {
+            indexes: vec![DbPostRefSerialized {
+                hash: "1".to_string(),
+                reply_to: "0".to_string(),
+                offset: 0,
+                length: 5,
+                deleted: false,
+                chunk_name: Some("0.db3".to_string()),
+            }],
+        };
+
+        let sealed = index.to_encrypted(&cipher).unwrap();
+        let read = IndexCollection::from_encrypted(&sealed, &cipher).unwrap();
+
+        assert_eq!(read.indexes, index.indexes);
+        assert!(IndexCollection::from_encrypted(&sealed, &Cipher::from_key([8; 32])).is_err());
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn unlock_should_create_key_file_and_check_passphrase() {
+            in_temp_dir!({
+                let cipher = Cipher::unlock("secret").unwrap();
+                let sealed = cipher.seal(b"message", b"");
+
+                let reopened = Cipher::unlock("secret").unwrap();
+
+                assert_eq!(reopened.open(&sealed, b"").unwrap(), b"message");
+                assert!(matches!(
+                    Cipher::unlock("wrong"),
+                    Err(CryptoError::WrongPassphrase)
+                ));
+            });
+        }
+    }
+}
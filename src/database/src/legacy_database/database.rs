@@ -1,16 +1,39 @@
-use std::io;
+use std::{collections::HashSet, io, sync::mpsc::Sender};
 
 use super::{
-    chunk::{chunk_processor::ChunkCollectionProcessor, ChunkError},
+    chunk::{
+        chunk_processor::{ChunkCollectionProcessor, OnDiskChunkCollectionProcessorError},
+        ChunkError,
+    },
+    config::{Durability, LegacyDatabaseConfig},
+    crypto::CryptoError,
     index::{
+        ban_list::{BanList, BanListError, BanRule},
+        db_post_ref::DbPostRefHash,
         diff::{Diff, DiffFileError},
+        index_file::IndexFileError,
+        metadata::{MetadataStore, MetadataStoreError},
+        search::{SearchIndex, SearchIndexError},
+        threads::{is_sage, ThreadIndex},
+        trash::{Trash, TrashEntry, TrashError},
         DbRefCollection, DbRefCollectionError,
     },
 };
-use crate::{post::Post, post_database::Database};
+use crate::{
+    post::{unix_time_now, Post, PostEntry, PostMetadata},
+    post_database::{Database, PutPostsReport},
+};
 
 use thiserror::Error;
 
+pub mod check;
+pub mod events;
+pub mod retention;
+pub mod snapshot;
+pub mod stats;
+
+use events::DatabaseEvent;
+
 #[derive(Debug, Error)]
 pub enum LegacyDatabaseError {
     #[error("Chunk error")]
@@ -19,6 +42,9 @@ pub enum LegacyDatabaseError {
         source: ChunkError,
     },
 
+    #[error("Chunk processor error")]
+    ChunkProcessorError(#[from] OnDiskChunkCollectionProcessorError),
+
     #[error("IO error")]
     IoError {
         #[from]
@@ -48,6 +74,39 @@ pub enum LegacyDatabaseError {
 
     #[error("Error processing DbReferenceCollection")]
     DbRefCollectionError(#[from] DbRefCollectionError),
+
+    #[error("Error processing search index")]
+    SearchIndexError(#[from] SearchIndexError),
+
+    #[error("Search index is not enabled")]
+    SearchIndexDisabled,
+
+    #[error("Thread index is not enabled")]
+    ThreadIndexDisabled,
+
+    #[error("Error processing post metadata")]
+    MetadataError(#[from] MetadataStoreError),
+
+    #[error("Post is banned")]
+    PostBanned,
+
+    #[error("Error processing ban list")]
+    BanListError(#[from] BanListError),
+
+    #[error("Post is not in the trash")]
+    PostNotInTrash,
+
+    #[error("Error processing trash")]
+    TrashError(#[from] TrashError),
+
+    #[error("Encryption error")]
+    CryptoError(#[from] CryptoError),
+
+    #[error("Error reading index")]
+    IndexFileError(#[from] IndexFileError),
+
+    #[error("Destination doesn't contain a complete snapshot")]
+    NoSnapshot,
 }
 
 pub type LegacyDatabaseResult<T> = Result<T, LegacyDatabaseError>;
@@ -59,6 +118,57 @@ where
 {
     reference: DbRefCollection<TDiff>,
     chunk_processor: TProcessor,
+    config: LegacyDatabaseConfig,
+
+    /// Full-text index of live posts, `None` if search is not enabled
+    search_index: Option<SearchIndex>,
+
+    /// Search index changes made by the current transaction, applied when it's committed
+    pending_search: Vec<SearchUpdate>,
+
+    /// Threads of the categories in bump order, `None` if not enabled
+    thread_index: Option<ThreadIndex>,
+
+    /// Thread index changes made by the current transaction, applied when it's committed
+    pending_threads: Vec<ThreadUpdate>,
+
+    metadata: MetadataStore,
+
+    /// Metadata of the posts inserted by the current transaction, stored when it's committed
+    pending_metadata: Vec<(String, PostMetadata)>,
+
+    ban_list: BanList,
+
+    /// Messages of deleted posts, filled only if [`LegacyDatabaseConfig::trash_retention`] is set
+    trash: Trash,
+
+    /// Posts deleted by the current transaction, put into the trash when it's committed
+    pending_trash: Vec<TrashEntry>,
+
+    /// Senders of the receivers returned by [`LegacyDatabase::subscribe`]
+    subscribers: Vec<Sender<DatabaseEvent>>,
+
+    /// Events of the current transaction, published when it's committed
+    pending_events: Vec<DatabaseEvent>,
+
+    /// Failure to write the sidecars, to sync the diff or to enforce the retention after the last transaction was committed
+    sidecar_error: Option<LegacyDatabaseError>,
+}
+
+enum SearchUpdate {
+    Insert { hash: String, message: String },
+    Remove { hash: String },
+}
+
+enum ThreadUpdate {
+    Insert {
+        hash: String,
+        parent: String,
+        bump: bool,
+    },
+    Remove {
+        hash: String,
+    },
 }
 
 impl<TProcessor, TDiff> LegacyDatabase<TProcessor, TDiff>
@@ -68,28 +178,610 @@ where
     TDiff: Diff,
 {
     pub fn new(reference: DbRefCollection<TDiff>, chunk_processor: TProcessor) -> Self {
-        LegacyDatabase {
+        Self::with_config(reference, chunk_processor, Default::default())
+    }
+
+    /// Creates the database over the opened references and chunks.
+    /// If [`LegacyDatabaseConfig::trash_retention`] is set, the trash is loaded from the current directory.
+    pub fn with_config(
+        mut reference: DbRefCollection<TDiff>,
+        chunk_processor: TProcessor,
+        config: LegacyDatabaseConfig,
+    ) -> Self {
+        reference.set_fit_strategy(chunk_processor.fit_strategy());
+        let mut db = LegacyDatabase {
             reference,
             chunk_processor,
+            config,
+            search_index: None,
+            pending_search: Vec::new(),
+            thread_index: None,
+            pending_threads: Vec::new(),
+            metadata: MetadataStore::default(),
+            pending_metadata: Vec::new(),
+            ban_list: BanList::default(),
+            trash: Trash::default(),
+            pending_trash: Vec::new(),
+            subscribers: Vec::new(),
+            pending_events: Vec::new(),
+            sidecar_error: None,
+        };
+        if db.config.trash_retention.is_some() {
+            db.load_trash();
         }
+
+        db
+    }
+
+    /// Loads `trash-3.list` of the enabled trash from the current directory, sealed by the cipher if it's set.
+    /// If it can't be loaded, the trash is kept in memory only and the error is returned by
+    /// [`LegacyDatabase::sidecar_error`].
+    fn load_trash(&mut self) {
+        let trash = match &self.config.cipher {
+            Some(cipher) => Trash::open_encrypted(cipher.clone()),
+            None => Trash::open(),
+        };
+        match trash {
+            Ok(trash) => self.trash = trash,
+            Err(err) => self.sidecar_error = Some(err.into()),
+        }
+    }
+
+    /// Replaces the in-memory trash, e.g. with the one loaded by [`Trash::open`],
+    /// or by [`Trash::open_encrypted`] if the database is encrypted
+    pub fn set_trash(&mut self, trash: Trash) {
+        self.trash = trash;
+    }
+
+    pub fn trash(&self) -> &Trash {
+        &self.trash
+    }
+
+    /// Removes posts which were deleted longer than [`LegacyDatabaseConfig::trash_retention`] ago from the trash,
+    /// so they can't be restored anymore. If the trash is disabled, it's emptied.
+    /// # Returns
+    /// Hashes of the purged posts
+    pub fn purge_trash(&mut self) -> LegacyDatabaseResult<Vec<String>> {
+        let deleted_before = match self.config.trash_retention {
+            Some(retention) => unix_time_now().saturating_sub(retention.as_secs()),
+            None => u64::MAX,
+        };
+
+        Ok(self.trash.purge(deleted_before)?)
+    }
+
+    /// Replaces the ban list, e.g. with the one loaded by [`BanList::load`]
+    pub fn set_ban_list(&mut self, ban_list: BanList) {
+        self.ban_list = ban_list;
+    }
+
+    pub fn ban_list(&self) -> &BanList {
+        &self.ban_list
     }
 
-    fn upsert_post(&mut self, post: Post) -> Result<(), LegacyDatabaseError> {
+    /// Adds the rule to the ban list. Posts which are already in the database are left as is.
+    pub fn ban(&mut self, rule: BanRule) -> LegacyDatabaseResult<()> {
+        self.ban_list.add(rule)?;
+        Ok(())
+    }
+
+    pub fn unban(&mut self, rule: &BanRule) -> bool {
+        self.ban_list.remove(rule)
+    }
+
+    /// Writes the ban list with the rejected posts next to the `index-3.json`
+    pub fn save_ban_list(&self) -> LegacyDatabaseResult<()> {
+        Ok(self.ban_list.save()?)
+    }
+
+    /// Checks the post against the ban list.
+    /// Post is banned if its hash or message is banned, if it or any of its known ancestors is a banned thread,
+    /// or if any of its ancestors was rejected by a thread ban before, see [`BanList::reject`].
+    /// # Returns
+    /// Rule the post is banned by
+    fn banned_by(&self, post: &Post) -> Option<BanRule> {
+        if self.ban_list.is_post_banned(&post.hash) {
+            return Some(BanRule::Post(post.hash.clone()));
+        }
+
+        if let Some(pattern) = self.ban_list.matching_pattern(post.message.as_str()) {
+            return Some(BanRule::Pattern(pattern.to_string()));
+        }
+
+        if self.ban_list.is_thread_banned(&post.hash) {
+            return Some(BanRule::Thread(post.hash.clone()));
+        }
+
+        let mut visited = HashSet::new();
+        let mut ancestor = DbPostRefHash::new(&post.reply_to);
+        // Parent chain of corrupted index may contain cycles
+        while visited.insert(ancestor.clone()) {
+            let hash = ancestor.to_string();
+            if self.ban_list.is_thread_banned(&hash) {
+                return Some(BanRule::Thread(hash));
+            }
+
+            if let Some(rule) = self.ban_list.rejected_by(&hash) {
+                return Some(rule.clone());
+            }
+
+            match self.reference.get_ref(&ancestor) {
+                Some(db_ref) => ancestor = db_ref.parent_hash.clone(),
+                None => break,
+            }
+        }
+
+        None
+    }
+
+    /// Remembers the post as rejected if it's banned as a part of a thread, so its replies are rejected as well
+    fn reject_if_banned(&mut self, post: &Post) -> bool {
+        match self.banned_by(post) {
+            Some(rule) => {
+                self.ban_list.reject(post.hash.clone(), rule);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces the in-memory metadata store, e.g. with the one loaded by [`MetadataStore::open`],
+    /// or by [`MetadataStore::open_encrypted`] if the database is encrypted
+    pub fn set_metadata_store(&mut self, metadata: MetadataStore) {
+        self.metadata = metadata;
+    }
+
+    /// Error of writing the metadata or the trash, of syncing the diff, or of enforcing the retention policy,
+    /// after the last change was committed.
+    /// Committed posts are kept and their metadata is available, so the change must not be retried.
+    /// Unwritten sidecar changes are written by the next change or by [`LegacyDatabase::flush_sidecars`].
+    pub fn sidecar_error(&self) -> Option<&LegacyDatabaseError> {
+        self.sidecar_error.as_ref()
+    }
+
+    /// Writes the metadata and trash changes which failed to be written after their transaction was committed,
+    /// and syncs them along with the diff
+    pub fn flush_sidecars(&mut self) -> LegacyDatabaseResult<()> {
+        self.metadata.flush()?;
+        self.trash.flush()?;
+        self.sync_sidecars()?;
+        self.sidecar_error = None;
+        Ok(())
+    }
+
+    /// Rewrites the metadata file, dropping outdated entries
+    pub fn compact_metadata(&mut self) -> LegacyDatabaseResult<()> {
+        Ok(self.metadata.compact()?)
+    }
+
+    /// Enables full-text search. The index is kept up to date on every put and delete.
+    ///
+    /// Index of the encrypted database is loaded with [`SearchIndex::load_encrypted`].
+    /// Index loaded with [`SearchIndex::load`] misses the changes made after it was saved, so it's brought up to date:
+    /// live posts which are not indexed are indexed, and posts which are not live anymore are removed.
+    pub fn enable_search(&mut self, mut index: SearchIndex) -> LegacyDatabaseResult<()> {
+        let outdated: Vec<String> = index
+            .hashes()
+            .filter(|hash| !self.reference.ref_exists(*hash) || self.reference.ref_deleted(*hash))
+            .cloned()
+            .collect();
+        for hash in outdated {
+            index.remove(&hash);
+        }
+
+        for (hash, db_ref) in self.reference.iter() {
+            let hash = hash.to_string();
+            if db_ref.deleted || index.contains(&hash) {
+                continue;
+            }
+
+            let settings = db_ref
+                .chunk_settings
+                .as_ref()
+                .ok_or_else(|| LegacyDatabaseError::EntryCorrupted(hash.clone()))?;
+            let bytes = self.chunk_processor.get_bytes(settings, db_ref.length)?;
+            index.insert(&hash, &String::from_utf8_lossy(&bytes));
+        }
+
+        self.search_index = Some(index);
+        Ok(())
+    }
+
+    /// Indexes every live post from scratch and enables search
+    pub fn rebuild_search_index(&mut self) -> LegacyDatabaseResult<()> {
+        self.enable_search(SearchIndex::default())
+    }
+
+    /// Writes the search index next to the `index-3.json`, sealed by [`LegacyDatabaseConfig::cipher`] if it's set
+    pub fn save_search_index(&self) -> LegacyDatabaseResult<()> {
+        let index = self
+            .search_index
+            .as_ref()
+            .ok_or(LegacyDatabaseError::SearchIndexDisabled)?;
+        match &self.config.cipher {
+            Some(cipher) => index.save_encrypted(cipher)?,
+            None => index.save()?,
+        }
+        Ok(())
+    }
+
+    /// Finds live posts by their message, see [`SearchIndex::search`] for the query syntax.
+    /// # Returns
+    /// Up to `limit` post hashes, most relevant first
+    pub fn search(&self, query: &str, limit: usize) -> LegacyDatabaseResult<Vec<String>> {
+        let index = self
+            .search_index
+            .as_ref()
+            .ok_or(LegacyDatabaseError::SearchIndexDisabled)?;
+
+        let found = index
+            .search(query, usize::MAX)
+            .into_iter()
+            .filter(|hash| self.reference.ref_exists(hash) && !self.reference.ref_deleted(hash))
+            .take(limit)
+            .collect();
+
+        Ok(found)
+    }
+
+    /// Enables thread lists of the categories, built from the posts which are already in the database.
+    /// Posts are bumped by the time they were received, see [`PostMetadata::received_at`].
+    ///
+    /// Replies to the posts which were not received yet are indexed too, so they bump their thread
+    /// once their parent arrives.
+    pub fn enable_thread_index(
+        &mut self,
+        categories: impl IntoIterator<Item = String>,
+    ) -> LegacyDatabaseResult<()> {
+        let mut index = ThreadIndex::new(categories).with_roots(self.config.roots.clone());
+        let roots: Vec<DbPostRefHash> = self
+            .reference
+            .iter()
+            .filter(|(_, db_ref)| index.is_category(&db_ref.parent_hash.to_string()))
+            .map(|(hash, _)| hash.clone())
+            .chain(self.reference.list_orphans())
+            .collect();
+
+        let mut indexed = HashSet::new();
+        for root in roots {
+            // Subtree goes from parents to replies, so every reply finds its thread
+            for hash in self.reference.subtree(&root) {
+                if index.is_category(&hash.to_string()) || !indexed.insert(hash.clone()) {
+                    continue;
+                }
+
+                let db_ref = self.reference.get_ref(&hash).unwrap();
+                let hash = hash.to_string();
+                let received_at = self.metadata.get(&hash).map_or(0, |meta| meta.received_at);
+                let bump = match &db_ref.chunk_settings {
+                    Some(settings) if !db_ref.deleted => {
+                        let bytes = self.chunk_processor.get_bytes(settings, db_ref.length)?;
+                        !is_sage(&String::from_utf8_lossy(&bytes))
+                    }
+                    _ => false,
+                };
+                let parent = db_ref.parent_hash.to_string();
+                if is_outside_categories(&index, &self.reference, &parent, &HashSet::new()) {
+                    index.skip(&hash);
+                    continue;
+                }
+                index.insert(&hash, &parent, received_at, bump);
+                if db_ref.deleted {
+                    index.remove(&hash);
+                }
+            }
+        }
+
+        self.thread_index = Some(index);
+        Ok(())
+    }
+
+    /// Returns threads of the category, last bumped first
+    pub fn threads(&self, category: &str) -> LegacyDatabaseResult<Vec<String>> {
+        let index = self
+            .thread_index
+            .as_ref()
+            .ok_or(LegacyDatabaseError::ThreadIndexDisabled)?;
+        Ok(index.threads(category))
+    }
+
+    /// Puts the post into the current transaction, recording `source` in its metadata if it's set
+    fn upsert_post(&mut self, post: Post, source: Option<&str>) -> Result<(), LegacyDatabaseError> {
         //todo validate post
-        let (hash, message) = self.reference.put_post(post)?;
-        let db_ref = self.reference.get_ref_mut(&hash).unwrap();
-        match &db_ref.chunk_settings {
+        let stored = self
+            .chunk_processor
+            .encode(post.message.as_bytes())?
+            .into_owned();
+        let shared = match self.chunk_processor.find_duplicate(&stored)? {
+            Some(settings) if self.reference.extent_referrers(&settings) > 0 => Some(settings),
+            _ => None,
+        };
+        let is_shared = shared.is_some();
+        let restored = self.reference.ref_exists(&post.hash);
+        let orphans: Vec<String> = if self.reference.is_missing_parent(&post.hash) {
+            self.reference
+                .replies(&post.hash)
+                .iter()
+                .map(|reply| reply.to_string())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let parent = post.reply_to.clone();
+        let (hash, message) = self
+            .reference
+            .put_stored_post(post, stored.len() as u64, shared)?;
+        let hash = hash.to_string();
+        if self.search_index.is_some() {
+            self.pending_search.push(SearchUpdate::Insert {
+                hash: hash.to_string(),
+                message: message.as_str().to_string(),
+            });
+        }
+        if self.thread_index.is_some() {
+            self.pending_threads.push(ThreadUpdate::Insert {
+                hash: hash.to_string(),
+                parent: parent.clone(),
+                bump: !is_sage(message.as_str()),
+            });
+        }
+
+        let event = if restored {
+            DatabaseEvent::PostRestored {
+                hash: hash.to_string(),
+                parent,
+            }
+        } else {
+            DatabaseEvent::PostAdded {
+                hash: hash.to_string(),
+                parent,
+            }
+        };
+        self.pending_events.push(event);
+        if !orphans.is_empty() {
+            self.pending_events.push(DatabaseEvent::ParentArrived {
+                hash: hash.to_string(),
+                orphans,
+            });
+        }
+
+        let metadata = match (self.metadata.get(&hash), source) {
+            (None, _) => Some(PostMetadata::received_now()),
+            (Some(metadata), Some(_)) => Some(metadata.clone()),
+            (Some(_), None) => None,
+        };
+        if let Some(mut metadata) = metadata {
+            if let Some(source) = source {
+                metadata.source = Some(source.to_string());
+            }
+            self.pending_metadata.push((hash.to_string(), metadata));
+        }
+
+        // Shared extent already holds the message
+        if is_shared {
+            return Ok(());
+        }
+
+        match &self.reference.get_ref(&hash).unwrap().chunk_settings {
             Some(settings) => {
                 self.chunk_processor
-                    .insert_into_existing(&settings, &message)?;
+                    .insert_into_existing(settings, &stored)?;
             }
             None => {
-                let chunk_settings = self.chunk_processor.insert(&message)?;
-                db_ref.chunk_settings = Some(chunk_settings);
+                let chunk_settings = self.chunk_processor.insert(&stored)?;
+                self.reference.set_chunk_settings(&hash, chunk_settings)?;
             }
         };
+
+        if self.config.durability == Durability::PerWrite {
+            self.chunk_processor.sync()?;
+        }
+        Ok(())
+    }
+
+    fn put_batch(
+        &mut self,
+        posts: Vec<Post>,
+        source: Option<&str>,
+    ) -> LegacyDatabaseResult<PutPostsReport> {
+        let report = self.in_transaction(|db| {
+            let mut report = PutPostsReport::default();
+            for post in posts {
+                if db.reference.ref_exists(&post.hash) {
+                    report.duplicates.push(post.hash);
+                    continue;
+                }
+
+                if db.reject_if_banned(&post) {
+                    report.banned.push(post.hash);
+                    continue;
+                }
+
+                let hash = post.hash.clone();
+                db.upsert_post(post, source)?;
+                report.inserted.push(hash);
+            }
+
+            Ok(report)
+        })?;
+
+        self.enforce_retention_after_put();
+        Ok(report)
+    }
+
+    /// Runs `action` inside of the reference collection transaction.
+    /// If `action` fails, all reference changes made by it are rolled back.
+    ///
+    /// Chunk data written by `action` is synced before the diff lines are written,
+    /// so the diff never references data which is not on the disk yet.
+    /// Once the transaction is committed, the call succeeds, see [`LegacyDatabase::sidecar_error`].
+    fn in_transaction<T>(
+        &mut self,
+        action: impl FnOnce(&mut Self) -> LegacyDatabaseResult<T>,
+    ) -> LegacyDatabaseResult<T> {
+        self.reference.begin_transaction()?;
+        let result = action(self).and_then(|result| {
+            if self.config.durability != Durability::None {
+                self.chunk_processor.sync()?;
+            }
+            Ok(result)
+        });
+
+        match result {
+            Ok(result) => {
+                if let Err(err) = self.reference.commit_transaction() {
+                    self.clear_pending();
+                    return Err(err.into());
+                }
+                // Changes are committed, so subscribers learn about them even if the sidecars fail below
+                let events = std::mem::take(&mut self.pending_events);
+                self.publish_events(events);
+                self.apply_pending_search();
+                self.apply_pending_threads();
+                self.persist_committed();
+                Ok(result)
+            }
+            Err(err) => {
+                self.clear_pending();
+                self.reference.rollback_transaction()?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Writes the metadata and trash changes of the committed transaction and syncs them along with the diff.
+    /// Failure doesn't undo the transaction, it's kept in `sidecar_error` and unwritten changes are retried later.
+    fn persist_committed(&mut self) {
+        let metadata = self
+            .metadata
+            .append(std::mem::take(&mut self.pending_metadata));
+        let trash = self.trash.append(std::mem::take(&mut self.pending_trash));
+        self.sidecar_error = metadata
+            .map_err(LegacyDatabaseError::MetadataError)
+            .and(trash.map_err(LegacyDatabaseError::TrashError))
+            .and_then(|_| self.sync_sidecars())
+            .err();
+    }
+
+    fn sync_sidecars(&mut self) -> LegacyDatabaseResult<()> {
+        if self.config.durability != Durability::None {
+            self.reference.sync_diff()?;
+            self.metadata.sync()?;
+            self.trash.sync()?;
+        }
         Ok(())
     }
+
+    fn clear_pending(&mut self) {
+        self.pending_search.clear();
+        self.pending_threads.clear();
+        self.pending_metadata.clear();
+        self.pending_trash.clear();
+        self.pending_events.clear();
+    }
+
+    /// Marks the post as deleted. If the trash is enabled, its message is kept there before it's erased.
+    fn mark_post_as_deleted(&mut self, hash: &str) -> LegacyDatabaseResult<()> {
+        if self.config.trash_retention.is_some() {
+            let db_ref = self
+                .reference
+                .get_ref(hash)
+                .ok_or(LegacyDatabaseError::PostDoesntExist)?;
+            if let Some(settings) = &db_ref.chunk_settings {
+                self.pending_trash.push(TrashEntry {
+                    hash: hash.to_string(),
+                    reply_to: db_ref.parent_hash.to_string(),
+                    message: self.chunk_processor.get_bytes(settings, db_ref.length)?,
+                    deleted_at: unix_time_now(),
+                });
+            }
+        }
+
+        self.mark_post_as_deleted_untrashed(hash)
+    }
+
+    /// Same as [`Self::mark_post_as_deleted`], but the message is never kept in the trash, e.g. as it can't be read
+    fn mark_post_as_deleted_untrashed(&mut self, hash: &str) -> LegacyDatabaseResult<()> {
+        self.reference.mark_post_as_deleted(hash)?;
+        self.pending_search.push(SearchUpdate::Remove {
+            hash: hash.to_string(),
+        });
+        self.pending_threads.push(ThreadUpdate::Remove {
+            hash: hash.to_string(),
+        });
+        self.pending_events.push(DatabaseEvent::PostDeleted {
+            hash: hash.to_string(),
+            parent: self
+                .reference
+                .get_ref(hash)
+                .unwrap()
+                .parent_hash
+                .to_string(),
+        });
+        Ok(())
+    }
+
+    /// Zeroes messages of the deleted posts
+    fn erase_messages(&mut self, hashes: &[String]) -> LegacyDatabaseResult<()> {
+        let mut erased = false;
+        for hash in hashes {
+            let db_ref = self.reference.get_ref(hash).unwrap();
+            if let Some(settings) = &db_ref.chunk_settings {
+                self.chunk_processor.remove(settings, db_ref.length)?;
+                erased = true;
+            }
+        }
+
+        if erased && self.config.durability != Durability::None {
+            self.chunk_processor.sync()?;
+        }
+        Ok(())
+    }
+
+    fn apply_pending_threads(&mut self) {
+        let index = match &mut self.thread_index {
+            Some(index) => index,
+            None => return self.pending_threads.clear(),
+        };
+
+        // Replies may come before their parent in the same transaction
+        let mut unapplied: HashSet<String> = self
+            .pending_threads
+            .iter()
+            .filter_map(|update| match update {
+                ThreadUpdate::Insert { hash, .. } => Some(hash.clone()),
+                ThreadUpdate::Remove { .. } => None,
+            })
+            .collect();
+        let now = unix_time_now();
+        for update in self.pending_threads.drain(..) {
+            match update {
+                ThreadUpdate::Insert { hash, parent, bump } => {
+                    unapplied.remove(&hash);
+                    if is_outside_categories(index, &self.reference, &parent, &unapplied) {
+                        index.skip(&hash);
+                    } else {
+                        index.insert(&hash, &parent, now, bump);
+                    }
+                }
+                ThreadUpdate::Remove { hash } => index.remove(&hash),
+            }
+        }
+    }
+
+    fn apply_pending_search(&mut self) {
+        let index = match &mut self.search_index {
+            Some(index) => index,
+            None => return self.pending_search.clear(),
+        };
+
+        for update in self.pending_search.drain(..) {
+            match update {
+                SearchUpdate::Insert { hash, message } => index.insert(&hash, &message),
+                SearchUpdate::Remove { hash } => index.remove(&hash),
+            }
+        }
+    }
 }
 
 impl<TProcessor: ChunkCollectionProcessor, TDiff: Diff> Database
@@ -104,11 +796,32 @@ where
             return Err(LegacyDatabaseError::DuplicatePost);
         }
 
-        self.upsert_post(post)?;
+        if self.reject_if_banned(&post) {
+            return Err(LegacyDatabaseError::PostBanned);
+        }
 
+        self.in_transaction(|db| db.upsert_post(post, None))?;
+        self.enforce_retention_after_put();
         Ok(())
     }
 
+    /// Inserts the whole batch within a single transaction, so all diff lines are written with a single write and fsync.
+    /// If any post fails to insert, the reference collection is reverted to the state before the call.
+    ///
+    /// Message bytes already written into chunks by the failed batch are not reclaimed, as no reference points to them.
+    fn put_posts(&mut self, posts: Vec<Post>) -> LegacyDatabaseResult<PutPostsReport> {
+        self.put_batch(posts, None)
+    }
+
+    /// Inserts the batch the same way as [`Database::put_posts`], metadata with the source is stored on commit
+    fn put_posts_from(
+        &mut self,
+        posts: Vec<Post>,
+        source: &str,
+    ) -> LegacyDatabaseResult<PutPostsReport> {
+        self.put_batch(posts, Some(source))
+    }
+
     fn update_post(&mut self, post: Post) -> Result<(), Self::Error> {
         if !self.reference.ref_exists(&post.hash) {
             return Err(LegacyDatabaseError::PostDoesntExist);
@@ -118,23 +831,26 @@ where
             return Err(LegacyDatabaseError::CantUpdateNonDeletedPost);
         }
 
-        self.upsert_post(post)?;
+        if self.reject_if_banned(&post) {
+            return Err(LegacyDatabaseError::PostBanned);
+        }
 
+        self.in_transaction(|db| db.upsert_post(post, None))?;
+        self.enforce_retention_after_put();
         Ok(())
     }
 
-    fn get_post(&self, hash: String) -> Result<Option<Post>, LegacyDatabaseError> {
+    fn get_post(&self, hash: String) -> Result<Option<PostEntry>, LegacyDatabaseError> {
         let db_ref = match self.reference.get_ref(&hash) {
             Some(db_ref) => db_ref,
             None => return Ok(None),
         };
 
         if db_ref.deleted {
-            return Ok(Some(Post::new(
+            return Ok(Some(PostEntry::Deleted {
                 hash,
-                db_ref.parent_hash.to_string(),
-                "Deleted Message Stub Move Me To Const Pls :)".to_string(),
-            )));
+                parent: db_ref.parent_hash.to_string(),
+            }));
         }
 
         let chunk_settings = db_ref
@@ -144,35 +860,125 @@ where
 
         let post_message = self
             .chunk_processor
-            .get_message(&chunk_settings, db_ref.length)?;
+            .get_message(chunk_settings, db_ref.length)?;
 
-        Ok(Some(Post {
+        Ok(Some(PostEntry::Live(Post {
             hash,
             message: post_message,
             reply_to: db_ref.parent_hash.to_string(),
-        }))
+        })))
     }
 
+    /// Marks the post as deleted and zeroes its message.
+    /// The diff is written before the message is zeroed, so the reference never points to erased data.
     fn delete_post(&mut self, hash: String) -> Result<(), Self::Error> {
-        self.reference.mark_post_as_deleted(&hash)?;
-        let db_ref = self.reference.get_ref(&hash).unwrap();
-        let settings = match &db_ref.chunk_settings {
-            Some(s) => s,
-            None => return Ok(())
-        };
-        
-        self.chunk_processor.remove(settings, db_ref.length)?;
+        self.in_transaction(|db| db.mark_post_as_deleted(&hash))?;
+        self.erase_messages(&[hash])
+    }
+
+    /// Deleted posts are marked within a single transaction, then their messages are zeroed, like in [`Database::delete_post`].
+    fn delete_thread(&mut self, root_hash: String) -> Result<Vec<String>, Self::Error> {
+        if !self.reference.ref_exists(&root_hash) {
+            return Err(LegacyDatabaseError::PostDoesntExist);
+        }
+
+        let deleted = self.in_transaction(|db| {
+            let mut deleted = Vec::new();
+            for hash in db.reference.subtree(&root_hash) {
+                if !db.reference.ref_deleted(&hash) {
+                    db.mark_post_as_deleted(&hash.to_string())?;
+                    deleted.push(hash.to_string());
+                }
+            }
+
+            Ok(deleted)
+        })?;
+
+        self.erase_messages(&deleted)?;
+        Ok(deleted)
+    }
+
+    /// Puts the deleted post back from the trash, unless it was banned since it was deleted
+    fn restore_post(&mut self, hash: String) -> Result<(), Self::Error> {
+        let post = self
+            .trash
+            .get(&hash)
+            .ok_or(LegacyDatabaseError::PostNotInTrash)?
+            .to_post()?;
+
+        if self.reference.ref_exists(&hash) && !self.reference.ref_deleted(&hash) {
+            return Err(LegacyDatabaseError::CantUpdateNonDeletedPost);
+        }
+
+        if self.reject_if_banned(&post) {
+            return Err(LegacyDatabaseError::PostBanned);
+        }
+
+        self.in_transaction(|db| db.upsert_post(post, None))?;
+        // Post is restored already, so failure to write the trash is kept like the one of the transaction sidecars
+        let durability = self.config.durability;
+        let trash = self.trash.take(&hash).and_then(|_| match durability {
+            Durability::None => Ok(()),
+            _ => self.trash.sync(),
+        });
+        if let Err(err) = trash {
+            self.sidecar_error = Some(err.into());
+        }
         Ok(())
     }
+
+    fn get_post_metadata(&self, hash: String) -> Result<Option<PostMetadata>, Self::Error> {
+        Ok(self.metadata.get(&hash).cloned())
+    }
+
+    fn set_post_metadata(
+        &mut self,
+        hash: String,
+        metadata: PostMetadata,
+    ) -> Result<(), Self::Error> {
+        if !self.reference.ref_exists(&hash) {
+            return Err(LegacyDatabaseError::PostDoesntExist);
+        }
+
+        self.metadata.append(vec![(hash, metadata)])?;
+        if self.config.durability != Durability::None {
+            self.metadata.sync()?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the reply is outside of the thread index categories: its parent is in the database, but not in the index.
+/// Posts in `unapplied` are not inserted into the index yet, so their replies may still wait for them.
+fn is_outside_categories<TDiff: Diff>(
+    index: &ThreadIndex,
+    reference: &DbRefCollection<TDiff>,
+    parent: &str,
+    unapplied: &HashSet<String>,
+) -> bool {
+    !index.is_category(parent)
+        && !index.is_indexed(parent)
+        && !unapplied.contains(parent)
+        && reference.ref_exists(parent)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::legacy_database::chunk::{
+        chunk_index_to_name,
+        chunk_processor::OnDiskChunkCollectionProcessor,
+        compression::{Compressor, DEFAULT_LEVEL},
+        memory_storage::{Fault, MemoryStorage},
+        Chunk,
+    };
     use crate::{
-        assert_err, legacy_database::index::db_post_ref::ChunkSettings, post::PostMessage,
+        assert_err, in_temp_dir,
+        legacy_database::index::{db_post_ref::ChunkSettings, serialized::IndexCollection},
         tests::test_utils::*,
     };
+    use rusty_fork::rusty_fork_test;
+    use std::time::Duration;
 
     #[test]
     fn update_post_if_post_doesnt_exist_should_return_error() {
@@ -211,7 +1017,7 @@ mod tests {
         let post = some_post("5", "10", "test");
         let mut db = LegacyDatabase::new(collection, processor);
 
-        db.upsert_post(post).unwrap();
+        db.upsert_post(post, None).unwrap();
 
         let db_ref = db.reference.get_ref("5").unwrap();
         assert_eq!(db_ref.parent_hash, rc("10"));
@@ -225,7 +1031,7 @@ mod tests {
         let post = some_post("5", "0", "test");
         let mut db = LegacyDatabase::new(collection, processor);
 
-        db.upsert_post(post).unwrap();
+        db.upsert_post(post, None).unwrap();
 
         let expected_chunk_settings = ChunkSettings {
             chunk_index: 0,
@@ -238,7 +1044,7 @@ mod tests {
             .unwrap();
         let db_ref = db.reference.get_ref("5").unwrap();
 
-        assert_eq!(collected, &PostMessage::new("test".to_string()));
+        assert_eq!(collected, b"test");
         assert_eq!(
             db_ref.chunk_settings.as_ref().unwrap(),
             &expected_chunk_settings
@@ -254,19 +1060,621 @@ mod tests {
         let post = some_post("1", "0", "test");
 
         let mut db = LegacyDatabase::new(collection, processor);
-        db.upsert_post(post).unwrap();
+        db.upsert_post(post, None).unwrap();
 
         let expected_chunk_settings = ChunkSettings {
             chunk_index: 10,
             offset: 1,
         };
-        let expected_post = PostMessage::new("test".to_string());
         let collected = db
             .chunk_processor
             .data
             .get(&expected_chunk_settings)
             .unwrap();
 
-        assert_eq!(collected, &expected_post);
+        assert_eq!(collected, b"test");
+    }
+
+    #[test]
+    fn put_posts_should_report_inserted_and_duplicate_posts() {
+        let collection = collection(vec![some_raw_ref("1", "0", 10)]);
+        let mut db = LegacyDatabase::new(collection, collecting_chunk_processor());
+        let posts = vec![
+            some_post("1", "0", "existing"),
+            some_post("2", "0", "new"),
+            some_post("2", "0", "new again"),
+            some_post("3", "2", "reply"),
+        ];
+
+        let report = db.put_posts(posts).unwrap();
+
+        assert_eq!(report.inserted, vec!["2".to_string(), "3".to_string()]);
+        assert_eq!(report.duplicates, vec!["1".to_string(), "2".to_string()]);
+    }
+
+    #[test]
+    fn put_posts_when_insert_fails_should_not_keep_any_post() {
+        let mut processor = collecting_chunk_processor();
+        processor.capacity = Some(1);
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+        let posts = vec![some_post("1", "0", "first"), some_post("2", "0", "second")];
+
+        let result = db.put_posts(posts);
+
+        assert!(result.is_err());
+        assert!(!db.reference.ref_exists("1"));
+        assert!(!db.reference.ref_exists("2"));
+    }
+
+    #[test]
+    fn put_posts_per_batch_durability_should_sync_chunks_once() {
+        let mut db = db_with_durability(Durability::PerBatch);
+
+        db.put_posts(vec![some_post("1", "0", "a"), some_post("2", "0", "b")])
+            .unwrap();
+
+        assert_eq!(db.chunk_processor.syncs, 1);
+    }
+
+    #[test]
+    fn put_posts_per_write_durability_should_sync_every_chunk_write() {
+        let mut db = db_with_durability(Durability::PerWrite);
+
+        db.put_posts(vec![some_post("1", "0", "a"), some_post("2", "0", "b")])
+            .unwrap();
+
+        assert_eq!(db.chunk_processor.syncs, 3);
+    }
+
+    #[test]
+    fn put_post_without_durability_should_not_sync() {
+        let mut db = db_with_durability(Durability::None);
+
+        db.put_post(some_post("1", "0", "a")).unwrap();
+        db.delete_post("1".to_string()).unwrap();
+
+        assert_eq!(db.chunk_processor.syncs, 0);
+    }
+
+    #[test]
+    fn search_should_find_put_posts_and_skip_deleted_ones() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.enable_search(SearchIndex::default()).unwrap();
+
+        db.put_posts(vec![
+            some_post("1", "0", "hello world"),
+            some_post("2", "0", "hello there"),
+        ])
+        .unwrap();
+        db.delete_post("2".to_string()).unwrap();
+
+        assert_eq!(db.search("hello", 10).unwrap(), vec!["1".to_string()]);
+    }
+
+    #[test]
+    fn search_should_not_index_rolled_back_posts() {
+        let mut processor = collecting_chunk_processor();
+        processor.capacity = Some(1);
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+        db.enable_search(SearchIndex::default()).unwrap();
+
+        let result = db.put_posts(vec![
+            some_post("1", "0", "hello"),
+            some_post("2", "0", "hello"),
+        ]);
+
+        assert!(result.is_err());
+        assert!(db.search("hello", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn search_when_not_enabled_should_return_error() {
+        let db = LegacyDatabase::new(collection(vec![]), dummy_chunk_processor());
+
+        let result = db.search("hello", 10);
+        assert_err!(result, LegacyDatabaseError::SearchIndexDisabled)
+    }
+
+    #[test]
+    fn rebuild_search_index_should_index_live_posts() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_post(some_post("1", "0", "hello world")).unwrap();
+
+        db.rebuild_search_index().unwrap();
+
+        assert_eq!(db.search("wor*", 10).unwrap(), vec!["1".to_string()]);
+    }
+
+    #[test]
+    fn enable_search_should_catch_up_with_changes_made_after_index_was_saved() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_posts(vec![
+            some_post("1", "0", "hello world"),
+            some_post("2", "0", "hello there"),
+        ])
+        .unwrap();
+        let mut saved = SearchIndex::default();
+        saved.insert("2", "hello there");
+        saved.insert("3", "hello gone");
+        db.delete_post("2".to_string()).unwrap();
+
+        db.enable_search(saved).unwrap();
+
+        assert_eq!(db.search("hello", 10).unwrap(), vec!["1".to_string()]);
+        assert!(!db.search_index.as_ref().unwrap().contains("2"));
+        assert!(!db.search_index.as_ref().unwrap().contains("3"));
+    }
+
+    #[test]
+    fn threads_should_follow_bumps_except_sage_and_deleted() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.enable_thread_index(vec!["b".to_string()]).unwrap();
+        db.put_posts(vec![
+            some_post("1", "b", "first"),
+            some_post("2", "b", "second"),
+            some_post("3", "b", "third"),
+        ])
+        .unwrap();
+
+        db.put_post(some_post("4", "1", "bump")).unwrap();
+        db.put_post(some_post("5", "2", "[sage] no bump")).unwrap();
+        db.delete_post("3".to_string()).unwrap();
+
+        assert_eq!(db.threads("b").unwrap(), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn thread_index_should_keep_only_replies_to_missing_posts_waiting() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.enable_thread_index(vec!["b".to_string()]).unwrap();
+        db.put_posts(vec![
+            some_post("3", "2", "reply to reply"),
+            some_post("2", "1", "reply"),
+            some_post("1", check::ROOT_PARENT_HASH, "root"),
+        ])
+        .unwrap();
+        db.put_post(some_post("4", "3", "late reply")).unwrap();
+        assert_eq!(db.thread_index.as_ref().unwrap().waiting_replies(), 0);
+
+        db.put_post(some_post("5", "9", "orphan")).unwrap();
+        assert_eq!(db.thread_index.as_ref().unwrap().waiting_replies(), 1);
+        db.delete_post("5".to_string()).unwrap();
+
+        assert_eq!(db.thread_index.as_ref().unwrap().waiting_replies(), 0);
+    }
+
+    #[test]
+    fn enable_thread_index_should_order_existing_threads_by_receive_time() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_posts(vec![
+            some_post("1", "b", "first"),
+            some_post("2", "b", "second"),
+            some_post("3", "1", "reply"),
+            some_post("4", "2", "[sage]"),
+        ])
+        .unwrap();
+        for (hash, received_at) in [("1", 10), ("2", 20), ("3", 30), ("4", 40)] {
+            let metadata = PostMetadata {
+                received_at,
+                ..Default::default()
+            };
+            db.set_post_metadata(hash.to_string(), metadata).unwrap();
+        }
+
+        db.enable_thread_index(vec!["b".to_string()]).unwrap();
+
+        assert_eq!(db.threads("b").unwrap(), vec!["1", "2"]);
+        assert_err!(
+            LegacyDatabase::new(collection(vec![]), collecting_chunk_processor()).threads("b"),
+            LegacyDatabaseError::ThreadIndexDisabled
+        );
+    }
+
+    #[test]
+    fn thread_should_be_bumped_by_replies_received_before_their_parent() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_posts(vec![
+            some_post("1", "b", "first"),
+            some_post("2", "b", "second"),
+            some_post("4", "3", "early reply"),
+        ])
+        .unwrap();
+        db.enable_thread_index(vec!["b".to_string()]).unwrap();
+        db.put_post(some_post("5", "3", "[sage] another early reply"))
+            .unwrap();
+
+        db.put_post(some_post("3", "1", "[sage] late parent"))
+            .unwrap();
+
+        assert_eq!(db.threads("b").unwrap(), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn put_post_should_record_receive_time_and_keep_it_on_update() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_post(some_post("1", "0", "hello")).unwrap();
+        let mut metadata = db.get_post_metadata("1".to_string()).unwrap().unwrap();
+        assert!(metadata.received_at > 0);
+
+        metadata.received_at = 42;
+        metadata.source = Some("image.png".to_string());
+        db.set_post_metadata("1".to_string(), metadata.clone())
+            .unwrap();
+        db.delete_post("1".to_string()).unwrap();
+        db.update_post(some_post("1", "0", "hello")).unwrap();
+
+        assert_eq!(
+            db.get_post_metadata("1".to_string()).unwrap(),
+            Some(metadata)
+        );
+    }
+
+    #[test]
+    fn put_posts_from_should_record_source_with_the_posts() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+
+        let report = db
+            .put_posts_from(
+                vec![some_post("1", "0", "hello"), some_post("2", "1", "reply")],
+                "image.png",
+            )
+            .unwrap();
+
+        assert_eq!(report.inserted, vec!["1".to_string(), "2".to_string()]);
+        for hash in ["1", "2"] {
+            let metadata = db.get_post_metadata(hash.to_string()).unwrap().unwrap();
+            assert_eq!(metadata.source.as_deref(), Some("image.png"));
+            assert!(metadata.received_at > 0);
+        }
+    }
+
+    #[test]
+    fn put_post_when_diff_sync_fails_should_keep_post_and_report_sidecar_error() {
+        let collection =
+            DbRefCollection::<UnsyncableDiff>::new(IndexCollection { indexes: vec![] }).unwrap();
+        let mut db = LegacyDatabase::new(collection, collecting_chunk_processor());
+
+        db.put_post(some_post("1", "0", "hello")).unwrap();
+
+        assert!(matches!(
+            db.sidecar_error(),
+            Some(LegacyDatabaseError::DbRefCollectionError(_))
+        ));
+        assert!(db.get_post_metadata("1".to_string()).unwrap().is_some());
+        assert_err!(
+            db.put_post(some_post("1", "0", "hello")),
+            LegacyDatabaseError::DuplicatePost
+        );
+        assert!(db.flush_sidecars().is_err());
+    }
+
+    #[test]
+    fn put_posts_when_insert_fails_should_not_keep_metadata() {
+        let mut processor = collecting_chunk_processor();
+        processor.capacity = Some(1);
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+
+        let result = db.put_posts(vec![some_post("1", "0", "a"), some_post("2", "0", "b")]);
+
+        assert!(result.is_err());
+        assert_eq!(db.get_post_metadata("1".to_string()).unwrap(), None);
+    }
+
+    #[test]
+    fn set_post_metadata_if_post_doesnt_exist_should_return_error() {
+        let mut db = LegacyDatabase::new(collection(vec![]), dummy_chunk_processor());
+
+        let result = db.set_post_metadata("1".to_string(), PostMetadata::default());
+        assert_err!(result, LegacyDatabaseError::PostDoesntExist)
+    }
+
+    #[test]
+    fn put_post_if_post_is_banned_should_return_error() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.ban(BanRule::Post("1".to_string())).unwrap();
+        db.ban(BanRule::Pattern("spam".to_string())).unwrap();
+
+        assert_err!(
+            db.put_post(some_post("1", "0", "hello")),
+            LegacyDatabaseError::PostBanned
+        );
+        assert_err!(
+            db.put_post(some_post("2", "0", "some spam")),
+            LegacyDatabaseError::PostBanned
+        );
+        assert!(!db.reference.ref_exists("1"));
+    }
+
+    #[test]
+    fn put_post_should_accept_replies_to_rejected_post_banned_by_hash() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.ban(BanRule::Post("1".to_string())).unwrap();
+        db.ban(BanRule::Pattern("spam".to_string())).unwrap();
+        db.put_post(some_post("1", "0", "hello")).unwrap_err();
+        db.put_post(some_post("2", "0", "some spam")).unwrap_err();
+
+        db.put_posts(vec![
+            some_post("3", "1", "reply"),
+            some_post("4", "2", "reply"),
+        ])
+        .unwrap();
+
+        assert!(db.reference.ref_exists("3"));
+        assert!(db.reference.ref_exists("4"));
+    }
+
+    #[test]
+    fn update_post_if_post_is_banned_should_not_restore_it() {
+        let collection = collection(vec![some_raw_deleted_ref("1", "0", 5)]);
+        let mut db = LegacyDatabase::new(collection, collecting_chunk_processor());
+        db.ban(BanRule::Post("1".to_string())).unwrap();
+
+        let result = db.update_post(some_post("1", "0", "hello"));
+
+        assert_err!(result, LegacyDatabaseError::PostBanned);
+        assert!(db.reference.ref_deleted("1"));
+    }
+
+    #[test]
+    fn put_posts_should_skip_replies_to_banned_thread() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_post(some_post("1", "0", "thread")).unwrap();
+        db.put_post(some_post("2", "1", "reply")).unwrap();
+        db.ban(BanRule::Thread("1".to_string())).unwrap();
+
+        let report = db
+            .put_posts(vec![
+                some_post("3", "2", "nested reply"),
+                some_post("4", "3", "reply to the skipped one"),
+                some_post("5", "0", "other thread"),
+            ])
+            .unwrap();
+
+        assert_eq!(report.banned, vec!["3".to_string(), "4".to_string()]);
+        assert_eq!(report.inserted, vec!["5".to_string()]);
+        assert_err!(
+            db.put_post(some_post("6", "4", "reply in the next batch")),
+            LegacyDatabaseError::PostBanned
+        );
+
+        db.unban(&BanRule::Thread("1".to_string()));
+        db.put_post(some_post("6", "4", "reply in the next batch"))
+            .unwrap();
+    }
+
+    #[test]
+    fn delete_thread_should_delete_and_erase_whole_subtree() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_posts(vec![
+            some_post("1", "0", "thread"),
+            some_post("2", "1", "reply"),
+            some_post("3", "2", "nested reply"),
+            some_post("4", "0", "other thread"),
+        ])
+        .unwrap();
+        db.delete_post("2".to_string()).unwrap();
+
+        let deleted = db.delete_thread("1".to_string()).unwrap();
+
+        assert_eq!(deleted, vec!["1".to_string(), "3".to_string()]);
+        assert!(["1", "2", "3"]
+            .iter()
+            .all(|hash| db.reference.ref_deleted(*hash)));
+        assert!(!db.reference.ref_deleted("4"));
+        assert_eq!(db.chunk_processor.data.len(), 1);
+    }
+
+    #[test]
+    fn delete_thread_if_post_doesnt_exist_should_return_error() {
+        let mut db = LegacyDatabase::new(collection(vec![]), dummy_chunk_processor());
+
+        let result = db.delete_thread("1".to_string());
+        assert_err!(result, LegacyDatabaseError::PostDoesntExist)
+    }
+
+    #[test]
+    fn get_post_if_post_is_deleted_should_return_tombstone() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_post(some_post("1", "0", "hello")).unwrap();
+        db.delete_post("1".to_string()).unwrap();
+
+        let post = db.get_post("1".to_string()).unwrap();
+
+        assert_eq!(
+            post,
+            Some(PostEntry::Deleted {
+                hash: "1".to_string(),
+                parent: "0".to_string()
+            })
+        );
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn restore_post_should_put_deleted_post_back_from_trash() {
+            in_temp_dir!({
+                let mut db = db_with_trash(Some(Duration::from_secs(60)));
+                db.put_post(some_post("1", "0", "hello")).unwrap();
+                db.delete_post("1".to_string()).unwrap();
+                assert!(db.chunk_processor.data.is_empty());
+
+                db.restore_post("1".to_string()).unwrap();
+
+                let post = db.get_post("1".to_string()).unwrap().unwrap();
+                assert_eq!(post, PostEntry::Live(some_post("1", "0", "hello")));
+                assert!(db.trash.get("1").is_none());
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn restore_post_should_reject_post_banned_after_deletion() {
+            in_temp_dir!({
+                let mut db = db_with_trash(Some(Duration::from_secs(60)));
+                db.put_post(some_post("1", "0", "hello")).unwrap();
+                db.delete_post("1".to_string()).unwrap();
+                db.ban(BanRule::Post("1".to_string())).unwrap();
+
+                let result = db.restore_post("1".to_string());
+
+                assert_err!(result, LegacyDatabaseError::PostBanned);
+                assert!(db.reference.ref_deleted("1"));
+                assert!(db.trash.get("1").is_some());
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn trash_should_be_loaded_when_enabled() {
+            in_temp_dir!({
+                let mut db = db_with_trash(Some(Duration::from_secs(60)));
+                db.put_post(some_post("1", "0", "hello")).unwrap();
+                db.delete_post("1".to_string()).unwrap();
+
+                let db = db_with_trash(Some(Duration::from_secs(60)));
+
+                assert_eq!(db.trash.get("1").unwrap().message, b"hello");
+            });
+        }
+    }
+
+    #[test]
+    fn restore_post_when_trash_is_disabled_should_return_error() {
+        let mut db = db_with_trash(None);
+        db.put_post(some_post("1", "0", "hello")).unwrap();
+        db.delete_post("1".to_string()).unwrap();
+
+        let result = db.restore_post("1".to_string());
+        assert_err!(result, LegacyDatabaseError::PostNotInTrash)
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn purge_trash_should_keep_recently_deleted_posts() {
+            in_temp_dir!({
+                let mut db = db_with_trash(Some(Duration::from_secs(60)));
+                db.put_posts(vec![some_post("1", "0", "a"), some_post("2", "0", "b")])
+                    .unwrap();
+                db.delete_thread("1".to_string()).unwrap();
+                db.delete_post("2".to_string()).unwrap();
+                db.trash.append(vec![old_entry("1")]).unwrap();
+
+                let purged = db.purge_trash().unwrap();
+
+                assert_eq!(purged, vec!["1".to_string()]);
+                assert_err!(
+                    db.restore_post("1".to_string()),
+                    LegacyDatabaseError::PostNotInTrash
+                );
+                db.restore_post("2".to_string()).unwrap();
+            });
+        }
+    }
+
+    fn db_with_trash(
+        trash_retention: Option<Duration>,
+    ) -> LegacyDatabase<CollectingChunkProcessor, DummyDiff> {
+        LegacyDatabase::with_config(
+            collection(vec![]),
+            collecting_chunk_processor(),
+            LegacyDatabaseConfig {
+                trash_retention,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn old_entry(hash: &str) -> TrashEntry {
+        TrashEntry {
+            hash: hash.to_string(),
+            reply_to: "0".to_string(),
+            message: b"a".to_vec(),
+            deleted_at: 0,
+        }
+    }
+
+    #[test]
+    fn put_posts_when_storage_is_full_should_not_keep_any_post() {
+        let processor =
+            OnDiskChunkCollectionProcessor::<Chunk<MemoryStorage>>::new(Default::default())
+                .unwrap();
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+        MemoryStorage::inject_fault(Fault::NoSpace { capacity: 8 });
+
+        let result = db.put_posts(vec![
+            some_post("1", "0", "first"),
+            some_post("2", "0", "second"),
+        ]);
+
+        assert!(matches!(
+            result,
+            Err(LegacyDatabaseError::ChunkProcessorError(_))
+        ));
+        assert!(db.get_post("1".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn compressed_post_should_reuse_space_by_stored_length() {
+        let processor =
+            OnDiskChunkCollectionProcessor::<Chunk<MemoryStorage>>::new(Default::default())
+                .unwrap()
+                .with_compressor(Compressor::new(&[], DEFAULT_LEVEL));
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+        let long = "sage ".repeat(200);
+        db.put_post(some_post("1", "0", &long)).unwrap();
+        let stored_length = db.reference.get_ref("1").unwrap().length;
+        db.delete_post("1".to_string()).unwrap();
+
+        db.put_post(some_post("2", "0", &"bump ".repeat(200)))
+            .unwrap();
+
+        assert!(stored_length < long.len() as u64);
+        let reused = db.reference.get_ref("2").unwrap();
+        assert_eq!(reused.chunk_settings.as_ref().unwrap().offset, 0);
+        let post = db.get_post("2".to_string()).unwrap().unwrap().into_live();
+        assert_eq!(post.unwrap().message.as_str(), "bump ".repeat(200));
+    }
+
+    #[test]
+    fn identical_posts_should_share_extent_until_last_is_deleted() {
+        let processor =
+            OnDiskChunkCollectionProcessor::<Chunk<MemoryStorage>>::new(Default::default())
+                .unwrap()
+                .with_deduplication(vec![]);
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+        db.put_posts(vec![
+            some_post("1", "0", "repost"),
+            some_post("2", "5", "repost"),
+        ])
+        .unwrap();
+        let chunk_size = MemoryStorage::read(&chunk_index_to_name(0)).unwrap().len();
+
+        db.delete_post("1".to_string()).unwrap();
+
+        assert_eq!(chunk_size, 6);
+        let post = db.get_post("2".to_string()).unwrap().unwrap().into_live();
+        assert_eq!(post.unwrap().message.as_str(), "repost");
+
+        db.delete_post("2".to_string()).unwrap();
+
+        assert_eq!(
+            MemoryStorage::read(&chunk_index_to_name(0)).unwrap(),
+            [0; 6]
+        );
+    }
+
+    fn db_with_durability(
+        durability: Durability,
+    ) -> LegacyDatabase<CollectingChunkProcessor, DummyDiff> {
+        LegacyDatabase::with_config(
+            collection(vec![]),
+            collecting_chunk_processor(),
+            LegacyDatabaseConfig {
+                durability,
+                ..Default::default()
+            },
+        )
     }
 }
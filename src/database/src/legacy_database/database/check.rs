@@ -0,0 +1,562 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{LegacyDatabase, LegacyDatabaseError, LegacyDatabaseResult};
+use crate::legacy_database::{
+    chunk::{
+        chunk_processor::{ChunkCollectionProcessor, OnDiskChunkCollectionProcessorError},
+        ChunkIndex,
+    },
+    index::diff::Diff,
+};
+
+/// Problem found in a single post reference
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CheckIssue {
+    /// Post isn't deleted, but it has no chunk settings
+    MissingChunkSettings,
+
+    /// Chunk file referenced by the post does not exist
+    ChunkFileMissing { chunk_index: ChunkIndex },
+
+    /// Post extent ends after the end of the chunk file
+    OutOfBounds {
+        chunk_index: ChunkIndex,
+        offset: u64,
+        length: u64,
+        chunk_size: u64,
+    },
+
+    /// Message of the live post is not a valid UTF-8 string
+    InvalidUtf8,
+
+    /// Message of the live post consists of zeros only, as if it was removed
+    ZeroedMessage,
+
+    /// Message of the live post can't be decrypted or decompressed
+    UndecodableMessage,
+
+    /// Post extent overlaps with the extent of another post
+    Overlap { other: String },
+
+    /// Parent of the post does not exist in the database
+    OrphanReply { parent: String },
+}
+
+/// Parent hash of the nanoboard root post, which doesn't belong to any post
+pub const ROOT_PARENT_HASH: &str = "00000000000000000000000000000000";
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CheckProblem {
+    pub hash: String,
+    pub issue: CheckIssue,
+}
+
+/// Result of [`LegacyDatabase::check`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Number of checked references
+    pub checked: usize,
+
+    pub problems: Vec<CheckProblem>,
+
+    /// Hashes of posts which were marked as deleted during the repair
+    pub repaired: Vec<String>,
+}
+
+/// How a broken reference is repaired
+enum Repair {
+    /// Mark as deleted, its space stays reusable
+    Delete,
+
+    /// Same as [`Repair::Delete`], but the message can't be read, so it's not kept in the trash
+    DeleteUnreadable,
+
+    /// Mark as deleted and forget its space
+    Discard,
+}
+
+#[derive(Clone)]
+struct Extent {
+    hash: String,
+    deleted: bool,
+    chunk_index: ChunkIndex,
+    offset: u64,
+    end: u64,
+}
+
+impl<TProcessor, TDiff> LegacyDatabase<TProcessor, TDiff>
+where
+    LegacyDatabaseError: From<<TProcessor as ChunkCollectionProcessor>::Error>,
+    TProcessor: ChunkCollectionProcessor,
+    TDiff: Diff,
+{
+    /// Walks every post reference and verifies that it points to valid data:
+    /// chunk files exist, extents are in bounds and don't overlap, live messages can be decoded, are valid UTF-8
+    /// and not zeroed.
+    /// Posts whose parent is unknown are reported as orphans, except the roots: posts replying to [`ROOT_PARENT_HASH`]
+    /// and the ones listed in [`LegacyDatabaseConfig::roots`](crate::legacy_database::config::LegacyDatabaseConfig::roots).
+    ///
+    /// If `repair` is set, broken references are marked as deleted in a single transaction.
+    /// References pointing outside of the chunks also lose their chunk space, so it's never reused.
+    /// Overlapping live posts and orphans are only reported.
+    pub fn check(&mut self, repair: bool) -> LegacyDatabaseResult<CheckReport> {
+        let mut report = CheckReport::default();
+        let mut repairs = Vec::new();
+        let mut extents = Vec::new();
+        let mut chunk_sizes = HashMap::new();
+
+        for (hash, db_ref) in self.reference.iter() {
+            report.checked += 1;
+            let mut problem = |issue| {
+                report.problems.push(CheckProblem {
+                    hash: hash.to_string(),
+                    issue,
+                })
+            };
+
+            let is_root = db_ref.parent_hash.to_string() == ROOT_PARENT_HASH
+                || self.config.roots.contains(&hash.to_string());
+            if !db_ref.deleted && !is_root && !self.reference.ref_exists(&db_ref.parent_hash) {
+                problem(CheckIssue::OrphanReply {
+                    parent: db_ref.parent_hash.to_string(),
+                });
+            }
+
+            let settings = match &db_ref.chunk_settings {
+                Some(settings) => settings,
+                None if db_ref.deleted => continue,
+                None => {
+                    problem(CheckIssue::MissingChunkSettings);
+                    repairs.push((hash.to_string(), Repair::Delete));
+                    continue;
+                }
+            };
+
+            let chunk_size = match chunk_sizes.get(&settings.chunk_index) {
+                Some(size) => *size,
+                None => {
+                    let size = self.chunk_processor.chunk_size(settings.chunk_index)?;
+                    chunk_sizes.insert(settings.chunk_index, size);
+                    size
+                }
+            };
+            let chunk_size = match chunk_size {
+                Some(size) => size,
+                None => {
+                    problem(CheckIssue::ChunkFileMissing {
+                        chunk_index: settings.chunk_index,
+                    });
+                    repairs.push((hash.to_string(), Repair::Discard));
+                    continue;
+                }
+            };
+
+            // Encrypted extents are longer than the stored message
+            let extent_length = self.chunk_processor.extent_length(db_ref.length);
+            let end = settings.offset.checked_add(extent_length);
+            if end.is_none_or(|end| end > chunk_size) {
+                problem(CheckIssue::OutOfBounds {
+                    chunk_index: settings.chunk_index,
+                    offset: settings.offset,
+                    length: extent_length,
+                    chunk_size,
+                });
+                repairs.push((hash.to_string(), Repair::Discard));
+                continue;
+            }
+
+            if extent_length > 0 {
+                extents.push(Extent {
+                    hash: hash.to_string(),
+                    deleted: db_ref.deleted,
+                    chunk_index: settings.chunk_index,
+                    offset: settings.offset,
+                    end: settings.offset + extent_length,
+                });
+            }
+
+            if db_ref.deleted {
+                continue;
+            }
+
+            let bytes = match self.chunk_processor.get_bytes(settings, db_ref.length) {
+                Ok(bytes) => bytes,
+                Err(err) => match LegacyDatabaseError::from(err) {
+                    LegacyDatabaseError::ChunkProcessorError(
+                        OnDiskChunkCollectionProcessorError::CryptoError(_)
+                        | OnDiskChunkCollectionProcessorError::CompressionError(_),
+                    ) => {
+                        problem(CheckIssue::UndecodableMessage);
+                        repairs.push((hash.to_string(), Repair::DeleteUnreadable));
+                        continue;
+                    }
+                    err => return Err(err),
+                },
+            };
+            if !bytes.is_empty() && bytes.iter().all(|byte| *byte == 0) {
+                problem(CheckIssue::ZeroedMessage);
+                repairs.push((hash.to_string(), Repair::Delete));
+            } else if std::str::from_utf8(&bytes).is_err() {
+                problem(CheckIssue::InvalidUtf8);
+                repairs.push((hash.to_string(), Repair::Delete));
+            }
+        }
+
+        for (first, second) in find_overlaps(extents) {
+            report.problems.push(CheckProblem {
+                hash: second.hash.clone(),
+                issue: CheckIssue::Overlap {
+                    other: first.hash.clone(),
+                },
+            });
+
+            // Reusing deleted space which overlaps with another post would overwrite it
+            for extent in [&first, &second] {
+                if extent.deleted {
+                    repairs.push((extent.hash.clone(), Repair::Discard));
+                }
+            }
+        }
+
+        if repair {
+            report.repaired = self.repair(repairs)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Live posts are deleted the same way as by [`Database::delete_post`](crate::post_database::Database::delete_post),
+    /// so subscribers, the search and thread indexes, and the trash learn about them
+    fn repair(&mut self, repairs: Vec<(String, Repair)>) -> LegacyDatabaseResult<Vec<String>> {
+        self.in_transaction(|db| {
+            let mut repaired = Vec::new();
+            let mut seen = HashSet::new();
+            for (hash, repair) in repairs {
+                let live = !db.reference.ref_deleted(&hash);
+                match repair {
+                    Repair::Delete if live => db.mark_post_as_deleted(&hash)?,
+                    Repair::DeleteUnreadable if live => db.mark_post_as_deleted_untrashed(&hash)?,
+                    Repair::Delete | Repair::DeleteUnreadable => continue,
+                    Repair::Discard => {
+                        if live {
+                            db.mark_post_as_deleted_untrashed(&hash)?;
+                        }
+                        db.reference.discard_ref_space(&hash)?;
+                    }
+                }
+
+                if seen.insert(hash.clone()) {
+                    repaired.push(hash);
+                }
+            }
+
+            Ok(repaired)
+        })
+    }
+}
+
+/// Returns pairs of overlapping extents. The first extent of the pair starts before the second one.
+/// The same extent referenced by live posts with identical messages is shared, not overlapping.
+fn find_overlaps(mut extents: Vec<Extent>) -> Vec<(Extent, Extent)> {
+    extents.sort_by_key(|extent| (extent.chunk_index, extent.offset));
+
+    let mut overlaps = Vec::new();
+    let mut furthest: Option<Extent> = None;
+    for extent in extents {
+        if let Some(previous) = &furthest {
+            let overlapping =
+                previous.chunk_index == extent.chunk_index && extent.offset < previous.end;
+            let shared = !previous.deleted
+                && !extent.deleted
+                && previous.offset == extent.offset
+                && previous.end == extent.end;
+            if overlapping && !shared {
+                overlaps.push((previous.clone(), extent.clone()));
+            }
+            if overlapping && extent.end <= previous.end {
+                continue;
+            }
+        }
+        furthest = Some(extent);
+    }
+
+    overlaps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        legacy_database::{
+            chunk::{
+                chunk_index_to_name, chunk_processor::OnDiskChunkCollectionProcessor,
+                memory_storage::MemoryStorage, Chunk,
+            },
+            config::LegacyDatabaseConfig,
+            crypto::Cipher,
+            database::events::DatabaseEvent,
+            index::{
+                db_post_ref::ChunkSettings, search::SearchIndex, serialized::DbPostRefSerialized,
+            },
+        },
+        tests::test_utils::*,
+    };
+    use std::collections::HashSet;
+
+    type EncryptedProcessor = OnDiskChunkCollectionProcessor<Chunk<MemoryStorage>>;
+
+    #[test]
+    fn check_healthy_database_should_not_report_problems() {
+        let mut processor = processor_with_chunk(100);
+        store(&mut processor, 0, "root");
+        store(&mut processor, 10, "hello");
+        let refs = vec![ref_at("0", ROOT_PARENT_HASH, 0, 4), ref_at("1", "0", 10, 5)];
+        let mut db = LegacyDatabase::new(collection(refs), processor);
+
+        let report = db.check(false).unwrap();
+
+        assert_eq!(report.checked, 2);
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn check_should_report_missing_chunk_and_discard_space_on_repair() {
+        let mut db = LegacyDatabase::new(
+            collection(vec![some_raw_ref("1", "0", 5)]),
+            collecting_chunk_processor(),
+        );
+
+        let report = db.check(true).unwrap();
+
+        assert!(report.problems.contains(&problem(
+            "1",
+            CheckIssue::ChunkFileMissing { chunk_index: 0 }
+        )));
+        assert_eq!(report.repaired, vec!["1".to_string()]);
+        let db_ref = db.reference.get_ref("1").unwrap();
+        assert!(db_ref.deleted);
+        assert!(db_ref.chunk_settings.is_none());
+    }
+
+    #[test]
+    fn check_should_report_out_of_bounds_extent() {
+        let mut db = LegacyDatabase::new(
+            collection(vec![ref_at("1", "0", 8, 5)]),
+            processor_with_chunk(10),
+        );
+
+        let report = db.check(false).unwrap();
+
+        assert!(report.problems.contains(&problem(
+            "1",
+            CheckIssue::OutOfBounds {
+                chunk_index: 0,
+                offset: 8,
+                length: 5,
+                chunk_size: 10
+            }
+        )));
+        assert!(!db.reference.ref_deleted("1"));
+    }
+
+    #[test]
+    fn check_should_report_zeroed_and_invalid_messages_and_delete_them_on_repair() {
+        let mut processor = processor_with_chunk(100);
+        processor.data.insert(settings(10), vec![0xc3, 0x28]);
+        let refs = vec![ref_at("1", "0", 0, 5), ref_at("2", "0", 10, 2)];
+        let mut db = LegacyDatabase::new(collection(refs), processor);
+
+        let report = db.check(true).unwrap();
+
+        assert!(report
+            .problems
+            .contains(&problem("1", CheckIssue::ZeroedMessage)));
+        assert!(report
+            .problems
+            .contains(&problem("2", CheckIssue::InvalidUtf8)));
+        assert!(db.reference.ref_deleted("1"));
+        assert!(db.reference.ref_deleted("2"));
+        assert!(db.reference.get_ref("1").unwrap().chunk_settings.is_some());
+    }
+
+    #[test]
+    fn repair_should_notify_subscribers_and_update_search_and_threads() {
+        let mut processor = processor_with_chunk(100);
+        store(&mut processor, 0, "hello world");
+        let refs = vec![ref_at("1", "b", 0, 11), ref_at("2", "b", 20, 5)];
+        let mut db = LegacyDatabase::new(collection(refs), processor);
+        let mut search_index = SearchIndex::default();
+        search_index.insert("2", "hello there");
+        db.enable_search(search_index).unwrap();
+        db.enable_thread_index(vec!["b".to_string()]).unwrap();
+        let events = db.subscribe();
+
+        db.check(true).unwrap();
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![DatabaseEvent::PostDeleted {
+                hash: "2".to_string(),
+                parent: "b".to_string()
+            }]
+        );
+        assert_eq!(db.search("hello", 10).unwrap(), vec!["1".to_string()]);
+        assert_eq!(db.threads("b").unwrap(), vec!["1"]);
+    }
+
+    #[test]
+    fn check_should_discard_deleted_extent_overlapping_live_post() {
+        let mut processor = processor_with_chunk(100);
+        store(&mut processor, 0, "hello world");
+        let mut deleted = some_raw_deleted_ref("2", "0", 5);
+        deleted.offset = 3;
+        let refs = vec![ref_at("1", "0", 0, 11), deleted];
+        let mut db = LegacyDatabase::new(collection(refs), processor);
+
+        let report = db.check(true).unwrap();
+
+        assert!(report.problems.contains(&problem(
+            "2",
+            CheckIssue::Overlap {
+                other: "1".to_string()
+            }
+        )));
+        assert_eq!(report.repaired, vec!["2".to_string()]);
+        assert!(db.reference.get_ref("2").unwrap().chunk_settings.is_none());
+    }
+
+    #[test]
+    fn check_should_not_report_extent_shared_by_live_posts() {
+        let mut processor = processor_with_chunk(100);
+        store(&mut processor, 0, "hello");
+        let refs = vec![
+            ref_at("1", ROOT_PARENT_HASH, 0, 5),
+            ref_at("2", ROOT_PARENT_HASH, 0, 5),
+        ];
+        let mut db = LegacyDatabase::new(collection(refs), processor);
+
+        let report = db.check(false).unwrap();
+
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn check_should_report_orphan_replies() {
+        let mut processor = processor_with_chunk(100);
+        store(&mut processor, 1, "hello");
+        let mut db = LegacyDatabase::new(collection(vec![ref_at("1", "9", 1, 5)]), processor);
+
+        let report = db.check(true).unwrap();
+
+        assert_eq!(
+            report.problems,
+            vec![problem(
+                "1",
+                CheckIssue::OrphanReply {
+                    parent: "9".to_string()
+                }
+            )]
+        );
+        assert!(report.repaired.is_empty());
+    }
+
+    #[test]
+    fn check_should_not_report_configured_roots_as_orphans() {
+        let mut processor = processor_with_chunk(100);
+        store(&mut processor, 1, "hello");
+        let config = LegacyDatabaseConfig {
+            roots: HashSet::from(["1".to_string()]),
+            ..Default::default()
+        };
+        let mut db = LegacyDatabase::with_config(
+            collection(vec![ref_at("1", "9", 1, 5)]),
+            processor,
+            config,
+        );
+
+        let report = db.check(false).unwrap();
+
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn check_should_measure_encrypted_extents_by_their_sealed_length() {
+        encrypted_processor().insert(b"hello").unwrap();
+        let mut chunk = MemoryStorage::read(&chunk_index_to_name(0)).unwrap();
+        chunk.truncate(20);
+        MemoryStorage::write(&chunk_index_to_name(0), &chunk);
+        let mut db = LegacyDatabase::new(
+            collection(vec![ref_at("1", "0", 0, 5)]),
+            encrypted_processor(),
+        );
+
+        let report = db.check(false).unwrap();
+
+        assert!(report.problems.contains(&problem(
+            "1",
+            CheckIssue::OutOfBounds {
+                chunk_index: 0,
+                offset: 0,
+                length: 5 + Cipher::OVERHEAD,
+                chunk_size: 20
+            }
+        )));
+    }
+
+    #[test]
+    fn check_should_report_undecodable_messages_and_delete_them_on_repair() {
+        encrypted_processor().insert(b"hello").unwrap();
+        let mut chunk = MemoryStorage::read(&chunk_index_to_name(0)).unwrap();
+        chunk[30] ^= 1;
+        MemoryStorage::write(&chunk_index_to_name(0), &chunk);
+        let mut db = LegacyDatabase::new(
+            collection(vec![ref_at("1", "0", 0, 5)]),
+            encrypted_processor(),
+        );
+
+        let report = db.check(true).unwrap();
+
+        assert!(report
+            .problems
+            .contains(&problem("1", CheckIssue::UndecodableMessage)));
+        assert_eq!(report.repaired, vec!["1".to_string()]);
+    }
+
+    fn encrypted_processor() -> EncryptedProcessor {
+        EncryptedProcessor::new(Default::default())
+            .unwrap()
+            .with_cipher(Cipher::from_key([1; 32]))
+    }
+
+    fn problem(hash: &str, issue: CheckIssue) -> CheckProblem {
+        CheckProblem {
+            hash: hash.to_string(),
+            issue,
+        }
+    }
+
+    fn ref_at(hash: &str, parent: &str, offset: u64, length: u64) -> DbPostRefSerialized {
+        let mut db_ref = some_raw_ref(hash, parent, length);
+        db_ref.offset = offset;
+        db_ref
+    }
+
+    fn settings(offset: u64) -> ChunkSettings {
+        ChunkSettings {
+            chunk_index: 0,
+            offset,
+        }
+    }
+
+    fn processor_with_chunk(size: u64) -> CollectingChunkProcessor {
+        let mut processor = collecting_chunk_processor();
+        processor.chunk_sizes.insert(0, size);
+        processor
+    }
+
+    fn store(processor: &mut CollectingChunkProcessor, offset: u64, message: &str) {
+        processor
+            .data
+            .insert(settings(offset), message.as_bytes().to_vec());
+    }
+}
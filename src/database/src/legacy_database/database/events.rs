@@ -0,0 +1,194 @@
+use std::sync::mpsc::{self, Receiver};
+
+use super::{LegacyDatabase, LegacyDatabaseError};
+use crate::legacy_database::{chunk::chunk_processor::ChunkCollectionProcessor, index::diff::Diff};
+
+/// Change of the posts, sent to the subscribers once the transaction which made it is committed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatabaseEvent {
+    /// New post was put into the database
+    PostAdded { hash: String, parent: String },
+
+    /// Post was marked as deleted
+    PostDeleted { hash: String, parent: String },
+
+    /// Deleted post was put back, by updating it or from the trash
+    PostRestored { hash: String, parent: String },
+
+    /// Post which was replied to before it was received has arrived, sent right after its [`DatabaseEvent::PostAdded`]
+    ParentArrived {
+        hash: String,
+
+        /// Direct replies which were waiting for the post
+        orphans: Vec<String>,
+    },
+}
+
+impl DatabaseEvent {
+    pub fn hash(&self) -> &str {
+        match self {
+            DatabaseEvent::PostAdded { hash, .. }
+            | DatabaseEvent::PostDeleted { hash, .. }
+            | DatabaseEvent::PostRestored { hash, .. }
+            | DatabaseEvent::ParentArrived { hash, .. } => hash,
+        }
+    }
+}
+
+impl<TProcessor, TDiff> LegacyDatabase<TProcessor, TDiff>
+where
+    LegacyDatabaseError: From<<TProcessor as ChunkCollectionProcessor>::Error>,
+    TProcessor: ChunkCollectionProcessor,
+    TDiff: Diff,
+{
+    /// Returns the receiver of the events committed after this call, in the order they were made.
+    /// Subscription ends when the receiver is dropped.
+    pub fn subscribe(&mut self) -> Receiver<DatabaseEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Sends the events of the committed transaction, forgetting subscribers whose receivers are dropped
+    pub(super) fn publish_events(&mut self, events: Vec<DatabaseEvent>) {
+        if events.is_empty() {
+            return;
+        }
+
+        self.subscribers.retain(|subscriber| {
+            events
+                .iter()
+                .all(|event| subscriber.send(event.clone()).is_ok())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        in_temp_dir,
+        legacy_database::{
+            config::LegacyDatabaseConfig,
+            index::{serialized::IndexCollection, DbRefCollection},
+        },
+        post_database::Database,
+        tests::test_utils::*,
+    };
+    use rusty_fork::rusty_fork_test;
+    use std::time::Duration;
+
+    rusty_fork_test! {
+        #[test]
+        fn subscriber_should_receive_committed_changes_in_order() {
+            in_temp_dir!({
+                let mut db = db_with_trash();
+                let events = db.subscribe();
+
+                db.put_posts(vec![some_post("1", "0", "a"), some_post("2", "1", "b")])
+                    .unwrap();
+                db.delete_thread("1".to_string()).unwrap();
+                db.restore_post("2".to_string()).unwrap();
+
+                assert_eq!(
+                    events.try_iter().collect::<Vec<_>>(),
+                    vec![
+                        added("1", "0"),
+                        added("2", "1"),
+                        DatabaseEvent::PostDeleted {
+                            hash: "1".to_string(),
+                            parent: "0".to_string()
+                        },
+                        DatabaseEvent::PostDeleted {
+                            hash: "2".to_string(),
+                            parent: "1".to_string()
+                        },
+                        DatabaseEvent::PostRestored {
+                            hash: "2".to_string(),
+                            parent: "1".to_string()
+                        },
+                    ]
+                );
+            });
+        }
+    }
+
+    #[test]
+    fn failed_transaction_should_not_send_events() {
+        let mut processor = collecting_chunk_processor();
+        processor.capacity = Some(1);
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+        let events = db.subscribe();
+
+        db.put_posts(vec![some_post("1", "0", "a"), some_post("2", "0", "b")])
+            .unwrap_err();
+        db.chunk_processor.capacity = None;
+        db.put_post(some_post("3", "0", "c")).unwrap();
+
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![added("3", "0")]);
+    }
+
+    #[test]
+    fn committed_transaction_should_send_events_even_if_diff_sync_fails() {
+        let index = IndexCollection { indexes: vec![] };
+        let collection = DbRefCollection::<UnsyncableDiff>::new(index).unwrap();
+        let mut db = LegacyDatabase::new(collection, collecting_chunk_processor());
+        let events = db.subscribe();
+
+        db.put_post(some_post("1", "0", "a")).unwrap();
+
+        assert!(db.sidecar_error().is_some());
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![added("1", "0")]);
+    }
+
+    #[test]
+    fn parent_arrived_should_be_sent_with_its_orphans() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        let events = db.subscribe();
+
+        db.put_posts(vec![some_post("2", "1", "b"), some_post("3", "1", "c")])
+            .unwrap();
+        db.put_post(some_post("1", "0", "a")).unwrap();
+
+        assert_eq!(
+            events.try_iter().skip(2).collect::<Vec<_>>(),
+            vec![
+                added("1", "0"),
+                DatabaseEvent::ParentArrived {
+                    hash: "1".to_string(),
+                    orphans: vec!["2".to_string(), "3".to_string()]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_receiver_should_be_unsubscribed() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        drop(db.subscribe());
+        let events = db.subscribe();
+
+        db.put_post(some_post("1", "0", "a")).unwrap();
+
+        assert_eq!(db.subscribers.len(), 1);
+        assert_eq!(events.recv().unwrap().hash(), "1");
+    }
+
+    fn added(hash: &str, parent: &str) -> DatabaseEvent {
+        DatabaseEvent::PostAdded {
+            hash: hash.to_string(),
+            parent: parent.to_string(),
+        }
+    }
+
+    fn db_with_trash() -> LegacyDatabase<CollectingChunkProcessor, DummyDiff> {
+        LegacyDatabase::with_config(
+            collection(vec![]),
+            collecting_chunk_processor(),
+            LegacyDatabaseConfig {
+                trash_retention: Some(Duration::from_secs(60)),
+                ..Default::default()
+            },
+        )
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+
+use super::{LegacyDatabase, LegacyDatabaseError, LegacyDatabaseResult};
+use crate::{
+    legacy_database::{
+        chunk::chunk_processor::ChunkCollectionProcessor,
+        config::EvictionOrder,
+        index::{db_post_ref::ChunkSettings, diff::Diff},
+    },
+    post::unix_time_now,
+    post_database::Database,
+};
+
+/// Metadata flag which protects the thread from eviction
+pub const PINNED_FLAG: &str = "pinned";
+
+/// Space taken by the thread and its activity
+struct ThreadUsage {
+    hash: String,
+
+    /// Live posts of the thread, including the thread itself
+    posts: usize,
+
+    /// Total length of the live post messages freed by deleting the thread.
+    /// Extents shared with the posts of other threads are not counted, as they stay.
+    bytes: u64,
+
+    replies: usize,
+
+    /// Newest receive time of the thread posts, `None` if any of them has no recorded receive time
+    active_at: Option<u64>,
+}
+
+impl<TProcessor, TDiff> LegacyDatabase<TProcessor, TDiff>
+where
+    LegacyDatabaseError: From<<TProcessor as ChunkCollectionProcessor>::Error>,
+    TProcessor: ChunkCollectionProcessor,
+    TDiff: Diff,
+{
+    /// Deletes threads which are older than [`RetentionPolicy::max_age`], and then evicts threads in
+    /// [`RetentionPolicy::eviction`] order until the database is within its limits.
+    /// Evicted threads are deleted the same way as by [`Database::delete_thread`], so their space is reused.
+    ///
+    /// Threads of the thread index categories are evicted, so the thread index must be enabled.
+    /// Protected and pinned threads are never evicted, so the database may stay over the limits.
+    /// Threads with posts of unknown receive time never expire and are evicted after all the others.
+    ///
+    /// If the policy sets any limit, this is called after every put once the thread index is enabled.
+    /// # Returns
+    /// Hashes of the evicted threads
+    /// # Errors
+    /// [`LegacyDatabaseError::ThreadIndexDisabled`] if the thread index is not enabled
+    ///
+    /// [`RetentionPolicy::max_age`]: crate::legacy_database::config::RetentionPolicy::max_age
+    /// [`RetentionPolicy::eviction`]: crate::legacy_database::config::RetentionPolicy::eviction
+    pub fn enforce_retention(&mut self) -> LegacyDatabaseResult<Vec<String>> {
+        let policy = self.config.retention.clone();
+        let mut posts = self
+            .reference
+            .iter()
+            .filter(|(_, db_ref)| !db_ref.deleted)
+            .count();
+        let mut bytes = self.reference.live_bytes();
+
+        let mut threads: Vec<ThreadUsage> = self
+            .eviction_candidates()?
+            .into_iter()
+            .filter(|hash| !policy.protected.contains(hash) && !self.is_pinned(hash))
+            .map(|hash| self.thread_usage(hash))
+            .collect();
+        match policy.eviction {
+            EvictionOrder::Oldest => {
+                threads.sort_by_key(|thread| (thread.active_at.is_none(), thread.active_at))
+            }
+            EvictionOrder::LeastReplied => threads.sort_by_key(|thread| {
+                (thread.active_at.is_none(), thread.replies, thread.active_at)
+            }),
+        }
+
+        let expired_before = policy
+            .max_age
+            .map(|age| unix_time_now().saturating_sub(age.as_secs()));
+        let mut evicted = Vec::new();
+        for thread in threads {
+            let expired = match (expired_before, thread.active_at) {
+                (Some(before), Some(active_at)) => active_at < before,
+                _ => false,
+            };
+            let over_limits = policy.max_posts.is_some_and(|max| posts > max)
+                || policy.max_bytes.is_some_and(|max| bytes > max);
+            if !expired && !over_limits {
+                continue;
+            }
+
+            self.delete_thread(thread.hash.clone())?;
+            posts -= thread.posts;
+            bytes -= thread.bytes;
+            evicted.push(thread.hash);
+        }
+
+        Ok(evicted)
+    }
+
+    /// Enforces the retention policy after the put was committed, if the policy sets any limit.
+    /// The put is kept if the enforcement fails, the error is returned by [`LegacyDatabase::sidecar_error`].
+    pub(super) fn enforce_retention_after_put(&mut self) {
+        if !self.config.retention.has_limits() || self.thread_index.is_none() {
+            return;
+        }
+
+        if let Err(err) = self.enforce_retention() {
+            self.sidecar_error = Some(err);
+        }
+    }
+
+    /// Live threads which may be evicted, before the protected ones are filtered out.
+    ///
+    /// Posts without a parent in the database aren't threads, as they may be category roots or orphan replies.
+    fn eviction_candidates(&self) -> LegacyDatabaseResult<Vec<String>> {
+        let index = self
+            .thread_index
+            .as_ref()
+            .ok_or(LegacyDatabaseError::ThreadIndexDisabled)?;
+        let mut candidates: Vec<String> = index.all_threads().cloned().collect();
+        candidates.retain(|hash| !self.reference.ref_deleted(hash));
+
+        // Hash order makes the eviction of threads with equal activity predictable
+        candidates.sort();
+        Ok(candidates)
+    }
+
+    fn is_pinned(&self, hash: &str) -> bool {
+        self.metadata
+            .get(hash)
+            .is_some_and(|metadata| metadata.flags.contains(PINNED_FLAG))
+    }
+
+    fn thread_usage(&self, hash: String) -> ThreadUsage {
+        let subtree = self.reference.subtree(&hash);
+        let mut usage = ThreadUsage {
+            hash,
+            posts: 0,
+            bytes: 0,
+            replies: subtree.len() - 1,
+            active_at: Some(0),
+        };
+
+        // Length and number of the thread posts referencing every extent
+        let mut extents: HashMap<&ChunkSettings, (u64, usize)> = HashMap::new();
+        for post in &subtree {
+            let db_ref = self.reference.get_ref(post).unwrap();
+            let post = post.to_string();
+            if !db_ref.deleted {
+                usage.posts += 1;
+                if let Some(settings) = &db_ref.chunk_settings {
+                    let extent = extents.entry(settings).or_insert((db_ref.length, 0));
+                    extent.1 += 1;
+                }
+            }
+            let received_at = self.metadata.get(&post).map(|meta| meta.received_at);
+            usage.active_at = usage.active_at.zip(received_at).map(|(a, b)| a.max(b));
+        }
+
+        usage.bytes = extents
+            .into_iter()
+            .filter(|(settings, (_, referrers))| {
+                *referrers == self.reference.extent_referrers(settings)
+            })
+            .map(|(_, (length, _))| length)
+            .sum();
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        assert_err,
+        legacy_database::{
+            config::{LegacyDatabaseConfig, RetentionPolicy},
+            index::metadata::MetadataStore,
+        },
+        post::PostMetadata,
+        tests::test_utils::*,
+    };
+
+    fn db_with_policy(
+        policy: RetentionPolicy,
+    ) -> LegacyDatabase<CollectingChunkProcessor, DummyDiff> {
+        let mut db = LegacyDatabase::with_config(
+            collection(vec![]),
+            collecting_chunk_processor(),
+            LegacyDatabaseConfig {
+                retention: policy,
+                ..Default::default()
+            },
+        );
+        db.put_posts(vec![
+            some_post("1", "0", "old"),
+            some_post("2", "1", "reply"),
+            some_post("3", "0", "middle"),
+            some_post("4", "0", "new"),
+        ])
+        .unwrap();
+        db.enable_thread_index(vec!["0".to_string()]).unwrap();
+        let now = unix_time_now();
+        for (hash, age) in [("1", 300), ("2", 250), ("3", 200), ("4", 0)] {
+            set_metadata(&mut db, hash, now - age, &[]);
+        }
+
+        db
+    }
+
+    fn set_metadata(
+        db: &mut LegacyDatabase<CollectingChunkProcessor, DummyDiff>,
+        hash: &str,
+        received_at: u64,
+        flags: &[&str],
+    ) {
+        let metadata = PostMetadata {
+            received_at,
+            flags: flags.iter().map(|flag| flag.to_string()).collect(),
+            ..Default::default()
+        };
+        db.set_post_metadata(hash.to_string(), metadata).unwrap();
+    }
+
+    #[test]
+    fn enforce_retention_should_evict_oldest_threads_until_within_limits() {
+        let mut db = db_with_policy(RetentionPolicy {
+            max_posts: Some(2),
+            ..Default::default()
+        });
+
+        let evicted = db.enforce_retention().unwrap();
+
+        assert_eq!(evicted, vec!["1".to_string()]);
+        assert!(db.reference.ref_deleted("2"));
+        assert!(!db.reference.ref_deleted("3"));
+    }
+
+    #[test]
+    fn enforce_retention_should_evict_least_replied_threads_first() {
+        let mut db = db_with_policy(RetentionPolicy {
+            max_bytes: Some(12),
+            eviction: EvictionOrder::LeastReplied,
+            ..Default::default()
+        });
+
+        let evicted = db.enforce_retention().unwrap();
+
+        assert_eq!(evicted, vec!["3".to_string()]);
+    }
+
+    #[test]
+    fn enforce_retention_should_delete_expired_threads_except_protected() {
+        let mut db = db_with_policy(RetentionPolicy {
+            max_age: Some(Duration::from_secs(100)),
+            protected: vec!["3".to_string()].into_iter().collect(),
+            ..Default::default()
+        });
+        let now = unix_time_now();
+        set_metadata(&mut db, "1", now - 300, &[PINNED_FLAG]);
+
+        let evicted = db.enforce_retention().unwrap();
+
+        assert!(evicted.is_empty());
+
+        set_metadata(&mut db, "1", now - 300, &[]);
+        assert_eq!(db.enforce_retention().unwrap(), vec!["1".to_string()]);
+    }
+
+    #[test]
+    fn enforce_retention_without_limits_should_keep_everything() {
+        let mut db = db_with_policy(RetentionPolicy::default());
+
+        assert!(db.enforce_retention().unwrap().is_empty());
+    }
+
+    #[test]
+    fn enforce_retention_should_keep_threads_of_unknown_receive_time_for_last() {
+        let mut db = db_with_policy(RetentionPolicy {
+            max_posts: Some(2),
+            max_age: Some(Duration::from_secs(100)),
+            ..Default::default()
+        });
+        db.set_metadata_store(MetadataStore::default());
+        set_metadata(&mut db, "3", unix_time_now() - 200, &[]);
+
+        let evicted = db.enforce_retention().unwrap();
+
+        assert_eq!(evicted, vec!["3".to_string(), "1".to_string()]);
+        assert!(!db.reference.ref_deleted("4"));
+    }
+
+    #[test]
+    fn enforce_retention_should_count_shared_extents_once() {
+        let refs = vec![some_raw_ref("1", "0", 5), some_raw_ref("2", "0", 5)];
+        let config = LegacyDatabaseConfig {
+            retention: RetentionPolicy {
+                max_bytes: Some(5),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut db =
+            LegacyDatabase::with_config(collection(refs), collecting_chunk_processor(), config);
+        db.enable_thread_index(vec!["0".to_string()]).unwrap();
+
+        assert!(db.enforce_retention().unwrap().is_empty());
+    }
+
+    #[test]
+    fn put_should_enforce_retention_once_thread_index_is_enabled() {
+        let config = LegacyDatabaseConfig {
+            retention: RetentionPolicy {
+                max_posts: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut db =
+            LegacyDatabase::with_config(collection(vec![]), collecting_chunk_processor(), config);
+        db.enable_thread_index(vec!["0".to_string()]).unwrap();
+        db.put_posts(vec![
+            some_post("1", "0", "first"),
+            some_post("2", "0", "second"),
+        ])
+        .unwrap();
+
+        db.put_post(some_post("3", "0", "third")).unwrap();
+
+        assert!(db.reference.ref_deleted("1"));
+        assert!(!db.reference.ref_deleted("2"));
+        assert!(!db.reference.ref_deleted("3"));
+        assert!(db.sidecar_error().is_none());
+    }
+
+    #[test]
+    fn enforce_retention_without_thread_index_should_return_error() {
+        let mut db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+        db.put_post(some_post("1", "0", "thread")).unwrap();
+
+        assert_err!(
+            db.enforce_retention(),
+            LegacyDatabaseError::ThreadIndexDisabled
+        );
+    }
+}
@@ -0,0 +1,632 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{LegacyDatabase, LegacyDatabaseError, LegacyDatabaseResult};
+use crate::{
+    legacy_database::{
+        chunk::{
+            chunk_index_to_name, chunk_name_to_index, chunk_processor::ChunkCollectionProcessor,
+            compression::DICTIONARY_FILENAME, ChunkIndex,
+        },
+        crypto::KEY_FILENAME,
+        index::{
+            ban_list::BAN_LIST_FILENAME,
+            db_post_ref::ChunkSettings,
+            diff::{self, Diff, DiffPosition, DIFF_FILENAME},
+            index_file,
+            metadata::METADATA_FILENAME,
+            search::SEARCH_INDEX_FILENAME,
+            serialized::{DbPostRefSerialized, PostHashes, INDEX_FILENAME},
+            trash::TRASH_FILENAME,
+        },
+    },
+    post::unix_time_now,
+};
+
+/// Written into the snapshot directory after everything else, so its presence means the snapshot is complete
+pub const SNAPSHOT_MANIFEST_FILENAME: &str = "snapshot-3.json";
+
+/// Number of chunk bytes read at once while copying
+const COPY_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Files next to the `index-3.json` which are copied into the snapshot as is
+const SIDECAR_FILES: &[&str] = &[
+    KEY_FILENAME,
+    DICTIONARY_FILENAME,
+    METADATA_FILENAME,
+    BAN_LIST_FILENAME,
+    SEARCH_INDEX_FILENAME,
+    TRASH_FILENAME,
+];
+
+/// State of the database at the moment the snapshot was made
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotManifest {
+    /// Unix time of the snapshot
+    pub created_at: u64,
+
+    /// Number of bytes of every chunk copied into the snapshot
+    pub chunk_sizes: BTreeMap<ChunkIndex, u64>,
+
+    /// End of the database diff at the moment of the snapshot, see [`LegacyDatabase::snapshot_incremental`]
+    #[serde(default)]
+    pub diff_position: Option<DiffPosition>,
+}
+
+impl SnapshotManifest {
+    /// Reads the manifest of the snapshot in the directory
+    /// # Returns
+    /// `None` if there's no complete snapshot in the directory
+    pub fn load(dir: &Path) -> LegacyDatabaseResult<Option<Self>> {
+        match File::open(dir.join(SNAPSHOT_MANIFEST_FILENAME)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(io::BufReader::new(file))?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, dir: &Path) -> LegacyDatabaseResult<()> {
+        write_atomically(
+            &dir.join(SNAPSHOT_MANIFEST_FILENAME),
+            &serde_json::to_vec(self)?,
+        )
+    }
+}
+
+/// Result of [`LegacyDatabase::snapshot`] and [`LegacyDatabase::snapshot_incremental`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SnapshotReport {
+    /// References written into the index (full snapshot) or appended to the diff (incremental one)
+    pub refs: usize,
+
+    /// Chunk bytes copied into the snapshot
+    pub chunk_bytes: u64,
+}
+
+impl<TProcessor, TDiff> LegacyDatabase<TProcessor, TDiff>
+where
+    LegacyDatabaseError: From<<TProcessor as ChunkCollectionProcessor>::Error>,
+    TProcessor: ChunkCollectionProcessor,
+    TDiff: Diff,
+{
+    /// Writes all references into `index-3.json`, replacing it atomically.
+    ///
+    /// Diff is left as is: replaying it over the new index gives the same references,
+    /// so a crash between the checkpoint and the next start loses nothing.
+    pub fn checkpoint(&mut self) -> LegacyDatabaseResult<()> {
+        self.chunk_processor.sync()?;
+        self.reference.sync_diff()?;
+        self.write_index(Path::new(INDEX_FILENAME))?;
+        Ok(())
+    }
+
+    /// Copies the database in the current directory into `dest`, which must exist.
+    /// Copy is consistent: chunks are copied up to their sizes at the moment of the snapshot,
+    /// so the data written after that is never referenced by the copied index.
+    ///
+    /// Existing snapshot in `dest` is overwritten.
+    pub fn snapshot(&mut self, dest: &Path) -> LegacyDatabaseResult<SnapshotReport> {
+        self.chunk_processor.sync()?;
+        self.reference.sync_diff()?;
+
+        // Incomplete snapshot must not be mistaken for a base of the incremental one
+        remove_if_exists(&dest.join(SNAPSHOT_MANIFEST_FILENAME))?;
+
+        let mut manifest = SnapshotManifest {
+            created_at: unix_time_now(),
+            chunk_sizes: BTreeMap::new(),
+            diff_position: self.reference.diff_position()?,
+        };
+        let mut report = SnapshotReport::default();
+        for (chunk_index, size) in self.chunk_sizes()? {
+            let mut target = File::create(dest.join(chunk_index_to_name(chunk_index)))?;
+            report.chunk_bytes += self.copy_range(chunk_index, &mut target, 0, size)?;
+            target.sync_data()?;
+            manifest.chunk_sizes.insert(chunk_index, size);
+        }
+
+        report.refs = self.write_index(&dest.join(INDEX_FILENAME))?;
+        remove_if_exists(&dest.join(DIFF_FILENAME))?;
+        copy_sidecars(dest)?;
+
+        manifest.save(dest)?;
+        Ok(report)
+    }
+
+    /// Brings the snapshot made by [`LegacyDatabase::snapshot`] in `dest` up to date.
+    /// Only chunk data written since the last snapshot is copied, and changed references are appended to its diff.
+    /// Messages of the posts deleted since then are zeroed in the snapshot, as they're erased from the database.
+    ///
+    /// Changes are read from the diff lines written since the last snapshot. If the database was reopened
+    /// since then, its diff was started anew, so all references are compared with the snapshot ones instead.
+    /// # Errors
+    /// [`LegacyDatabaseError::NoSnapshot`] if there's no complete snapshot in `dest`
+    pub fn snapshot_incremental(&mut self, dest: &Path) -> LegacyDatabaseResult<SnapshotReport> {
+        let mut manifest = SnapshotManifest::load(dest)?.ok_or(LegacyDatabaseError::NoSnapshot)?;
+        self.chunk_processor.sync()?;
+        self.reference.sync_diff()?;
+
+        let appended = match &manifest.diff_position {
+            Some(position) => self.reference.diff_since(position)?,
+            None => None,
+        };
+        let (changed, erased) = match appended {
+            Some(appended) => self.changes_in_diff(appended),
+            None => self.changes_since_snapshot(dest)?,
+        };
+
+        let mut report = SnapshotReport {
+            refs: changed.len(),
+            chunk_bytes: 0,
+        };
+        let old_sizes = manifest.chunk_sizes.clone();
+        for (chunk_index, size) in self.chunk_sizes()? {
+            let old_size = old_sizes.get(&chunk_index).copied().unwrap_or(0);
+            let chunk_name = chunk_index_to_name(chunk_index);
+            let mut target = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dest.join(&chunk_name))?;
+
+            // Zeroed before the copying, as the space may be already reused by the copied posts
+            for old_ref in &erased {
+                if old_ref.chunk_name.as_ref() == Some(&chunk_name) {
+                    let length = self.chunk_processor.extent_length(old_ref.length);
+                    zero_range(&mut target, old_ref.offset, length)?;
+                }
+            }
+
+            if size > old_size {
+                report.chunk_bytes +=
+                    self.copy_range(chunk_index, &mut target, old_size, size - old_size)?;
+            }
+
+            // Space of the deleted posts is reused, so new messages may be written before the copied end
+            for db_ref in changed.iter().filter(|db_ref| !db_ref.deleted) {
+                if db_ref.chunk_name.as_ref() != Some(&chunk_name) || db_ref.offset >= old_size {
+                    continue;
+                }
+
+                let length = self.chunk_processor.extent_length(db_ref.length);
+                report.chunk_bytes +=
+                    self.copy_range(chunk_index, &mut target, db_ref.offset, length)?;
+            }
+
+            target.sync_data()?;
+            manifest.chunk_sizes.insert(chunk_index, size.max(old_size));
+        }
+
+        let mut lines = String::new();
+        for db_ref in &changed {
+            lines.push_str(&diff::encode_line(db_ref, self.config.cipher.as_ref())?);
+        }
+        let mut diff_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(dest.join(DIFF_FILENAME))?;
+        diff_file.write_all(lines.as_bytes())?;
+        diff_file.sync_data()?;
+
+        copy_sidecars(dest)?;
+
+        manifest.created_at = unix_time_now();
+        manifest.diff_position = self.reference.diff_position()?;
+        manifest.save(dest)?;
+        Ok(report)
+    }
+
+    /// Changed references and erased extents from the diff lines written since the last snapshot
+    fn changes_in_diff(
+        &self,
+        appended: Vec<DbPostRefSerialized>,
+    ) -> (Vec<DbPostRefSerialized>, Vec<DbPostRefSerialized>) {
+        // Every deleted line is checked, as the later ones may have lost the extent when its space was reused
+        let erased = appended
+            .iter()
+            .filter(|db_ref| db_ref.deleted && self.is_erased(db_ref))
+            .cloned()
+            .collect();
+
+        // Last line of the post is its current state
+        let mut seen = HashSet::new();
+        let mut changed: Vec<DbPostRefSerialized> = appended
+            .into_iter()
+            .rev()
+            .filter(|db_ref| seen.insert(db_ref.hash.clone()))
+            .collect();
+        changed.reverse();
+
+        (changed, erased)
+    }
+
+    /// Changed references and erased extents found by comparing all references with the snapshot ones
+    fn changes_since_snapshot(
+        &self,
+        dest: &Path,
+    ) -> LegacyDatabaseResult<(Vec<DbPostRefSerialized>, Vec<DbPostRefSerialized>)> {
+        let mut snapshot_refs = self.read_snapshot_refs(dest)?;
+        let changed: Vec<DbPostRefSerialized> = self
+            .reference
+            .to_index_collection()
+            .indexes
+            .into_iter()
+            .filter(|db_ref| snapshot_refs.get(&db_ref.hash) != Some(db_ref))
+            .collect();
+
+        let erased = changed
+            .iter()
+            .filter(|db_ref| db_ref.deleted)
+            .filter_map(|db_ref| snapshot_refs.remove(&db_ref.hash))
+            .filter(|old_ref| !old_ref.deleted && self.is_erased(old_ref))
+            .collect();
+
+        Ok((changed, erased))
+    }
+
+    /// Whether the extent of the reference is erased from the chunk.
+    /// Extent of the deleted post stays in the chunk while another live post shares it.
+    fn is_erased(&self, db_ref: &DbPostRefSerialized) -> bool {
+        match &db_ref.chunk_name {
+            Some(chunk_name) => {
+                let settings = ChunkSettings {
+                    chunk_index: chunk_name_to_index(chunk_name.clone()),
+                    offset: db_ref.offset,
+                };
+                self.reference.extent_referrers(&settings) == 0
+            }
+            None => false,
+        }
+    }
+
+    /// Sizes of all chunks, which are numbered without gaps
+    fn chunk_sizes(&self) -> LegacyDatabaseResult<BTreeMap<ChunkIndex, u64>> {
+        let mut sizes = BTreeMap::new();
+        let mut chunk_index = 0;
+        while let Some(size) = self.chunk_processor.chunk_size(chunk_index)? {
+            sizes.insert(chunk_index, size);
+            chunk_index += 1;
+        }
+
+        Ok(sizes)
+    }
+
+    /// Copies `length` bytes starting from `offset` of the chunk into the same place of the target.
+    /// Bytes are copied as they are stored, block by block, so the whole chunk is never held in memory.
+    /// # Returns
+    /// Number of copied bytes
+    fn copy_range(
+        &self,
+        chunk_index: ChunkIndex,
+        target: &mut File,
+        offset: u64,
+        length: u64,
+    ) -> LegacyDatabaseResult<u64> {
+        target.seek(SeekFrom::Start(offset))?;
+        let mut copied = 0;
+        while copied < length {
+            let block = (length - copied).min(COPY_BLOCK_SIZE);
+            let bytes = self
+                .chunk_processor
+                .read_range(chunk_index, offset + copied, block)?;
+            target.write_all(&bytes)?;
+            copied += block;
+        }
+
+        Ok(copied)
+    }
+
+    /// Writes the index of all references to the path in [`LegacyDatabaseConfig::index_format`],
+    /// encrypted if [`LegacyDatabaseConfig::cipher`] is set
+    /// # Returns
+    /// Number of written references
+    ///
+    /// [`LegacyDatabaseConfig::index_format`]: crate::legacy_database::config::LegacyDatabaseConfig::index_format
+    /// [`LegacyDatabaseConfig::cipher`]: crate::legacy_database::config::LegacyDatabaseConfig::cipher
+    fn write_index(&self, path: &Path) -> LegacyDatabaseResult<usize> {
+        let mut contents = Vec::new();
+        let refs = self
+            .reference
+            .write_index(&mut contents, self.config.index_format)?;
+        if let Some(cipher) = &self.config.cipher {
+            contents = index_file::seal_index(&contents, cipher);
+        }
+
+        write_atomically(path, &contents)?;
+        Ok(refs)
+    }
+
+    /// Reads the references of the snapshot, with its diff applied
+    fn read_snapshot_refs(
+        &self,
+        dest: &Path,
+    ) -> LegacyDatabaseResult<HashMap<String, DbPostRefSerialized>> {
+        let mut index = fs::read(dest.join(INDEX_FILENAME))?;
+        if let Some(cipher) = &self.config.cipher {
+            index = index_file::open_index(&index, cipher)?;
+        }
+
+        let mut refs = HashMap::new();
+        index_file::read_index(index.as_slice(), &mut |hashes: PostHashes, db_ref| {
+            let db_ref = DbPostRefSerialized::new(&hashes, &db_ref);
+            refs.insert(db_ref.hash.clone(), db_ref);
+        })?;
+        let diff = diff::read_diff_file(&dest.join(DIFF_FILENAME), self.config.cipher.as_ref())?;
+        refs.extend(diff.into_iter().map(|db_ref| (db_ref.hash.clone(), db_ref)));
+
+        Ok(refs)
+    }
+}
+
+fn zero_range(target: &mut File, offset: u64, length: u64) -> io::Result<()> {
+    target.seek(SeekFrom::Start(offset))?;
+    io::copy(&mut io::repeat(0).take(length), target)?;
+    Ok(())
+}
+
+fn copy_sidecars(dest: &Path) -> io::Result<()> {
+    for file in SIDECAR_FILES {
+        if Path::new(file).exists() {
+            fs::copy(file, dest.join(file))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the file next to the target and renames it, so the target is never left half-written
+fn write_atomically(path: &Path, contents: &[u8]) -> LegacyDatabaseResult<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::current_dir;
+
+    use super::*;
+    use crate::{
+        in_temp_dir,
+        legacy_database::{
+            chunk::{chunk_processor::OnDiskChunkCollectionProcessor, Chunk},
+            config::{IndexFormat, LegacyDatabaseConfig},
+            index::{diff::DiffFile, serialized::IndexCollection, DbRefCollection},
+        },
+        post::PostEntry,
+        post_database::Database,
+        tests::test_utils::*,
+    };
+    use rusty_fork::rusty_fork_test;
+
+    type DiskDatabase = LegacyDatabase<OnDiskChunkCollectionProcessor<Chunk>, DiffFile>;
+
+    /// Opens the database in the current directory
+    fn open() -> DiskDatabase {
+        open_with(Default::default())
+    }
+
+    fn open_with(config: LegacyDatabaseConfig) -> DiskDatabase {
+        let reference = match File::open(INDEX_FILENAME) {
+            Ok(file) => DbRefCollection::from_reader(file).unwrap(),
+            Err(_) => DbRefCollection::new(IndexCollection { indexes: vec![] }).unwrap(),
+        };
+        LegacyDatabase::with_config(
+            reference,
+            OnDiskChunkCollectionProcessor::new(Default::default()).unwrap(),
+            config,
+        )
+    }
+
+    fn backup_dir() -> std::path::PathBuf {
+        let dir = current_dir().unwrap().join("backup");
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn message(db: &mut DiskDatabase, hash: &str) -> Option<String> {
+        match db.get_post(hash.to_string()).unwrap()? {
+            PostEntry::Live(post) => Some(post.message.as_str().to_string()),
+            PostEntry::Deleted { .. } => None,
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn snapshot_should_not_include_posts_put_after_it() {
+            in_temp_dir!({
+                let dest = backup_dir();
+                let mut db = open();
+                db.put_posts(vec![some_post("1", "0", "first"), some_post("2", "1", "second")])
+                    .unwrap();
+
+                let report = db.snapshot(&dest).unwrap();
+                db.put_post(some_post("3", "1", "third")).unwrap();
+                drop(db);
+
+                assert_eq!(report, SnapshotReport { refs: 2, chunk_bytes: 11 });
+                set_current_dir(&dest).unwrap();
+                let mut copy = open();
+                assert_eq!(message(&mut copy, "1").as_deref(), Some("first"));
+                assert_eq!(message(&mut copy, "2").as_deref(), Some("second"));
+                assert!(copy.get_post("3".to_string()).unwrap().is_none());
+                assert!(SnapshotManifest::load(&dest).unwrap().is_some());
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn snapshot_incremental_should_copy_appended_and_reused_space() {
+            in_temp_dir!({
+                let dest = backup_dir();
+                let mut db = open();
+                db.put_posts(vec![some_post("1", "0", "first"), some_post("2", "1", "second")])
+                    .unwrap();
+                db.snapshot(&dest).unwrap();
+
+                db.delete_post("1".to_string()).unwrap();
+                db.put_posts(vec![some_post("3", "0", "fresh"), some_post("4", "3", "appended")])
+                    .unwrap();
+                let report = db.snapshot_incremental(&dest).unwrap();
+                drop(db);
+
+                assert_eq!(report, SnapshotReport { refs: 3, chunk_bytes: 13 });
+                set_current_dir(&dest).unwrap();
+                let mut copy = open();
+                assert_eq!(message(&mut copy, "1"), None);
+                assert_eq!(message(&mut copy, "2").as_deref(), Some("second"));
+                assert_eq!(message(&mut copy, "3").as_deref(), Some("fresh"));
+                assert_eq!(message(&mut copy, "4").as_deref(), Some("appended"));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn snapshot_incremental_should_zero_messages_of_deleted_posts() {
+            in_temp_dir!({
+                let dest = backup_dir();
+                let mut db = open();
+                db.put_posts(vec![some_post("1", "0", "first"), some_post("2", "1", "second")])
+                    .unwrap();
+                db.snapshot(&dest).unwrap();
+
+                db.delete_post("2".to_string()).unwrap();
+                db.snapshot_incremental(&dest).unwrap();
+                drop(db);
+
+                let chunk = fs::read(dest.join(chunk_index_to_name(0))).unwrap();
+                assert_eq!(chunk, b"first\0\0\0\0\0\0");
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn snapshot_incremental_should_append_only_refs_changed_since_last_snapshot() {
+            in_temp_dir!({
+                let dest = backup_dir();
+                let mut db = open();
+                db.put_posts(vec![some_post("1", "0", "first"), some_post("2", "1", "second")])
+                    .unwrap();
+                db.snapshot(&dest).unwrap();
+                db.put_post(some_post("4", "1", "fourth")).unwrap();
+                db.snapshot_incremental(&dest).unwrap();
+
+                db.put_post(some_post("3", "1", "third")).unwrap();
+                db.delete_post("3".to_string()).unwrap();
+                let report = db.snapshot_incremental(&dest).unwrap();
+                let unchanged = db.snapshot_incremental(&dest).unwrap();
+                drop(db);
+
+                assert_eq!(report.refs, 1);
+                assert_eq!(unchanged, SnapshotReport::default());
+                let chunk = fs::read(dest.join(chunk_index_to_name(0))).unwrap();
+                assert_eq!(chunk, b"firstsecondfourth\0\0\0\0\0");
+                set_current_dir(&dest).unwrap();
+                let mut copy = open();
+                assert_eq!(message(&mut copy, "4").as_deref(), Some("fourth"));
+                assert_eq!(message(&mut copy, "3"), None);
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn snapshot_incremental_after_reopening_should_compare_refs_with_snapshot() {
+            in_temp_dir!({
+                let dest = backup_dir();
+                let mut db = open();
+                db.put_posts(vec![some_post("1", "0", "first"), some_post("2", "1", "second")])
+                    .unwrap();
+                db.snapshot(&dest).unwrap();
+                drop(db);
+
+                let mut db = open();
+                db.delete_post("2".to_string()).unwrap();
+                let report = db.snapshot_incremental(&dest).unwrap();
+                drop(db);
+
+                assert_eq!(report, SnapshotReport { refs: 1, chunk_bytes: 0 });
+                let chunk = fs::read(dest.join(chunk_index_to_name(0))).unwrap();
+                assert_eq!(chunk, b"first\0\0\0\0\0\0");
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn snapshot_incremental_without_snapshot_should_return_error() {
+            in_temp_dir!({
+                let dest = backup_dir();
+                let mut db = open();
+                db.put_post(some_post("1", "0", "first")).unwrap();
+
+                let result = db.snapshot_incremental(&dest);
+
+                assert!(matches!(result, Err(LegacyDatabaseError::NoSnapshot)));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn checkpoint_should_keep_posts_drained_from_diff() {
+            in_temp_dir!({
+                let mut db = open();
+                db.put_posts(vec![some_post("1", "0", "first"), some_post("2", "1", "second")])
+                    .unwrap();
+                drop(db);
+                // Opening drains the diff, so the posts are only in memory now
+                let mut db = open();
+
+                db.checkpoint().unwrap();
+                drop(db);
+                drop(open());
+                let mut db = open();
+
+                assert_eq!(message(&mut db, "1").as_deref(), Some("first"));
+                assert_eq!(message(&mut db, "2").as_deref(), Some("second"));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn checkpoint_with_binary_index_should_be_opened() {
+            in_temp_dir!({
+                let config = LegacyDatabaseConfig {
+                    index_format: IndexFormat::Binary,
+                    ..Default::default()
+                };
+                let mut db = open_with(config.clone());
+                db.put_posts(vec![some_post("1", "0", "first"), some_post("2", "1", "second")])
+                    .unwrap();
+                db.checkpoint().unwrap();
+                drop(db);
+
+                assert_eq!(fs::read(INDEX_FILENAME).unwrap()[0], 0);
+                let mut db = open_with(config);
+                assert_eq!(message(&mut db, "1").as_deref(), Some("first"));
+                assert_eq!(message(&mut db, "2").as_deref(), Some("second"));
+            });
+        }
+    }
+}
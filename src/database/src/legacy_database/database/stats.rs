@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use super::{LegacyDatabase, LegacyDatabaseError, LegacyDatabaseResult};
+use crate::legacy_database::{
+    chunk::{chunk_processor::ChunkCollectionProcessor, ChunkIndex},
+    index::diff::Diff,
+};
+
+/// Result of [`LegacyDatabase::stats`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DatabaseStats {
+    /// Number of all post references, including deleted ones
+    pub posts: usize,
+
+    pub deleted_posts: usize,
+
+    /// Total length of live post messages. Extent shared by posts with identical messages is counted once.
+    pub live_bytes: u64,
+
+    /// Total length of deleted post messages whose space can be reused
+    pub free_bytes: u64,
+
+    /// Sizes of the chunk files referenced by posts. Missing chunk files are not included.
+    pub chunk_sizes: BTreeMap<ChunkIndex, u64>,
+
+    /// Size of the diff, which is not merged into `index-3.json` yet
+    pub diff_bytes: u64,
+}
+
+impl DatabaseStats {
+    pub fn live_posts(&self) -> usize {
+        self.posts - self.deleted_posts
+    }
+
+    pub fn chunk_bytes(&self) -> u64 {
+        self.chunk_sizes.values().sum()
+    }
+
+    /// Share of the message space which is taken by deleted posts, from `0.0` to `1.0`
+    pub fn fragmentation(&self) -> f64 {
+        let used = self.live_bytes + self.free_bytes;
+        if used == 0 {
+            return 0.0;
+        }
+
+        self.free_bytes as f64 / used as f64
+    }
+}
+
+impl<TProcessor, TDiff> LegacyDatabase<TProcessor, TDiff>
+where
+    LegacyDatabaseError: From<<TProcessor as ChunkCollectionProcessor>::Error>,
+    TProcessor: ChunkCollectionProcessor,
+    TDiff: Diff,
+{
+    /// Collects post counts, space usage and diff size
+    pub fn stats(&self) -> LegacyDatabaseResult<DatabaseStats> {
+        let mut stats = DatabaseStats {
+            live_bytes: self.reference.live_bytes(),
+            free_bytes: self.reference.free_bytes(),
+            diff_bytes: self.reference.diff_size()?,
+            ..Default::default()
+        };
+
+        let mut chunks = Vec::new();
+        for (_, db_ref) in self.reference.iter() {
+            stats.posts += 1;
+            if db_ref.deleted {
+                stats.deleted_posts += 1;
+            }
+
+            if let Some(settings) = &db_ref.chunk_settings {
+                chunks.push(settings.chunk_index);
+            }
+        }
+
+        chunks.sort_unstable();
+        chunks.dedup();
+        for chunk_index in chunks {
+            if let Some(size) = self.chunk_processor.chunk_size(chunk_index)? {
+                stats.chunk_sizes.insert(chunk_index, size);
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{post_database::Database, tests::test_utils::*};
+
+    #[test]
+    fn stats_should_count_posts_and_space() {
+        let mut processor = collecting_chunk_processor();
+        processor.chunk_sizes.insert(0, 100);
+        let mut db = LegacyDatabase::new(collection(vec![]), processor);
+        db.put_posts(vec![
+            some_post("1", "0", "hello"),
+            some_post("2", "0", "world!"),
+            some_post("3", "1", "bye"),
+        ])
+        .unwrap();
+        db.delete_post("2".to_string()).unwrap();
+
+        let stats = db.stats().unwrap();
+
+        assert_eq!(stats.posts, 3);
+        assert_eq!(stats.live_posts(), 2);
+        assert_eq!(stats.live_bytes, 8);
+        assert_eq!(stats.free_bytes, 6);
+        assert_eq!(stats.chunk_bytes(), 100);
+        assert!((stats.fragmentation() - 6.0 / 14.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn stats_should_count_shared_extent_once() {
+        let refs = vec![some_raw_ref("1", "0", 5), some_raw_ref("2", "0", 5)];
+        let db = LegacyDatabase::new(collection(refs), collecting_chunk_processor());
+
+        let stats = db.stats().unwrap();
+
+        assert_eq!(stats.live_posts(), 2);
+        assert_eq!(stats.live_bytes, 5);
+    }
+
+    #[test]
+    fn stats_of_empty_database_should_not_be_fragmented() {
+        let db = LegacyDatabase::new(collection(vec![]), collecting_chunk_processor());
+
+        let stats = db.stats().unwrap();
+
+        assert_eq!(stats, DatabaseStats::default());
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+}
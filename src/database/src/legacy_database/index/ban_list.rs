@@ -0,0 +1,318 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const BAN_LIST_FILENAME: &str = "bans-3.json";
+const BAN_LIST_TMP_FILENAME: &str = "bans-3.json.tmp";
+
+/// Number of the rejected posts which are remembered, the earliest rejected ones are forgotten first
+pub const MAX_REJECTED_POSTS: usize = 100_000;
+
+#[derive(Debug, Error)]
+pub enum BanListError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+
+    #[error("Error (de)serializing ban list")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("Invalid ban pattern")]
+    InvalidPattern(#[from] regex::Error),
+}
+
+pub type BanListResult<T> = Result<T, BanListError>;
+
+/// Reason for the post to be rejected
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum BanRule {
+    /// Single post with the hash
+    Post(String),
+
+    /// Post with the hash and all of its replies, recursively
+    Thread(String),
+
+    /// Any post whose message matches the regular expression
+    Pattern(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct BanListSerialized {
+    rules: Vec<BanRule>,
+
+    /// Rejected posts in the order they were rejected
+    #[serde(default)]
+    rejected: Vec<(String, BanRule)>,
+}
+
+/// Posts which must not get into the database, stored in `bans-3.json`
+#[derive(Debug, Default)]
+pub struct BanList {
+    posts: HashSet<String>,
+    threads: HashSet<String>,
+    patterns: Vec<Regex>,
+
+    /// Hashes of the posts rejected by thread bans with the rule they were rejected by.
+    /// Rejected posts are never stored, so their replies are found banned by their parent hash.
+    rejected: HashMap<String, BanRule>,
+
+    /// Keys of the `rejected` in the order they were rejected, so the earliest ones are forgotten first
+    rejection_order: VecDeque<String>,
+}
+
+impl BanList {
+    /// Loads the list from `bans-3.json`. Returns an empty list if the file doesn't exist.
+    pub fn load() -> BanListResult<Self> {
+        let path = Path::new(BAN_LIST_FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let reader = BufReader::new(File::open(path)?);
+        let serialized: BanListSerialized = serde_json::from_reader(reader)?;
+
+        let mut list = Self::default();
+        for rule in serialized.rules {
+            list.add(rule)?;
+        }
+        for (hash, rule) in serialized.rejected {
+            list.reject(hash, rule);
+        }
+        Ok(list)
+    }
+
+    /// Writes the list into `bans-3.json`. The file is replaced atomically.
+    pub fn save(&self) -> BanListResult<()> {
+        let serialized = BanListSerialized {
+            rules: self.rules(),
+            rejected: self
+                .rejection_order
+                .iter()
+                .map(|hash| (hash.clone(), self.rejected[hash].clone()))
+                .collect(),
+        };
+
+        let mut writer = BufWriter::new(File::create(BAN_LIST_TMP_FILENAME)?);
+        serde_json::to_writer(&mut writer, &serialized)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(BAN_LIST_TMP_FILENAME, BAN_LIST_FILENAME)?;
+
+        Ok(())
+    }
+
+    /// # Returns
+    /// `false` if the rule was already in the list
+    /// # Errors
+    /// [BanListError::InvalidPattern] if [BanRule::Pattern] is not a valid regular expression
+    pub fn add(&mut self, rule: BanRule) -> BanListResult<bool> {
+        let added = match rule {
+            BanRule::Post(hash) => self.posts.insert(hash),
+            BanRule::Thread(hash) => self.threads.insert(hash),
+            BanRule::Pattern(pattern) => {
+                if self.patterns.iter().any(|regex| regex.as_str() == pattern) {
+                    return Ok(false);
+                }
+                self.patterns.push(Regex::new(&pattern)?);
+                true
+            }
+        };
+
+        Ok(added)
+    }
+
+    /// Posts rejected by the rule are forgotten, so they and their replies may be put again.
+    /// # Returns
+    /// `false` if the rule wasn't in the list
+    pub fn remove(&mut self, rule: &BanRule) -> bool {
+        let removed = match rule {
+            BanRule::Post(hash) => self.posts.remove(hash),
+            BanRule::Thread(hash) => self.threads.remove(hash),
+            BanRule::Pattern(pattern) => {
+                let count = self.patterns.len();
+                self.patterns.retain(|regex| regex.as_str() != pattern);
+                count != self.patterns.len()
+            }
+        };
+
+        self.rejected.retain(|_, by| by != rule);
+        let rejected = &self.rejected;
+        self.rejection_order
+            .retain(|hash| rejected.contains_key(hash));
+        removed
+    }
+
+    /// Remembers the post rejected by the thread ban, so its replies are rejected by the same rule.
+    /// Posts rejected by other rules are not remembered, as the rules ban the post itself only.
+    /// Up to [MAX_REJECTED_POSTS] posts are remembered, replies to the forgotten ones are accepted.
+    pub fn reject(&mut self, hash: String, rule: BanRule) {
+        if !matches!(rule, BanRule::Thread(_)) {
+            return;
+        }
+
+        if self.rejected.insert(hash.clone(), rule).is_none() {
+            self.rejection_order.push_back(hash);
+        }
+        if self.rejection_order.len() > MAX_REJECTED_POSTS {
+            if let Some(earliest) = self.rejection_order.pop_front() {
+                self.rejected.remove(&earliest);
+            }
+        }
+    }
+
+    /// Rule the post was rejected by, see [`BanList::reject`]
+    pub fn rejected_by(&self, hash: &str) -> Option<&BanRule> {
+        self.rejected.get(hash)
+    }
+
+    pub fn rules(&self) -> Vec<BanRule> {
+        let mut posts: Vec<_> = self.posts.iter().cloned().collect();
+        let mut threads: Vec<_> = self.threads.iter().cloned().collect();
+        posts.sort();
+        threads.sort();
+
+        let posts = posts.into_iter().map(BanRule::Post);
+        let threads = threads.into_iter().map(BanRule::Thread);
+        let patterns = self
+            .patterns
+            .iter()
+            .map(|regex| BanRule::Pattern(regex.as_str().to_string()));
+
+        posts.chain(threads).chain(patterns).collect()
+    }
+
+    pub fn is_post_banned(&self, hash: &str) -> bool {
+        self.posts.contains(hash)
+    }
+
+    /// Is the post the root of a banned thread
+    pub fn is_thread_banned(&self, hash: &str) -> bool {
+        self.threads.contains(hash)
+    }
+
+    pub fn is_message_banned(&self, message: &str) -> bool {
+        self.matching_pattern(message).is_some()
+    }
+
+    /// First banned pattern the message matches
+    pub fn matching_pattern(&self, message: &str) -> Option<&str> {
+        self.patterns
+            .iter()
+            .find(|regex| regex.is_match(message))
+            .map(|regex| regex.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_temp_dir;
+    use rusty_fork::rusty_fork_test;
+
+    #[test]
+    fn add_should_ban_posts_threads_and_messages() {
+        let list = list();
+
+        assert!(list.is_post_banned("1"));
+        assert!(!list.is_post_banned("2"));
+        assert!(list.is_thread_banned("2"));
+        assert!(list.is_message_banned("buy CHEAP pills"));
+        assert!(!list.is_message_banned("cheap"));
+    }
+
+    #[test]
+    fn add_should_reject_invalid_patterns_and_duplicates() {
+        let mut list = list();
+
+        assert!(matches!(
+            list.add(BanRule::Pattern("(".to_string())),
+            Err(BanListError::InvalidPattern(_))
+        ));
+        assert!(!list.add(BanRule::Post("1".to_string())).unwrap());
+        assert!(!list
+            .add(BanRule::Pattern("(?i)cheap pills".to_string()))
+            .unwrap());
+    }
+
+    #[test]
+    fn remove_should_unban() {
+        let mut list = list();
+
+        assert!(list.remove(&BanRule::Pattern("(?i)cheap pills".to_string())));
+        assert!(!list.remove(&BanRule::Post("2".to_string())));
+
+        assert!(!list.is_message_banned("cheap pills"));
+    }
+
+    #[test]
+    fn remove_should_forget_posts_rejected_by_the_rule() {
+        let mut list = list();
+        list.reject("3".to_string(), BanRule::Thread("2".to_string()));
+        list.reject("4".to_string(), BanRule::Thread("9".to_string()));
+
+        list.remove(&BanRule::Thread("2".to_string()));
+
+        assert!(list.rejected_by("3").is_none());
+        assert_eq!(
+            list.rejected_by("4"),
+            Some(&BanRule::Thread("9".to_string()))
+        );
+    }
+
+    #[test]
+    fn reject_should_remember_thread_rejections_only() {
+        let mut list = list();
+
+        list.reject("3".to_string(), BanRule::Post("3".to_string()));
+        list.reject("4".to_string(), BanRule::Pattern("spam".to_string()));
+
+        assert!(list.rejected_by("3").is_none());
+        assert!(list.rejected_by("4").is_none());
+    }
+
+    #[test]
+    fn reject_should_forget_earliest_rejections_over_the_limit() {
+        let mut list = list();
+
+        for i in 0..=MAX_REJECTED_POSTS {
+            list.reject(i.to_string(), BanRule::Thread("2".to_string()));
+        }
+
+        assert!(list.rejected_by("0").is_none());
+        assert!(list.rejected_by("1").is_some());
+        assert_eq!(list.rejected.len(), MAX_REJECTED_POSTS);
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn save_and_load_should_restore_rules() {
+            in_temp_dir!({
+                let mut list = list();
+                list.reject("3".to_string(), BanRule::Thread("2".to_string()));
+                list.save().unwrap();
+
+                let loaded = BanList::load().unwrap();
+
+                assert_eq!(loaded.rules(), list.rules());
+                assert_eq!(loaded.rejected, list.rejected);
+                assert_eq!(loaded.rejection_order, list.rejection_order);
+            });
+        }
+    }
+
+    fn list() -> BanList {
+        let mut list = BanList::default();
+        list.add(BanRule::Post("1".to_string())).unwrap();
+        list.add(BanRule::Thread("2".to_string())).unwrap();
+        list.add(BanRule::Pattern("(?i)cheap pills".to_string()))
+            .unwrap();
+        list
+    }
+}
@@ -1,9 +1,128 @@
-use std::rc::Rc;
+use std::{fmt, rc::Rc};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::legacy_database::chunk::ChunkIndex;
 
-/// Post hash, immutable
-pub type DbPostRefHash = Rc<String>;
+/// Length of the post hash digest in bytes, it's written as twice as many hex digits
+pub const HASH_BYTES: usize = 16;
+
+/// Post hash, immutable.
+///
+/// Hashes are hex digests, so they're kept as fixed-size binary which takes no allocation.
+/// Hashes of any other shape, e.g. from broken peers, are kept as text, so every hash is written back as it was read.
+#[derive(PartialEq, Clone, Eq, Hash, PartialOrd, Ord)]
+pub struct DbPostRefHash(HashRepr);
+
+#[derive(PartialEq, Clone, Eq, Hash, PartialOrd, Ord)]
+enum HashRepr {
+    Binary([u8; HASH_BYTES]),
+    Text(Rc<str>),
+}
+
+impl DbPostRefHash {
+    pub fn new(hash: &str) -> Self {
+        match parse_hex(hash) {
+            Some(bytes) => DbPostRefHash(HashRepr::Binary(bytes)),
+            None => DbPostRefHash(HashRepr::Text(hash.into())),
+        }
+    }
+
+    pub fn from_binary(bytes: [u8; HASH_BYTES]) -> Self {
+        DbPostRefHash(HashRepr::Binary(bytes))
+    }
+
+    /// Returns the digest, `None` if the hash is kept as text
+    pub fn as_binary(&self) -> Option<&[u8; HASH_BYTES]> {
+        match &self.0 {
+            HashRepr::Binary(bytes) => Some(bytes),
+            HashRepr::Text(_) => None,
+        }
+    }
+}
+
+/// Parses lowercase hex of the digest length. Uppercase hex is kept as text, otherwise it'd be written back in lowercase.
+fn parse_hex(hash: &str) -> Option<[u8; HASH_BYTES]> {
+    let digits = hash.as_bytes();
+    if digits.len() != HASH_BYTES * 2 {
+        return None;
+    }
+
+    let mut bytes = [0; HASH_BYTES];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+
+    Some(bytes)
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        _ => None,
+    }
+}
+
+impl fmt::Display for DbPostRefHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            HashRepr::Binary(bytes) => bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte)),
+            HashRepr::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+impl fmt::Debug for DbPostRefHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+impl From<&str> for DbPostRefHash {
+    fn from(hash: &str) -> Self {
+        DbPostRefHash::new(hash)
+    }
+}
+
+impl From<&String> for DbPostRefHash {
+    fn from(hash: &String) -> Self {
+        DbPostRefHash::new(hash)
+    }
+}
+
+impl From<&DbPostRefHash> for DbPostRefHash {
+    fn from(hash: &DbPostRefHash) -> Self {
+        hash.clone()
+    }
+}
+
+impl Serialize for DbPostRefHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DbPostRefHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HashVisitor;
+
+        impl de::Visitor<'_> for HashVisitor {
+            type Value = DbPostRefHash;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("post hash")
+            }
+
+            // Hash is parsed from the reader buffer, so binary hashes are read without allocations
+            fn visit_str<E: de::Error>(self, hash: &str) -> Result<Self::Value, E> {
+                Ok(DbPostRefHash::new(hash))
+            }
+        }
+
+        deserializer.deserialize_str(HashVisitor)
+    }
+}
 
 #[derive(Debug, PartialEq, Clone, Eq, Hash)]
 pub struct ChunkSettings {
@@ -14,13 +133,13 @@ pub struct ChunkSettings {
     pub offset: u64,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct DbPostRef {
     /// Chunk settings. `None` if post was deleted from database and its space was reused,
     /// so the message of the post is not occupying any space in the chunk.
     pub chunk_settings: Option<ChunkSettings>,
 
-    /// Post length in bytes
+    /// Length of the stored message in bytes, which is the compressed length if the database is compressed
     pub length: u64,
 
     /// Is post deleted from the database.
@@ -30,3 +149,29 @@ pub struct DbPostRef {
 
     pub parent_hash: DbPostRefHash,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_hash_should_be_kept_as_binary() {
+        let hex = "00ff10a9b8c7d6e5f4030201abcdef99";
+
+        let hash = DbPostRefHash::new(hex);
+
+        assert!(hash.as_binary().is_some());
+        assert_eq!(hash.to_string(), hex);
+        assert_eq!(hash, DbPostRefHash::from_binary(*hash.as_binary().unwrap()));
+    }
+
+    #[test]
+    fn other_hashes_should_be_kept_as_text() {
+        for text in ["1", "00FF10A9B8C7D6E5F4030201ABCDEF99", "zz", ""] {
+            let hash = DbPostRefHash::new(text);
+
+            assert!(hash.as_binary().is_none());
+            assert_eq!(hash.to_string(), text);
+        }
+    }
+}
@@ -1,17 +1,24 @@
 use std::fs::{self, File, OpenOptions};
 use std::{
-    io::{self, BufRead, BufReader, Write},
-    mem,
+    io::{self, Read, Seek, SeekFrom, Write},
     path::Path,
 };
 
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde::{Deserialize, Serialize};
+
 use super::{
+    super::crypto::Cipher,
     db_post_ref::DbPostRef,
+    json_lines::{parse_lines, JsonLinesError},
     serialized::{DbPostRefSerialized, PostHashes},
 };
 use thiserror::Error;
 
-const DIFF_FILENAME: &str = "diff-3.list";
+pub const DIFF_FILENAME: &str = "diff-3.list";
+
+/// Copy of the diff file which had corrupt lines, kept by [Diff::drain_salvaging]
+const DIFF_BACKUP_FILENAME: &str = "diff-3.list.bak";
 
 #[derive(Debug, Error)]
 pub enum DiffFileError {
@@ -19,61 +26,330 @@ pub enum DiffFileError {
     SerializationError(#[from] serde_json::Error),
     #[error("Error saving reference")]
     SavingError(#[from] std::io::Error),
+    #[error("Diff line {line_no} is corrupt")]
+    Corrupt { line_no: usize },
+    #[error("Diff is opened read-only")]
+    ReadOnly,
+}
+
+impl From<JsonLinesError> for DiffFileError {
+    fn from(err: JsonLinesError) -> Self {
+        match err {
+            JsonLinesError::IoError(err) => Self::SavingError(err),
+            JsonLinesError::SerdeError(err) => Self::SerializationError(err),
+            JsonLinesError::Corrupt { line_no } => Self::Corrupt { line_no },
+        }
+    }
 }
 
 pub type DiffResult<T> = Result<T, DiffFileError>;
 
+/// References recovered by [Diff::drain_salvaging]
+#[derive(Debug, Default)]
+pub struct SalvagedDiff {
+    pub refs: Vec<DbPostRefSerialized>,
+
+    /// Numbers (starting from 1) of the lines which could not be parsed and were skipped
+    pub corrupt_lines: Vec<usize>,
+}
+
+/// Place in the diff returned by [Diff::position]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffPosition {
+    /// Random id of the diff, which is emptied and started anew on every drain,
+    /// so an offset into the previous one is meaningless
+    pub generation: u64,
+
+    /// Number of bytes before the position
+    pub offset: u64,
+}
+
 pub trait Diff: Sized {
     fn append(&mut self, hashes: &PostHashes, db_ref: &DbPostRef) -> DiffResult<()>;
 
+    /// Appends all references with a single write.
+    fn append_batch(&mut self, refs: &[DbPostRefSerialized]) -> DiffResult<()>;
+
+    /// Flushes appended references to the disk.
+    fn sync(&mut self) -> DiffResult<()>;
+
+    /// Size of the diff in bytes
+    fn size(&self) -> DiffResult<u64>;
+
+    /// Reads all references and empties the diff.
+    /// A torn last line, left by an interrupted write, is ignored.
+    /// # Errors
+    /// [DiffFileError::Corrupt] if any other line can't be parsed. The diff is left untouched in this case.
     fn drain() -> DiffResult<(Self, Vec<DbPostRefSerialized>)>;
+
+    /// Same as [Diff::drain], but skips corrupt lines instead of failing, so every parseable reference is recovered.
+    fn drain_salvaging() -> DiffResult<(Self, SalvagedDiff)> {
+        let (diff, refs) = Self::drain()?;
+        Ok((
+            diff,
+            SalvagedDiff {
+                refs,
+                corrupt_lines: Vec::new(),
+            },
+        ))
+    }
+
+    /// End of the diff, for reading the references appended after it by [Diff::read_since].
+    /// `None` if the diff can't be read back.
+    fn position(&self) -> DiffResult<Option<DiffPosition>> {
+        Ok(None)
+    }
+
+    /// Reads the references appended since the position.
+    /// `None` if the position is of another diff, e.g. the one drained before the database was reopened.
+    fn read_since(&self, _position: &DiffPosition) -> DiffResult<Option<Vec<DbPostRefSerialized>>> {
+        Ok(None)
+    }
+}
+
+pub struct DiffFile {
+    file: File,
+
+    /// See [DiffPosition::generation]
+    generation: u64,
+
+    /// Encrypts appended lines if set, see [DiffFile::drain_encrypted]
+    cipher: Option<Cipher>,
 }
 
-pub struct DiffFile(File);
+/// Diff file which is read without being emptied, for inspecting the database while it's not in use.
+/// Appending to it fails with [DiffFileError::ReadOnly].
+/// Missing diff file, e.g. removed after the checkpoint, is read as empty without being created.
+pub struct ReadOnlyDiffFile(Option<File>);
 
 impl DiffFile {
-    fn new() -> DiffResult<Self> {
+    fn new(cipher: Option<Cipher>) -> DiffResult<Self> {
         let file = Self::create_file()?;
-        Ok(DiffFile(file))
+        Ok(DiffFile {
+            file,
+            generation: OsRng.next_u64(),
+            cipher,
+        })
+    }
+
+    /// Same as [Diff::drain], but every line is sealed by the cipher and stored base64 encoded.
+    /// Lines which can't be decrypted are treated as corrupt.
+    pub fn drain_encrypted(cipher: Cipher) -> DiffResult<(Self, Vec<DbPostRefSerialized>)> {
+        let (diff, entries) = Self::drain_entries(false, Some(cipher))?;
+        Ok((diff, entries.refs))
+    }
+
+    /// Same as [Diff::drain_salvaging] for the encrypted diff
+    pub fn drain_salvaging_encrypted(cipher: Cipher) -> DiffResult<(Self, SalvagedDiff)> {
+        Self::drain_entries(true, Some(cipher))
     }
 
     fn create_file() -> io::Result<File> {
         let file_path = Path::new(DIFF_FILENAME);
         if !file_path.exists() {
-            File::create(&file_path)?;
+            File::create(file_path)?;
         }
         let file = OpenOptions::new()
             .append(true)
             .create(true)
             .read(true)
-            .open(&file_path)?;
+            .open(file_path)?;
         Ok(file)
     }
+
+    /// Parses every line of the diff file, see [parse_entries]
+    fn read_entries(salvage: bool, cipher: Option<&Cipher>) -> DiffResult<SalvagedDiff> {
+        let mut contents = Vec::new();
+        Self::create_file()?.read_to_end(&mut contents)?;
+        parse_entries(&contents, salvage, cipher)
+    }
+
+    fn drain_entries(salvage: bool, cipher: Option<Cipher>) -> DiffResult<(Self, SalvagedDiff)> {
+        let entries = Self::read_entries(salvage, cipher.as_ref())?;
+        if entries.corrupt_lines.is_empty() {
+            fs::remove_file(DIFF_FILENAME)?;
+        } else {
+            fs::rename(DIFF_FILENAME, DIFF_BACKUP_FILENAME)?;
+        }
+
+        Ok((Self::new(cipher)?, entries))
+    }
+
+    fn encode_line(&self, db_ref: &DbPostRefSerialized) -> DiffResult<String> {
+        encode_line(db_ref, self.cipher.as_ref())
+    }
+}
+
+/// Encodes the reference the same way [DiffFile] appends it, e.g. for writing the diff of another database
+pub fn encode_line(db_ref: &DbPostRefSerialized, cipher: Option<&Cipher>) -> DiffResult<String> {
+    let serialized = db_ref.serialize()?;
+    let line = match cipher {
+        Some(cipher) => cipher.seal_line(serialized.as_bytes(), DIFF_AAD),
+        None => serialized,
+    };
+
+    Ok(format!("{}\n", line))
+}
+
+/// Reads references from the diff file at the path, e.g. of another database. Missing file is empty.
+/// # Errors
+/// [DiffFileError::Corrupt] if any line except the torn last one can't be parsed
+pub fn read_diff_file(
+    path: &Path,
+    cipher: Option<&Cipher>,
+) -> DiffResult<Vec<DbPostRefSerialized>> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(parse_entries(&contents, false, cipher)?.refs)
+}
+
+/// Parses every line of the diff, see [parse_lines]
+fn parse_entries(
+    contents: &[u8],
+    salvage: bool,
+    cipher: Option<&Cipher>,
+) -> DiffResult<SalvagedDiff> {
+    let parsed = parse_lines(contents, salvage, |line| decode_line(line, cipher))?;
+    Ok(SalvagedDiff {
+        refs: parsed.entries,
+        corrupt_lines: parsed.corrupt_lines,
+    })
+}
+
+/// Authenticated along with every encrypted line
+const DIFF_AAD: &[u8] = DIFF_FILENAME.as_bytes();
+
+/// Parses the line, decrypting it first if the diff is encrypted. `None` if the line is corrupt.
+fn decode_line(line: &[u8], cipher: Option<&Cipher>) -> Option<DbPostRefSerialized> {
+    let line = match cipher {
+        Some(cipher) => cipher.open_line(line, DIFF_AAD).ok()?,
+        None => line.to_vec(),
+    };
+
+    DbPostRefSerialized::deserialize(std::str::from_utf8(&line).ok()?).ok()
 }
 
 impl Diff for DiffFile {
     fn append(&mut self, hashes: &PostHashes, db_ref: &DbPostRef) -> DiffResult<()> {
-        let serialized_obj = DbPostRefSerialized::new(hashes, db_ref);
-        let serialized_string = serialized_obj.serialize()?;
-        let mut file = Self::create_file()?;
-        file.write_all(format!("{}\n", serialized_string).as_bytes())?;
+        let line = self.encode_line(&DbPostRefSerialized::new(hashes, db_ref))?;
+        self.file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn append_batch(&mut self, refs: &[DbPostRefSerialized]) -> DiffResult<()> {
+        if refs.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = String::new();
+        for db_ref in refs {
+            lines.push_str(&self.encode_line(db_ref)?);
+        }
+
+        self.file.write_all(lines.as_bytes())?;
 
         Ok(())
     }
 
+    fn sync(&mut self) -> DiffResult<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn size(&self) -> DiffResult<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
     fn drain() -> DiffResult<(Self, Vec<DbPostRefSerialized>)> {
-        let diff_file = Self::create_file()?;
-        let buf = BufReader::new(&diff_file);
+        let (diff, entries) = Self::drain_entries(false, None)?;
+        Ok((diff, entries.refs))
+    }
+
+    /// Corrupt lines are skipped. If there were any, the original file is kept as `diff-3.list.bak`.
+    fn drain_salvaging() -> DiffResult<(Self, SalvagedDiff)> {
+        Self::drain_entries(true, None)
+    }
+
+    fn position(&self) -> DiffResult<Option<DiffPosition>> {
+        Ok(Some(DiffPosition {
+            generation: self.generation,
+            offset: self.size()?,
+        }))
+    }
+
+    fn read_since(&self, position: &DiffPosition) -> DiffResult<Option<Vec<DbPostRefSerialized>>> {
+        if position.generation != self.generation {
+            return Ok(None);
+        }
+
+        // Appends ignore the cursor, so moving it doesn't affect them
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(position.offset))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(Some(
+            parse_entries(&contents, false, self.cipher.as_ref())?.refs,
+        ))
+    }
+}
+
+impl ReadOnlyDiffFile {
+    /// Same as [Diff::drain] for the diff encrypted by [DiffFile::drain_encrypted]
+    pub fn read_encrypted(cipher: &Cipher) -> DiffResult<(Self, Vec<DbPostRefSerialized>)> {
+        let (diff, entries) = Self::read(false, Some(cipher))?;
+        Ok((diff, entries.refs))
+    }
+
+    /// Opens the existing diff file without creating it, see [parse_entries]
+    fn read(salvage: bool, cipher: Option<&Cipher>) -> DiffResult<(Self, SalvagedDiff)> {
+        let mut file = match File::open(DIFF_FILENAME) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok((ReadOnlyDiffFile(None), SalvagedDiff::default()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let entries = parse_entries(&contents, salvage, cipher)?;
+        Ok((ReadOnlyDiffFile(Some(file)), entries))
+    }
+}
+
+impl Diff for ReadOnlyDiffFile {
+    fn append(&mut self, _hashes: &PostHashes, _db_ref: &DbPostRef) -> DiffResult<()> {
+        Err(DiffFileError::ReadOnly)
+    }
+
+    fn append_batch(&mut self, _refs: &[DbPostRefSerialized]) -> DiffResult<()> {
+        Err(DiffFileError::ReadOnly)
+    }
+
+    fn sync(&mut self) -> DiffResult<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> DiffResult<u64> {
+        match &self.0 {
+            Some(file) => Ok(file.metadata()?.len()),
+            None => Ok(0),
+        }
+    }
 
-        let result = buf
-            .lines()
-            .map(|l| l.expect("Invalid diff file!"))
-            .map(|line| DbPostRefSerialized::deserialize(&line).expect("Invalid diff file!"))
-            .collect();
-        mem::drop(diff_file);
-        fs::remove_file(DIFF_FILENAME)?;
+    /// Reads all references, the diff file is left as is.
+    /// Missing diff file is empty, it's not created.
+    fn drain() -> DiffResult<(Self, Vec<DbPostRefSerialized>)> {
+        let (diff, entries) = Self::read(false, None)?;
+        Ok((diff, entries.refs))
+    }
 
-        Ok((Self::new()?, result))
+    fn drain_salvaging() -> DiffResult<(Self, SalvagedDiff)> {
+        Self::read(true, None)
     }
 }
 
@@ -130,6 +406,158 @@ mod tests {
         }
     }
 
+    rusty_fork_test! {
+        #[test]
+        fn append_batch_should_write_all_refs() {
+            in_temp_dir!({
+                let (mut diff, _) = DiffFile::drain().unwrap();
+                diff.append_batch(&[ref_1(), ref_2()]).unwrap();
+
+                assert_eq!(
+                    read_to_string(DIFF_FILENAME).unwrap(),
+                    format!("{}\n", SERIALIZED_POSTS)
+                );
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn drain_should_ignore_torn_last_line() {
+            in_temp_dir!({
+                let mut file = create_file();
+                file.write_all(b"\n{\"h\":\"3\",\"r\":\"1\",\"o\"").unwrap();
+
+                let (_, coll) = DiffFile::drain().unwrap();
+
+                assert_eq!(coll, vec![ref_1(), ref_2()]);
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn drain_should_return_corrupt_line_and_keep_file() {
+            in_temp_dir!({
+                let mut file = DiffFile::create_file().unwrap();
+                file.write_all(b"{\"h\":\"3\"\n").unwrap();
+                file.write_all(SERIALIZED_POSTS.as_bytes()).unwrap();
+
+                let result = DiffFile::drain();
+
+                assert!(matches!(result, Err(DiffFileError::Corrupt { line_no: 1 })));
+                assert!(read_to_string(DIFF_FILENAME).unwrap().ends_with(SERIALIZED_POSTS));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn drain_salvaging_should_skip_corrupt_lines_and_keep_backup() {
+            in_temp_dir!({
+                let mut file = DiffFile::create_file().unwrap();
+                let first_line = SERIALIZED_POSTS.split('\n').next().unwrap();
+                file.write_all(format!("{}\n\u{0}\u{0}garbage\n", first_line).as_bytes()).unwrap();
+                file.write_all(SERIALIZED_POSTS.as_bytes()).unwrap();
+
+                let (_, salvaged) = DiffFile::drain_salvaging().unwrap();
+
+                assert_eq!(salvaged.refs, vec![ref_1(), ref_1(), ref_2()]);
+                assert_eq!(salvaged.corrupt_lines, vec![2]);
+                assert_eq!(read_to_string(DIFF_FILENAME).unwrap(), "".to_string());
+                assert!(Path::new(DIFF_BACKUP_FILENAME).exists());
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn read_only_drain_should_keep_file_and_reject_appends() {
+            in_temp_dir!({
+                create_file();
+
+                let (mut diff, coll) = ReadOnlyDiffFile::drain().unwrap();
+
+                assert_eq!(coll, vec![ref_1(), ref_2()]);
+                assert_eq!(read_to_string(DIFF_FILENAME).unwrap(), SERIALIZED_POSTS);
+                assert_eq!(diff.size().unwrap(), SERIALIZED_POSTS.len() as u64);
+                assert!(matches!(
+                    diff.append_batch(&[ref_1()]),
+                    Err(DiffFileError::ReadOnly)
+                ));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn read_only_drain_should_read_missing_file_as_empty_without_creating_it() {
+            in_temp_dir!({
+                let (diff, coll) = ReadOnlyDiffFile::drain().unwrap();
+
+                assert!(coll.is_empty());
+                assert_eq!(diff.size().unwrap(), 0);
+                assert!(!Path::new(DIFF_FILENAME).exists());
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn read_since_should_return_refs_appended_after_position() {
+            in_temp_dir!({
+                let (mut diff, _) = DiffFile::drain().unwrap();
+                diff.append_batch(&[ref_1()]).unwrap();
+                let position = diff.position().unwrap().unwrap();
+                diff.append_batch(&[ref_2()]).unwrap();
+
+                assert_eq!(diff.read_since(&position).unwrap(), Some(vec![ref_2()]));
+                diff.append_batch(&[ref_1()]).unwrap();
+                assert_eq!(
+                    diff.read_since(&position).unwrap(),
+                    Some(vec![ref_2(), ref_1()])
+                );
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn read_since_should_reject_position_of_another_diff() {
+            in_temp_dir!({
+                let (mut diff, _) = DiffFile::drain().unwrap();
+                diff.append_batch(&[ref_1()]).unwrap();
+                let position = diff.position().unwrap().unwrap();
+                drop(diff);
+
+                let (diff, _) = DiffFile::drain().unwrap();
+
+                assert_eq!(diff.read_since(&position).unwrap(), None);
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn encrypted_diff_should_be_read_with_the_same_key_only() {
+            in_temp_dir!({
+                let cipher = Cipher::from_key([1; 32]);
+                let (mut diff, _) = DiffFile::drain_encrypted(cipher.clone()).unwrap();
+                diff.append_batch(&[ref_1(), ref_2()]).unwrap();
+
+                let contents = read_to_string(DIFF_FILENAME).unwrap();
+                assert!(!contents.contains("0.db3"));
+                assert!(matches!(
+                    DiffFile::drain_encrypted(Cipher::from_key([2; 32])),
+                    Err(DiffFileError::Corrupt { line_no: 1 })
+                ));
+
+                let (_, coll) = ReadOnlyDiffFile::read_encrypted(&cipher).unwrap();
+                assert_eq!(coll, vec![ref_1(), ref_2()]);
+            });
+        }
+    }
+
     fn create_file() -> File {
         let mut file = DiffFile::create_file().unwrap();
         file.write_all(SERIALIZED_POSTS.as_bytes()).unwrap();
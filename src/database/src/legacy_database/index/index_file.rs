@@ -0,0 +1,519 @@
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use thiserror::Error;
+
+use crate::legacy_database::{
+    chunk::{ChunkIndex, CHUNK_EXT},
+    config::IndexFormat,
+    crypto::{Cipher, CryptoResult},
+};
+
+use super::{
+    db_post_ref::{ChunkSettings, DbPostRef, DbPostRefHash, HASH_BYTES},
+    serialized::{PostHashes, INDEX_FILENAME},
+};
+
+/// First bytes of the binary index. Zero byte can't start a JSON document, so the formats are told apart by it.
+const BINARY_MAGIC: &[u8; 4] = b"\0RBI";
+
+const BINARY_VERSION: u8 = 1;
+
+/// Entry flags of the binary index
+const FLAG_DELETED: u8 = 1;
+const FLAG_HAS_CHUNK: u8 = 1 << 1;
+const FLAG_TEXT_HASH: u8 = 1 << 2;
+const FLAG_TEXT_PARENT: u8 = 1 << 3;
+
+#[derive(Debug, Error)]
+pub enum IndexFileError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+
+    #[error("Serde error")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("Binary index is malformed")]
+    Malformed,
+
+    #[error("Binary index version {0} is not supported")]
+    UnsupportedVersion(u8),
+
+    #[error("Hash is too long to be written into the binary index")]
+    HashTooLong,
+}
+
+pub type IndexFileResult<T> = Result<T, IndexFileError>;
+
+/// Receives references as they are read by [read_index]
+pub trait IndexVisitor {
+    /// Called before the references with their number, if the format records it
+    fn reserve(&mut self, _refs: usize) {}
+
+    fn visit(&mut self, hashes: PostHashes, db_ref: DbPostRef);
+}
+
+impl<F: FnMut(PostHashes, DbPostRef)> IndexVisitor for F {
+    fn visit(&mut self, hashes: PostHashes, db_ref: DbPostRef) {
+        self(hashes, db_ref)
+    }
+}
+
+/// Reads the index reference by reference, so the whole index is never held in memory.
+/// The format is detected by the first bytes, see [IndexFormat].
+pub fn read_index(reader: impl Read, visitor: &mut impl IndexVisitor) -> IndexFileResult<()> {
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.first() == Some(&BINARY_MAGIC[0]) {
+        read_binary(&mut reader, visitor)
+    } else {
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        IndexSeed(visitor).deserialize(&mut deserializer)?;
+        deserializer.end()?;
+        Ok(())
+    }
+}
+
+/// Writes the references in the format, `count` is the number of references the iterator yields
+pub fn write_index<'a>(
+    mut writer: impl Write,
+    format: IndexFormat,
+    count: usize,
+    refs: impl Iterator<Item = (&'a DbPostRefHash, &'a DbPostRef)>,
+) -> IndexFileResult<()> {
+    match format {
+        IndexFormat::Json => {
+            // Same document as the serialized `IndexCollection`
+            writer.write_all(b"{\"indexes\":[")?;
+            for (i, (hash, db_ref)) in refs.enumerate() {
+                if i > 0 {
+                    writer.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut writer, &IndexEntry::new(hash, db_ref))?;
+            }
+            writer.write_all(b"]}")?;
+        }
+        IndexFormat::Binary => {
+            writer.write_all(BINARY_MAGIC)?;
+            writer.write_all(&[BINARY_VERSION])?;
+            writer.write_all(&(count as u64).to_le_bytes())?;
+            for (hash, db_ref) in refs {
+                write_binary_entry(&mut writer, hash, db_ref)?;
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decrypts the index sealed by [seal_index]
+pub fn open_index(sealed: &[u8], cipher: &Cipher) -> CryptoResult<Vec<u8>> {
+    cipher.open(sealed, INDEX_FILENAME.as_bytes())
+}
+
+/// Seals the index written by [write_index] to be written into `index-3.json`
+pub fn seal_index(plain: &[u8], cipher: &Cipher) -> Vec<u8> {
+    cipher.seal(plain, INDEX_FILENAME.as_bytes())
+}
+
+/// Reference in the JSON index, same as [DbPostRefSerialized](super::serialized::DbPostRefSerialized),
+/// but its hashes and chunk name are parsed without allocations
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    #[serde(rename = "h")]
+    hash: DbPostRefHash,
+
+    #[serde(rename = "r")]
+    reply_to: DbPostRefHash,
+
+    #[serde(rename = "o")]
+    offset: u64,
+
+    #[serde(rename = "l")]
+    length: u64,
+
+    #[serde(rename = "d")]
+    deleted: bool,
+
+    #[serde(rename = "f")]
+    chunk: Option<ChunkName>,
+}
+
+impl IndexEntry {
+    fn new(hash: &DbPostRefHash, db_ref: &DbPostRef) -> Self {
+        IndexEntry {
+            hash: hash.clone(),
+            reply_to: db_ref.parent_hash.clone(),
+            offset: db_ref.chunk_settings.as_ref().map_or(0, |s| s.offset),
+            length: db_ref.length,
+            deleted: db_ref.deleted,
+            chunk: db_ref
+                .chunk_settings
+                .as_ref()
+                .map(|s| ChunkName(s.chunk_index)),
+        }
+    }
+
+    fn split(self) -> (PostHashes, DbPostRef) {
+        let offset = self.offset;
+        let db_ref = DbPostRef {
+            chunk_settings: self.chunk.map(|ChunkName(chunk_index)| ChunkSettings {
+                chunk_index,
+                offset,
+            }),
+            length: self.length,
+            deleted: self.deleted,
+            parent_hash: self.reply_to.clone(),
+        };
+        let hashes = PostHashes {
+            parent: self.reply_to,
+            hash: self.hash,
+        };
+
+        (hashes, db_ref)
+    }
+}
+
+/// Chunk file name (`0.db3`) read as the chunk index
+struct ChunkName(ChunkIndex);
+
+impl Serialize for ChunkName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}{}", self.0, CHUNK_EXT))
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ChunkNameVisitor;
+
+        impl Visitor<'_> for ChunkNameVisitor {
+            type Value = ChunkName;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("chunk file name")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Self::Value, E> {
+                name.strip_suffix(CHUNK_EXT)
+                    .and_then(|index| index.parse().ok())
+                    .map(ChunkName)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(name), &self))
+            }
+        }
+
+        deserializer.deserialize_str(ChunkNameVisitor)
+    }
+}
+
+/// Reads the `{"indexes": [...]}` document handing every reference to the visitor
+struct IndexSeed<'a, V>(&'a mut V);
+
+impl<'de, V: IndexVisitor> DeserializeSeed<'de> for IndexSeed<'_, V> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, V: IndexVisitor> Visitor<'de> for IndexSeed<'_, V> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("index collection")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "indexes" {
+                map.next_value_seed(EntriesSeed(&mut *self.0))?;
+                found = true;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+
+        if !found {
+            return Err(de::Error::missing_field("indexes"));
+        }
+        Ok(())
+    }
+}
+
+struct EntriesSeed<'a, V>(&'a mut V);
+
+impl<'de, V: IndexVisitor> DeserializeSeed<'de> for EntriesSeed<'_, V> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, V: IndexVisitor> Visitor<'de> for EntriesSeed<'_, V> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("list of post references")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        if let Some(refs) = seq.size_hint() {
+            self.0.reserve(refs);
+        }
+        while let Some(entry) = seq.next_element::<IndexEntry>()? {
+            let (hashes, db_ref) = entry.split();
+            self.0.visit(hashes, db_ref);
+        }
+
+        Ok(())
+    }
+}
+
+/// Binary index is the magic, the version byte, the number of references and the references:
+///
+/// | Field                  | Size                                                          |
+/// |------------------------|---------------------------------------------------------------|
+/// | Flags                  | 1                                                             |
+/// | Hash                   | 16, or 2 bytes of the length and UTF-8 text if it's not hex   |
+/// | Parent hash            | Same as hash                                                  |
+/// | Length                 | 8                                                             |
+/// | Chunk index and offset | 8 + 8, only if the reference has chunk settings               |
+///
+/// All numbers are little endian.
+fn read_binary(reader: &mut impl Read, visitor: &mut impl IndexVisitor) -> IndexFileResult<()> {
+    let mut header = [0; BINARY_MAGIC.len() + 1];
+    read_exact(reader, &mut header)?;
+    if &header[..BINARY_MAGIC.len()] != BINARY_MAGIC {
+        return Err(IndexFileError::Malformed);
+    }
+    if header[BINARY_MAGIC.len()] != BINARY_VERSION {
+        return Err(IndexFileError::UnsupportedVersion(
+            header[BINARY_MAGIC.len()],
+        ));
+    }
+
+    let count = read_u64(reader)?;
+    // Count of a corrupted index must not make it reserve all the memory
+    visitor.reserve(count.min(1 << 24) as usize);
+    for _ in 0..count {
+        let mut flags = [0];
+        read_exact(reader, &mut flags)?;
+        let flags = flags[0];
+
+        let hash = read_hash(reader, flags & FLAG_TEXT_HASH != 0)?;
+        let parent_hash = read_hash(reader, flags & FLAG_TEXT_PARENT != 0)?;
+        let length = read_u64(reader)?;
+        let chunk_settings = if flags & FLAG_HAS_CHUNK != 0 {
+            Some(ChunkSettings {
+                chunk_index: read_u64(reader)?,
+                offset: read_u64(reader)?,
+            })
+        } else {
+            None
+        };
+
+        let hashes = PostHashes {
+            parent: parent_hash.clone(),
+            hash,
+        };
+        let db_ref = DbPostRef {
+            chunk_settings,
+            length,
+            deleted: flags & FLAG_DELETED != 0,
+            parent_hash,
+        };
+        visitor.visit(hashes, db_ref);
+    }
+
+    let mut rest = [0];
+    match reader.read(&mut rest)? {
+        0 => Ok(()),
+        _ => Err(IndexFileError::Malformed),
+    }
+}
+
+fn write_binary_entry(
+    writer: &mut impl Write,
+    hash: &DbPostRefHash,
+    db_ref: &DbPostRef,
+) -> IndexFileResult<()> {
+    let mut flags = 0;
+    if db_ref.deleted {
+        flags |= FLAG_DELETED;
+    }
+    if db_ref.chunk_settings.is_some() {
+        flags |= FLAG_HAS_CHUNK;
+    }
+    if hash.as_binary().is_none() {
+        flags |= FLAG_TEXT_HASH;
+    }
+    if db_ref.parent_hash.as_binary().is_none() {
+        flags |= FLAG_TEXT_PARENT;
+    }
+
+    writer.write_all(&[flags])?;
+    write_hash(writer, hash)?;
+    write_hash(writer, &db_ref.parent_hash)?;
+    writer.write_all(&db_ref.length.to_le_bytes())?;
+    if let Some(settings) = &db_ref.chunk_settings {
+        writer.write_all(&settings.chunk_index.to_le_bytes())?;
+        writer.write_all(&settings.offset.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn write_hash(writer: &mut impl Write, hash: &DbPostRefHash) -> IndexFileResult<()> {
+    match hash.as_binary() {
+        Some(bytes) => writer.write_all(bytes)?,
+        None => {
+            let text = hash.to_string();
+            let length = u16::try_from(text.len()).map_err(|_| IndexFileError::HashTooLong)?;
+            writer.write_all(&length.to_le_bytes())?;
+            writer.write_all(text.as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_hash(reader: &mut impl Read, text: bool) -> IndexFileResult<DbPostRefHash> {
+    if !text {
+        let mut bytes = [0; HASH_BYTES];
+        read_exact(reader, &mut bytes)?;
+        return Ok(DbPostRefHash::from_binary(bytes));
+    }
+
+    let mut length = [0; 2];
+    read_exact(reader, &mut length)?;
+    let mut bytes = vec![0; u16::from_le_bytes(length) as usize];
+    read_exact(reader, &mut bytes)?;
+    let text = String::from_utf8(bytes).map_err(|_| IndexFileError::Malformed)?;
+
+    Ok(DbPostRefHash::new(&text))
+}
+
+fn read_u64(reader: &mut impl Read) -> IndexFileResult<u64> {
+    let mut bytes = [0; 8];
+    read_exact(reader, &mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Same as [Read::read_exact], but a truncated index is reported as malformed
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> IndexFileResult<()> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => IndexFileError::Malformed,
+        _ => err.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::legacy_database::index::serialized::{DbPostRefSerialized, IndexCollection};
+
+    fn refs() -> Vec<(DbPostRefHash, DbPostRef)> {
+        let thread = DbPostRefHash::new("00ff10a9b8c7d6e5f4030201abcdef99");
+        vec![
+            (
+                thread.clone(),
+                DbPostRef {
+                    chunk_settings: Some(ChunkSettings {
+                        chunk_index: 3,
+                        offset: 120,
+                    }),
+                    length: 40,
+                    deleted: false,
+                    parent_hash: DbPostRefHash::new("bdd4b5fc1b3a933367bc6830fef72a35"),
+                },
+            ),
+            (
+                DbPostRefHash::new("not hex"),
+                DbPostRef {
+                    chunk_settings: None,
+                    length: 0,
+                    deleted: true,
+                    parent_hash: thread,
+                },
+            ),
+        ]
+    }
+
+    fn write(format: IndexFormat, refs: &[(DbPostRefHash, DbPostRef)]) -> Vec<u8> {
+        let mut written = Vec::new();
+        let iter = refs.iter().map(|(hash, db_ref)| (hash, db_ref));
+        write_index(&mut written, format, refs.len(), iter).unwrap();
+        written
+    }
+
+    fn read(index: &[u8]) -> IndexFileResult<Vec<(DbPostRefHash, DbPostRef)>> {
+        let mut read = Vec::new();
+        read_index(index, &mut |hashes: PostHashes, db_ref| {
+            read.push((hashes.hash, db_ref))
+        })?;
+        Ok(read)
+    }
+
+    #[test]
+    fn written_index_should_be_read_back_in_both_formats() {
+        for format in [IndexFormat::Json, IndexFormat::Binary] {
+            let written = write(format, &refs());
+
+            assert_eq!(read(&written).unwrap(), refs(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn json_index_should_be_same_as_serialized_collection() {
+        let collection = IndexCollection {
+            indexes: refs()
+                .iter()
+                .map(|(hash, db_ref)| {
+                    let hashes = PostHashes {
+                        parent: db_ref.parent_hash.clone(),
+                        hash: hash.clone(),
+                    };
+                    DbPostRefSerialized::new(&hashes, db_ref)
+                })
+                .collect(),
+        };
+
+        let written = write(IndexFormat::Json, &refs());
+
+        assert_eq!(written, serde_json::to_vec(&collection).unwrap());
+    }
+
+    #[test]
+    fn binary_index_should_be_smaller_than_json() {
+        let json = write(IndexFormat::Json, &refs());
+        let binary = write(IndexFormat::Binary, &refs());
+
+        assert!(binary.len() * 2 < json.len());
+    }
+
+    #[test]
+    fn truncated_binary_index_should_be_malformed() {
+        let written = write(IndexFormat::Binary, &refs());
+
+        let read = read(&written[..written.len() - 1]);
+
+        assert!(matches!(read, Err(IndexFileError::Malformed)));
+    }
+
+    #[test]
+    fn json_index_with_bad_chunk_name_should_fail() {
+        let index = br#"{"indexes":[{"h":"1","r":"0","o":0,"l":1,"d":false,"f":"1.txt"}]}"#;
+
+        assert!(matches!(read(index), Err(IndexFileError::SerdeError(_))));
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    marker::PhantomData,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::legacy_database::crypto::Cipher;
+
+#[derive(Debug, Error)]
+pub enum JsonLinesError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+
+    #[error("Error (de)serializing line")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("Line {line_no} is corrupt")]
+    Corrupt { line_no: usize },
+}
+
+pub type JsonLinesResult<T> = Result<T, JsonLinesError>;
+
+/// Lines read by [parse_lines]
+#[derive(Debug)]
+pub struct ParsedLines<T> {
+    pub entries: Vec<T>,
+
+    /// Numbers (starting from 1) of the lines which could not be parsed and were skipped
+    pub corrupt_lines: Vec<usize>,
+}
+
+/// Parses every line of the append-only file with `decode`, which returns `None` for a corrupt line.
+/// A torn last line, left by an interrupted write, is ignored.
+/// If `salvage` is set, corrupt lines are skipped, otherwise the first corrupt line is returned as an error.
+pub fn parse_lines<T>(
+    contents: &[u8],
+    salvage: bool,
+    decode: impl Fn(&[u8]) -> Option<T>,
+) -> JsonLinesResult<ParsedLines<T>> {
+    let mut result = ParsedLines {
+        entries: Vec::new(),
+        corrupt_lines: Vec::new(),
+    };
+    let mut lines = contents.split(|byte| *byte == b'\n').enumerate().peekable();
+    while let Some((index, line)) = lines.next() {
+        if line.is_empty() {
+            continue;
+        }
+
+        match decode(line) {
+            Some(entry) => result.entries.push(entry),
+            // Line without trailing newline is the one which was being written during a crash
+            None if lines.peek().is_none() => {}
+            None if salvage => result.corrupt_lines.push(index + 1),
+            None => return Err(JsonLinesError::Corrupt { line_no: index + 1 }),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Append-only list of JSON lines next to the `index-3.json`, every line sealed by the cipher if it's set.
+/// Lines which failed to be written are kept and written along with the next ones.
+/// List created with [`Default`] is kept in memory only.
+#[derive(Debug)]
+pub struct JsonLinesFile<T> {
+    filename: &'static str,
+    file: Option<File>,
+    cipher: Option<Cipher>,
+    unwritten: Vec<Vec<u8>>,
+    entry: PhantomData<T>,
+}
+
+impl<T> Default for JsonLinesFile<T> {
+    fn default() -> Self {
+        Self {
+            filename: "",
+            file: None,
+            cipher: None,
+            unwritten: Vec::new(),
+            entry: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> JsonLinesFile<T> {
+    /// Reads every line of the file, creating it if it doesn't exist.
+    /// Encrypted lines are authenticated along with the file name.
+    /// # Errors
+    /// [JsonLinesError::Corrupt] if any line except the torn last one can't be parsed
+    pub fn open(filename: &'static str, cipher: Option<Cipher>) -> JsonLinesResult<(Self, Vec<T>)> {
+        let mut file = open_file(filename)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let aad = filename.as_bytes();
+        let parsed = parse_lines(&contents, false, |line| match &cipher {
+            Some(cipher) => serde_json::from_slice(&cipher.open_line(line, aad).ok()?).ok(),
+            None => serde_json::from_slice(line).ok(),
+        })?;
+
+        let lines = Self {
+            filename,
+            file: Some(file),
+            cipher,
+            unwritten: Vec::new(),
+            entry: PhantomData,
+        };
+        Ok((lines, parsed.entries))
+    }
+
+    /// Appends the entries with a single write.
+    /// If the write fails, they're written by the next append or flush.
+    pub fn append(&mut self, entries: impl IntoIterator<Item = T>) -> JsonLinesResult<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+
+        for entry in entries {
+            let line = self.encode_line(&entry)?;
+            self.unwritten.push(line);
+        }
+
+        self.flush()
+    }
+
+    /// Writes lines which failed to be written before
+    pub fn flush(&mut self) -> JsonLinesResult<()> {
+        let file = match &mut self.file {
+            Some(file) if !self.unwritten.is_empty() => file,
+            _ => return Ok(()),
+        };
+
+        append_lines(file, &self.unwritten.concat())?;
+        self.unwritten.clear();
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> JsonLinesResult<()> {
+        self.flush()?;
+        if let Some(file) = &self.file {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the file so it contains the entries only, dropping the unwritten lines.
+    /// The file is replaced atomically.
+    pub fn compact(&mut self, entries: impl IntoIterator<Item = T>) -> JsonLinesResult<()> {
+        if self.file.is_none() {
+            return Ok(());
+        }
+
+        let tmp_filename = format!("{}.tmp", self.filename);
+        let mut writer = BufWriter::new(File::create(&tmp_filename)?);
+        for entry in entries {
+            writer.write_all(&self.encode_line(&entry)?)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(&tmp_filename, self.filename)?;
+
+        self.file = Some(open_file(self.filename)?);
+        self.unwritten.clear();
+        Ok(())
+    }
+
+    /// Serializes the line, sealing it if the list is encrypted
+    fn encode_line(&self, entry: &T) -> JsonLinesResult<Vec<u8>> {
+        let json = serde_json::to_vec(entry)?;
+        let mut line = match &self.cipher {
+            Some(cipher) => cipher
+                .seal_line(&json, self.filename.as_bytes())
+                .into_bytes(),
+            None => json,
+        };
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+fn open_file(filename: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .read(true)
+        .open(filename)
+}
+
+/// Appends the lines with a single write. If only a part of them is written, it's truncated,
+/// so the lines written later don't follow a torn line.
+fn append_lines(file: &mut File, lines: &[u8]) -> io::Result<()> {
+    let length = file.metadata()?.len();
+    file.write_all(lines).inspect_err(|_| {
+        let _ = file.set_len(length);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines_should_ignore_torn_last_line() {
+        let parsed = parse_lines(b"1\n2\n{", false, |line| {
+            serde_json::from_slice::<u32>(line).ok()
+        })
+        .unwrap();
+
+        assert_eq!(parsed.entries, vec![1, 2]);
+    }
+
+    #[test]
+    fn parse_lines_should_skip_corrupt_lines_when_salvaging_only() {
+        let decode = |line: &[u8]| serde_json::from_slice::<u32>(line).ok();
+
+        assert!(matches!(
+            parse_lines(b"1\nx\n2\n", false, decode),
+            Err(JsonLinesError::Corrupt { line_no: 2 })
+        ));
+        let parsed = parse_lines(b"1\nx\n2\n", true, decode).unwrap();
+        assert_eq!(parsed.entries, vec![1, 2]);
+        assert_eq!(parsed.corrupt_lines, vec![2]);
+    }
+}
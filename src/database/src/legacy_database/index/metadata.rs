@@ -0,0 +1,241 @@
+use std::{collections::BTreeSet, collections::HashMap, io};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::json_lines::{JsonLinesError, JsonLinesFile};
+use crate::{legacy_database::crypto::Cipher, post::PostMetadata};
+
+/// Sidecar of `index-3.json`, so the legacy index format stays untouched
+pub const METADATA_FILENAME: &str = "meta-3.list";
+
+#[derive(Debug, Error)]
+pub enum MetadataStoreError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+
+    #[error("Error (de)serializing metadata")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("Metadata line {line_no} is corrupt")]
+    Corrupt { line_no: usize },
+}
+
+impl From<JsonLinesError> for MetadataStoreError {
+    fn from(err: JsonLinesError) -> Self {
+        match err {
+            JsonLinesError::IoError(err) => Self::IoError(err),
+            JsonLinesError::SerdeError(err) => Self::SerdeError(err),
+            JsonLinesError::Corrupt { line_no } => Self::Corrupt { line_no },
+        }
+    }
+}
+
+pub type MetadataStoreResult<T> = Result<T, MetadataStoreError>;
+
+/// Line of the metadata file. Later lines override earlier lines of the same post.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct MetadataSerialized {
+    /// Post hash
+    #[serde(rename = "h")]
+    hash: String,
+
+    /// Unix time in seconds when the post was received
+    #[serde(rename = "t")]
+    received_at: u64,
+
+    /// Source container
+    #[serde(rename = "s", default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+
+    /// Local flags
+    #[serde(rename = "f", default, skip_serializing_if = "BTreeSet::is_empty")]
+    flags: BTreeSet<String>,
+}
+
+/// Post metadata, persisted as an append-only list of JSON lines in `meta-3.list`.
+/// Store created with [`Default`] is kept in memory only.
+#[derive(Debug, Default)]
+pub struct MetadataStore {
+    entries: HashMap<String, PostMetadata>,
+    lines: JsonLinesFile<MetadataSerialized>,
+}
+
+impl MetadataStore {
+    /// Reads `meta-3.list`, creating it if it doesn't exist.
+    /// A torn last line, left by an interrupted write, is ignored.
+    /// # Errors
+    /// [MetadataStoreError::Corrupt] if any other line can't be parsed
+    pub fn open() -> MetadataStoreResult<Self> {
+        Self::open_with(None)
+    }
+
+    /// Same as [MetadataStore::open], but every line is sealed by the cipher and stored base64 encoded.
+    /// Line which can't be decrypted is corrupt.
+    pub fn open_encrypted(cipher: Cipher) -> MetadataStoreResult<Self> {
+        Self::open_with(Some(cipher))
+    }
+
+    fn open_with(cipher: Option<Cipher>) -> MetadataStoreResult<Self> {
+        let (lines, serialized) = JsonLinesFile::open(METADATA_FILENAME, cipher)?;
+        let entries = serialized
+            .into_iter()
+            .map(MetadataSerialized::split)
+            .collect();
+        Ok(Self { entries, lines })
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&PostMetadata> {
+        self.entries.get(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Stores metadata of several posts with a single write.
+    /// If the write fails, metadata is still stored in memory and it's written by the next change or flush.
+    pub fn append(&mut self, entries: Vec<(String, PostMetadata)>) -> MetadataStoreResult<()> {
+        let serialized: Vec<MetadataSerialized> = entries
+            .iter()
+            .map(|(hash, metadata)| MetadataSerialized::new(hash, metadata))
+            .collect();
+        self.entries.extend(entries);
+        Ok(self.lines.append(serialized)?)
+    }
+
+    /// Writes metadata which failed to be written before
+    pub fn flush(&mut self) -> MetadataStoreResult<()> {
+        Ok(self.lines.flush()?)
+    }
+
+    /// Flushes appended metadata to the disk
+    pub fn sync(&mut self) -> MetadataStoreResult<()> {
+        Ok(self.lines.sync()?)
+    }
+
+    /// Rewrites `meta-3.list` so it contains a single line per post. The file is replaced atomically.
+    pub fn compact(&mut self) -> MetadataStoreResult<()> {
+        let serialized = self
+            .entries
+            .iter()
+            .map(|(hash, metadata)| MetadataSerialized::new(hash, metadata));
+        Ok(self.lines.compact(serialized)?)
+    }
+}
+
+impl MetadataSerialized {
+    fn new(hash: &str, metadata: &PostMetadata) -> Self {
+        Self {
+            hash: hash.to_string(),
+            received_at: metadata.received_at,
+            source: metadata.source.clone(),
+            flags: metadata.flags.clone(),
+        }
+    }
+
+    fn split(self) -> (String, PostMetadata) {
+        let metadata = PostMetadata {
+            received_at: self.received_at,
+            source: self.source,
+            flags: self.flags,
+        };
+        (self.hash, metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_temp_dir;
+    use rusty_fork::rusty_fork_test;
+    use std::fs;
+
+    rusty_fork_test! {
+        #[test]
+        fn open_should_restore_latest_metadata() {
+            in_temp_dir!({
+                let mut store = MetadataStore::open().unwrap();
+                store.append(vec![("1".to_string(), metadata(1, None))]).unwrap();
+                store
+                    .append(vec![("1".to_string(), metadata(1, Some("a.png")))])
+                    .unwrap();
+
+                let store = MetadataStore::open().unwrap();
+
+                assert_eq!(store.get("1"), Some(&metadata(1, Some("a.png"))));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn open_should_ignore_torn_last_line_and_fail_on_corrupt_ones() {
+            in_temp_dir!({
+                fs::write(METADATA_FILENAME, "{\"h\":\"1\",\"t\":5}\n{\"h\":\"2\",").unwrap();
+                let store = MetadataStore::open().unwrap();
+                assert_eq!(store.get("1"), Some(&metadata(5, None)));
+                assert!(!store.contains("2"));
+
+                fs::write(METADATA_FILENAME, "garbage\n{\"h\":\"1\",\"t\":5}\n").unwrap();
+                assert!(matches!(
+                    MetadataStore::open(),
+                    Err(MetadataStoreError::Corrupt { line_no: 1 })
+                ));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn compact_should_keep_single_line_per_post() {
+            in_temp_dir!({
+                let mut store = MetadataStore::open().unwrap();
+                store.append(vec![("1".to_string(), metadata(1, None))]).unwrap();
+                store.append(vec![("1".to_string(), metadata(2, None))]).unwrap();
+
+                store.compact().unwrap();
+                store.append(vec![("2".to_string(), metadata(3, None))]).unwrap();
+
+                let contents = fs::read_to_string(METADATA_FILENAME).unwrap();
+                assert_eq!(contents.lines().count(), 2);
+                let store = MetadataStore::open().unwrap();
+                assert_eq!(store.get("1"), Some(&metadata(2, None)));
+                assert_eq!(store.get("2"), Some(&metadata(3, None)));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn encrypted_store_should_be_read_with_the_same_key_only() {
+            in_temp_dir!({
+                let cipher = Cipher::from_key([1; 32]);
+                let mut store = MetadataStore::open_encrypted(cipher.clone()).unwrap();
+                store
+                    .append(vec![
+                        ("1".to_string(), metadata(1, Some("secret.png"))),
+                        ("2".to_string(), metadata(2, None)),
+                    ])
+                    .unwrap();
+
+                let contents = fs::read_to_string(METADATA_FILENAME).unwrap();
+                assert!(!contents.contains("secret"));
+                assert!(matches!(
+                    MetadataStore::open_encrypted(Cipher::from_key([2; 32])),
+                    Err(MetadataStoreError::Corrupt { line_no: 1 })
+                ));
+                let store = MetadataStore::open_encrypted(cipher).unwrap();
+                assert_eq!(store.get("1"), Some(&metadata(1, Some("secret.png"))));
+            });
+        }
+    }
+
+    fn metadata(received_at: u64, source: Option<&str>) -> PostMetadata {
+        PostMetadata {
+            received_at,
+            source: source.map(str::to_string),
+            flags: BTreeSet::new(),
+        }
+    }
+}
@@ -1,20 +1,29 @@
+pub mod ban_list;
 pub mod db_post_ref;
 pub mod diff;
+pub mod index_file;
+pub mod json_lines;
+pub mod metadata;
+pub mod search;
 pub mod serialized;
+pub mod sync;
+pub mod threads;
+pub mod trash;
 use std::{
     collections::{HashMap, HashSet},
-    mem,
-    rc::Rc,
-    usize,
+    io::{Read, Write},
 };
 
+use super::config::{FitStrategy, IndexFormat};
 use crate::post::{Post, PostMessage};
 use thiserror::Error;
 
 use self::{
-    db_post_ref::{DbPostRef, DbPostRefHash},
-    diff::{Diff, DiffFileError},
+    db_post_ref::{ChunkSettings, DbPostRef, DbPostRefHash},
+    diff::{Diff, DiffFileError, DiffPosition, DiffResult},
+    index_file::{IndexFileError, IndexVisitor},
     serialized::{DbPostRefSerialized, IndexCollection, PostHashes},
+    sync::{SyncSummary, DEFAULT_FALSE_POSITIVE_RATE},
 };
 
 pub type DbRefHashMap = HashMap<DbPostRefHash, DbPostRef>;
@@ -22,12 +31,16 @@ pub type RepliesHashMap = HashMap<DbPostRefHash, Vec<DbPostRefHash>>;
 pub type OrderedHashes = Vec<DbPostRefHash>;
 pub type DeletedPosts = HashSet<DbPostRefHash>;
 pub type FreeSpaceHashes = HashSet<DbPostRefHash>;
+pub type ExtentReferrers = HashMap<ChunkSettings, usize>;
 
 #[derive(Debug, Error)]
 pub enum DbRefCollectionError {
     #[error("Error appending to diff")]
     DiffError(#[from] DiffFileError),
 
+    #[error("Error reading index")]
+    IndexFileError(#[from] IndexFileError),
+
     #[error("Can't insert duplicate non-deleted posts")]
     DuplicatePostError,
 
@@ -36,6 +49,12 @@ pub enum DbRefCollectionError {
 
     #[error("Trying to delete already deleted ref")]
     RefAlreadyDeleted,
+
+    #[error("Transaction was not started")]
+    NoActiveTransaction,
+
+    #[error("Transaction was already started")]
+    TransactionAlreadyStarted,
 }
 
 pub type DbRefCollectionResult<T> = Result<T, DbRefCollectionError>;
@@ -57,130 +76,571 @@ pub struct DbRefCollection<TDiff: Diff> {
     ///Post hashes which are marked as deleted and their space is not used now
     free: FreeSpaceHashes,
 
+    /// Hashes of the posts which are replied to, but were not received yet
+    missing_parents: HashSet<DbPostRefHash>,
+
+    /// Number of live posts referencing every chunk extent.
+    /// Posts with identical messages may share one extent, which is freed only when the last of them is deleted.
+    referrers: ExtentReferrers,
+
+    /// Changes which are not written into the diff yet, see [DbRefCollection::begin_transaction]
+    transaction: Option<Transaction>,
+
+    /// How the space of deleted posts is picked for new posts
+    fit_strategy: FitStrategy,
+
     diff: TDiff,
 }
 
+/// Refs changed during the transaction, in order of their first change, with their state before the change.
+/// `None` state means that the ref did not exist before the transaction.
+#[derive(Default)]
+struct Transaction {
+    changes: Vec<(PostHashes, Option<DbPostRef>)>,
+    changed_hashes: HashSet<DbPostRefHash>,
+}
+
 impl<TDiff: Diff> DbRefCollection<TDiff> {
     /// Constructs reference collection from raw deserialized database references.
     pub fn new(index_collection: IndexCollection) -> DbRefCollectionResult<Self> {
         let (diff, diff_collection) = TDiff::drain()?;
-        let mut refr = DbRefCollection {
+        Ok(Self::from_parts(diff, index_collection, diff_collection))
+    }
+
+    /// Same as [DbRefCollection::new], but skips corrupt diff lines instead of failing.
+    /// # Returns
+    /// Collection and numbers of the skipped diff lines
+    pub fn new_salvaging(
+        index_collection: IndexCollection,
+    ) -> DbRefCollectionResult<(Self, Vec<usize>)> {
+        let (diff, salvaged) = TDiff::drain_salvaging()?;
+        let collection = Self::from_parts(diff, index_collection, salvaged.refs);
+        Ok((collection, salvaged.corrupt_lines))
+    }
+
+    /// Builds the collection from the diff drained by the caller, e.g. [diff::DiffFile::drain_encrypted]
+    pub fn from_parts(
+        diff: TDiff,
+        index_collection: IndexCollection,
+        diff_collection: Vec<DbPostRefSerialized>,
+    ) -> Self {
+        let mut refr = Self::empty(diff);
+        refr.ordered.reserve(index_collection.indexes.len());
+
+        refr.apply_serialized_posts(index_collection.indexes);
+        refr.apply_serialized_posts(diff_collection);
+
+        refr
+    }
+
+    /// Same as [DbRefCollection::new], but the index is read from `index` reference by reference,
+    /// in either [IndexFormat], without deserializing it into [IndexCollection] first.
+    pub fn from_reader(index: impl Read) -> DbRefCollectionResult<Self> {
+        Self::load(index, TDiff::drain)
+    }
+
+    /// Reads the index like [DbRefCollection::from_reader] and applies the diff returned by `drain`,
+    /// e.g. [diff::DiffFile::drain_encrypted]. The diff is drained only after the whole index is read,
+    /// so it's left untouched if the index is broken.
+    pub fn load(
+        index: impl Read,
+        drain: impl FnOnce() -> DiffResult<(TDiff, Vec<DbPostRefSerialized>)>,
+    ) -> DbRefCollectionResult<Self> {
+        let mut loaded = DbRefCollection::empty(LoadingDiff);
+        index_file::read_index(index, &mut IndexLoader(&mut loaded))?;
+
+        let (diff, diff_collection) = drain()?;
+        let mut refr = loaded.with_diff(diff);
+        refr.apply_serialized_posts(diff_collection);
+
+        Ok(refr)
+    }
+
+    /// Writes all references in the index order, see [index_file::write_index]
+    /// # Returns
+    /// Number of written references
+    pub fn write_index(
+        &self,
+        writer: impl Write,
+        format: IndexFormat,
+    ) -> DbRefCollectionResult<usize> {
+        index_file::write_index(writer, format, self.ordered.len(), self.iter())?;
+        Ok(self.ordered.len())
+    }
+
+    fn empty(diff: TDiff) -> Self {
+        DbRefCollection {
             diff,
             deleted: Default::default(),
             free: Default::default(),
+            missing_parents: Default::default(),
             ordered: Default::default(),
+            referrers: Default::default(),
             refs: Default::default(),
             reply_refs: Default::default(),
-        };
-        refr.ordered.reserve(index_collection.indexes.len());
+            transaction: None,
+            fit_strategy: FitStrategy::default(),
+        }
+    }
 
-        refr.apply_serialized_posts(index_collection.indexes);
-        refr.apply_serialized_posts(diff_collection);
+    /// Moves the references into the collection with another diff
+    fn with_diff<TOther: Diff>(self, diff: TOther) -> DbRefCollection<TOther> {
+        debug_assert!(self.transaction.is_none());
+        DbRefCollection {
+            diff,
+            deleted: self.deleted,
+            free: self.free,
+            missing_parents: self.missing_parents,
+            ordered: self.ordered,
+            referrers: self.referrers,
+            refs: self.refs,
+            reply_refs: self.reply_refs,
+            transaction: None,
+            fit_strategy: self.fit_strategy,
+        }
+    }
 
-        Ok(refr)
+    fn reserve(&mut self, additional: usize) {
+        self.refs.reserve(additional);
+        self.reply_refs.reserve(additional);
+        self.ordered.reserve(additional);
+        self.referrers.reserve(additional);
+    }
+
+    /// Sets how the space of deleted posts is picked for new posts, [FitStrategy::BestFit] by default
+    pub fn set_fit_strategy(&mut self, fit_strategy: FitStrategy) {
+        self.fit_strategy = fit_strategy;
     }
 
     /// Puts post into the database reference collection.
     pub fn put_post(&mut self, post: Post) -> DbRefCollectionResult<(DbPostRefHash, PostMessage)> {
-        let post_bytes = post.get_message_bytes();
+        let length = post.message.as_bytes().len() as u64;
+        self.put_stored_post(post, length, None)
+    }
+
+    /// Same as [DbRefCollection::put_post] for the message which takes `stored_length` bytes in the chunk,
+    /// e.g. after it's compressed.
+    /// If `shared` extent is passed, the post references it instead of taking free space.
+    /// It must hold the identical message of a live post, see [DbRefCollection::extent_referrers].
+    pub fn put_stored_post(
+        &mut self,
+        post: Post,
+        stored_length: u64,
+        shared: Option<ChunkSettings>,
+    ) -> DbRefCollectionResult<(DbPostRefHash, PostMessage)> {
         let hashes = PostHashes {
-            hash: DbPostRefHash::new(post.hash),
-            parent: DbPostRefHash::new(post.reply_to),
+            hash: DbPostRefHash::new(&post.hash),
+            parent: DbPostRefHash::new(&post.reply_to),
         };
 
         self.validate_post_not_exist_or_deleted(&hashes)?;
 
         let mut post_ref = DbPostRef {
-            chunk_settings: None,
+            chunk_settings: shared,
             deleted: false,
-            length: post_bytes.len() as u64,
+            length: stored_length,
             parent_hash: hashes.parent.clone(),
         };
 
-        self.put_ref_into_free_chunk(&mut post_ref, &post_bytes);
+        if post_ref.chunk_settings.is_none() {
+            self.put_ref_into_free_chunk(&mut post_ref)?;
+        }
+        self.track_change(&hashes);
         self.upsert_ref(&hashes, post_ref);
-        self.diff
-            .append(&hashes, self.refs.get(&hashes.hash).unwrap())?;
+        self.persist_change(&hashes)?;
 
         Ok((hashes.hash, post.message))
     }
 
-    pub fn mark_post_as_deleted(&mut self, hash: &str) -> DbRefCollectionResult<()> {
-        let db_ref = match self.refs.get_mut(&Rc::new(hash.to_string())) {
+    pub fn mark_post_as_deleted(
+        &mut self,
+        hash: impl Into<DbPostRefHash>,
+    ) -> DbRefCollectionResult<()> {
+        let hash = hash.into();
+        let parent = match self.refs.get(&hash) {
             None => Err(DbRefCollectionError::RefDoesNotExist),
             Some(db_ref) => {
                 if db_ref.deleted {
                     Err(DbRefCollectionError::RefAlreadyDeleted)
                 } else {
-                    Ok(db_ref)
+                    Ok(db_ref.parent_hash.clone())
                 }
             }
         }?;
+        let hashes = PostHashes {
+            hash: hash.clone(),
+            parent,
+        };
+
+        self.track_change(&hashes);
+        let db_ref = self.refs.get_mut(&hash).unwrap();
         db_ref.deleted = true;
-        let hash = Rc::new(hash.to_string());
-        let parent = db_ref.parent_hash.clone();
+        let settings = db_ref.chunk_settings.clone();
+        self.deleted.insert(hash.clone());
+        if let Some(settings) = settings {
+            self.remove_referrer(&settings);
+            if self.extent_referrers(&settings) > 0 {
+                // Extent is still used by other posts, so this one just stops referencing it
+                let db_ref = self.refs.get_mut(&hash).unwrap();
+                db_ref.chunk_settings = None;
+                db_ref.length = 0;
+            } else {
+                self.free.insert(hash);
+            }
+        }
+        self.persist_change(&hashes)?;
 
-        self.diff.append(
-            &PostHashes {
-                hash: hash.clone(),
-                parent,
-            },
-            db_ref,
-        )?;
+        Ok(())
+    }
 
-        self.deleted.insert(hash.clone());
-        self.free.insert(hash.clone());
+    /// Sets the extent the message of the live post was written into
+    pub fn set_chunk_settings(
+        &mut self,
+        hash: impl Into<DbPostRefHash>,
+        settings: ChunkSettings,
+    ) -> DbRefCollectionResult<()> {
+        let hash = hash.into();
+        let mut db_ref = self
+            .refs
+            .get(&hash)
+            .cloned()
+            .ok_or(DbRefCollectionError::RefDoesNotExist)?;
+        let hashes = PostHashes {
+            hash,
+            parent: db_ref.parent_hash.clone(),
+        };
 
+        self.track_change(&hashes);
+        db_ref.chunk_settings = Some(settings);
+        self.upsert_ref(&hashes, db_ref);
+        self.persist_change(&hashes)
+    }
+
+    /// Number of live posts referencing the extent
+    pub fn extent_referrers(&self, settings: &ChunkSettings) -> usize {
+        self.referrers.get(settings).copied().unwrap_or(0)
+    }
+
+    /// Extents of the live posts with their stored lengths, e.g. for
+    /// [OnDiskChunkCollectionProcessor::with_deduplication](super::chunk::chunk_processor::OnDiskChunkCollectionProcessor::with_deduplication).
+    /// Extent shared by several posts is returned for each of them.
+    pub fn live_extents(&self) -> impl Iterator<Item = (ChunkSettings, u64)> + '_ {
+        self.iter()
+            .filter(|(_, db_ref)| !db_ref.deleted)
+            .filter_map(|(_, db_ref)| Some((db_ref.chunk_settings.clone()?, db_ref.length)))
+    }
+
+    /// Total length of the live post messages, counting the extent shared by several posts once
+    pub fn live_bytes(&self) -> u64 {
+        let extents: HashMap<ChunkSettings, u64> = self.live_extents().collect();
+        extents.values().sum()
+    }
+
+    /// Starts collecting changes instead of writing them into the diff right away.
+    /// Collected changes are written with a single diff write on [DbRefCollection::commit_transaction],
+    /// or reverted with [DbRefCollection::rollback_transaction].
+    /// # Errors
+    /// [DbRefCollectionError::TransactionAlreadyStarted] if the transaction is not committed or rolled back yet,
+    /// as transactions can't be nested
+    pub fn begin_transaction(&mut self) -> DbRefCollectionResult<()> {
+        if self.transaction.is_some() {
+            return Err(DbRefCollectionError::TransactionAlreadyStarted);
+        }
+
+        self.transaction = Some(Transaction::default());
         Ok(())
     }
 
-    pub fn get_ref_mut(&mut self, hash: &str) -> Option<&mut DbPostRef> {
-        self.refs.get_mut(&hash.to_string())
+    /// Writes all refs changed during the transaction into the diff.
+    /// # Errors
+    /// If writing into the diff fails, the transaction is rolled back and the error is returned.
+    pub fn commit_transaction(&mut self) -> DbRefCollectionResult<()> {
+        let transaction = self
+            .transaction
+            .take()
+            .ok_or(DbRefCollectionError::NoActiveTransaction)?;
+
+        let serialized: Vec<DbPostRefSerialized> = transaction
+            .changes
+            .iter()
+            .map(|(hashes, _)| DbPostRefSerialized::new(hashes, &self.refs[&hashes.hash]))
+            .collect();
+
+        if let Err(err) = self.diff.append_batch(&serialized) {
+            self.revert(transaction);
+            return Err(err.into());
+        }
+
+        Ok(())
     }
 
-    pub fn get_ref(&self, hash: &str) -> Option<&DbPostRef> {
-        self.refs.get(&hash.to_string())
+    /// Flushes written diff lines to the disk
+    pub fn sync_diff(&mut self) -> DbRefCollectionResult<()> {
+        self.diff.sync()?;
+        Ok(())
     }
 
-    pub fn ref_exists(&self, hash: &str) -> bool {
-        self.refs.contains_key(&hash.to_string())
+    /// Reverts all refs changed during the transaction to their previous state.
+    pub fn rollback_transaction(&mut self) -> DbRefCollectionResult<()> {
+        let transaction = self
+            .transaction
+            .take()
+            .ok_or(DbRefCollectionError::NoActiveTransaction)?;
+        self.revert(transaction);
+
+        Ok(())
     }
 
-    pub fn ref_deleted(&self, hash: &str) -> bool {
-        self.get_ref(hash).map_or(false, |val| val.deleted)
+    pub fn get_ref_mut(&mut self, hash: impl Into<DbPostRefHash>) -> Option<&mut DbPostRef> {
+        self.refs.get_mut(&hash.into())
     }
 
-    fn put_ref_into_free_chunk(&mut self, post_ref: &mut DbPostRef, post_bytes: &[u8]) {
-        let opt_hash = self.find_free_ref(post_bytes);
+    pub fn get_ref(&self, hash: impl Into<DbPostRefHash>) -> Option<&DbPostRef> {
+        self.refs.get(&hash.into())
+    }
+
+    pub fn ref_exists(&self, hash: impl Into<DbPostRefHash>) -> bool {
+        self.refs.contains_key(&hash.into())
+    }
+
+    pub fn ref_deleted(&self, hash: impl Into<DbPostRefHash>) -> bool {
+        self.get_ref(hash).is_some_and(|val| val.deleted)
+    }
+
+    /// Total length of the deleted posts whose chunk space can be reused
+    pub fn free_bytes(&self) -> u64 {
+        self.free.iter().map(|hash| self.refs[hash].length).sum()
+    }
+
+    /// Size of the diff in bytes
+    pub fn diff_size(&self) -> DbRefCollectionResult<u64> {
+        Ok(self.diff.size()?)
+    }
+
+    /// Current end of the diff, see [Diff::position]
+    pub fn diff_position(&self) -> DbRefCollectionResult<Option<DiffPosition>> {
+        Ok(self.diff.position()?)
+    }
+
+    /// References written into the diff since the position, see [Diff::read_since]
+    pub fn diff_since(
+        &self,
+        position: &DiffPosition,
+    ) -> DbRefCollectionResult<Option<Vec<DbPostRefSerialized>>> {
+        Ok(self.diff.read_since(position)?)
+    }
+
+    /// Iterates over post references in the index order
+    pub fn iter(&self) -> impl Iterator<Item = (&DbPostRefHash, &DbPostRef)> {
+        self.ordered
+            .iter()
+            .map(move |hash| (hash, &self.refs[hash]))
+    }
+
+    /// Serializes references in the index order, e.g. to be written into `index-3.json`
+    pub fn to_index_collection(&self) -> IndexCollection {
+        let indexes = self
+            .iter()
+            .map(|(hash, db_ref)| {
+                let hashes = PostHashes {
+                    parent: db_ref.parent_hash.clone(),
+                    hash: hash.clone(),
+                };
+                DbPostRefSerialized::new(&hashes, db_ref)
+            })
+            .collect();
+
+        IndexCollection { indexes }
+    }
+
+    /// Summarizes hashes of all posts, including deleted ones, so the peer doesn't send posts which were deleted here
+    pub fn sync_summary(&self) -> SyncSummary {
+        let mut summary = SyncSummary::with_capacity(self.refs.len(), DEFAULT_FALSE_POSITIVE_RATE);
+        for hash in &self.ordered {
+            summary.insert(&hash.to_string());
+        }
+
+        summary
+    }
+
+    /// Lists live posts which are not in the peer summary, in the index order, so parents go before their replies.
+    /// Few of the missing posts may be left out, see [SyncSummary].
+    pub fn missing_from(&self, peer: &SyncSummary) -> Vec<DbPostRefHash> {
+        self.iter()
+            .filter(|(hash, db_ref)| !db_ref.deleted && !peer.contains(&hash.to_string()))
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    /// Returns posts whose parent was not received yet, in the index order
+    pub fn list_orphans(&self) -> Vec<DbPostRefHash> {
+        self.iter()
+            .filter(|(_, db_ref)| self.missing_parents.contains(&db_ref.parent_hash))
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    /// Returns hashes of the posts which are replied to, but were not received yet, e.g. to look for them
+    pub fn missing_parents(&self) -> impl Iterator<Item = &DbPostRefHash> {
+        self.missing_parents.iter()
+    }
+
+    pub fn is_missing_parent(&self, hash: impl Into<DbPostRefHash>) -> bool {
+        self.missing_parents.contains(&hash.into())
+    }
+
+    /// Returns direct replies of the post in the order they were received, even if the post itself is missing
+    pub fn replies(&self, hash: impl Into<DbPostRefHash>) -> &[DbPostRefHash] {
+        self.reply_refs.get(&hash.into()).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns hashes of the post and all of its replies, recursively. Parents always go before their replies.
+    /// Empty if the post doesn't exist.
+    pub fn subtree(&self, root: impl Into<DbPostRefHash>) -> Vec<DbPostRefHash> {
+        let root = root.into();
+        if !self.refs.contains_key(&root) {
+            return Vec::new();
+        }
+
+        // Post replying to itself or other cycles in corrupted index must not be walked twice
+        let mut visited = HashSet::new();
+        visited.insert(root.clone());
+        let mut subtree = vec![root];
+        let mut next = 0;
+        while let Some(hash) = subtree.get(next).cloned() {
+            next += 1;
+            for reply in self.reply_refs.get(&hash).into_iter().flatten() {
+                if visited.insert(reply.clone()) {
+                    subtree.push(reply.clone());
+                }
+            }
+        }
+
+        subtree
+    }
+
+    /// Marks the post as deleted and forgets its chunk space, so it can't be reused anymore.
+    /// Used for references which point to space that can't be trusted.
+    pub fn discard_ref_space(
+        &mut self,
+        hash: impl Into<DbPostRefHash>,
+    ) -> DbRefCollectionResult<()> {
+        let hash = hash.into();
+        let parent = match self.refs.get(&hash) {
+            None => return Err(DbRefCollectionError::RefDoesNotExist),
+            Some(db_ref) => db_ref.parent_hash.clone(),
+        };
+        let hashes = PostHashes {
+            hash: hash.clone(),
+            parent,
+        };
+
+        self.track_change(&hashes);
+        let db_ref = self.refs.get_mut(&hash).unwrap();
+        let was_live = !db_ref.deleted;
+        db_ref.deleted = true;
+        let settings = db_ref.chunk_settings.take();
+        db_ref.length = 0;
+        if let (Some(settings), true) = (settings, was_live) {
+            self.remove_referrer(&settings);
+        }
+        self.deleted.insert(hash.clone());
+        self.free.remove(&hash);
+        self.persist_change(&hashes)?;
+
+        Ok(())
+    }
+
+    fn put_ref_into_free_chunk(&mut self, post_ref: &mut DbPostRef) -> DbRefCollectionResult<()> {
+        let opt_hash = self.find_free_ref(post_ref.length);
         let free_ref_hash = match opt_hash {
             Some(it) => it,
-            _ => return,
+            _ => return Ok(()),
         };
-        let free_ref = self.refs.get_mut(&free_ref_hash).unwrap();
-        let free_chunk_settings = mem::replace(&mut free_ref.chunk_settings, None);
+        let hashes = PostHashes {
+            parent: self.refs[&free_ref_hash].parent_hash.clone(),
+            hash: free_ref_hash,
+        };
+        self.track_change(&hashes);
+
+        let free_ref = self.refs.get_mut(&hashes.hash).unwrap();
+        let free_chunk_settings = free_ref.chunk_settings.take();
         post_ref.chunk_settings = free_chunk_settings;
         free_ref.length = 0;
 
-        self.free.remove(&free_ref_hash);
-        self.diff
-            .append(
-                &PostHashes {
-                    hash: free_ref_hash,
-                    parent: free_ref.parent_hash.clone(),
-                },
-                &free_ref,
-            )
-            .unwrap();
+        self.free.remove(&hashes.hash);
+        self.persist_change(&hashes)?;
+
+        Ok(())
+    }
+
+    /// Remembers the state of the ref before its first change in the current transaction
+    fn track_change(&mut self, hashes: &PostHashes) {
+        let transaction = match &mut self.transaction {
+            Some(transaction) => transaction,
+            None => return,
+        };
+
+        if transaction.changed_hashes.insert(hashes.hash.clone()) {
+            let previous = self.refs.get(&hashes.hash).cloned();
+            transaction.changes.push((hashes.clone(), previous));
+        }
+    }
+
+    /// Writes changed ref into the diff, unless the transaction is active
+    fn persist_change(&mut self, hashes: &PostHashes) -> DbRefCollectionResult<()> {
+        if self.transaction.is_none() {
+            self.diff.append(hashes, &self.refs[&hashes.hash])?;
+        }
+
+        Ok(())
+    }
+
+    fn revert(&mut self, transaction: Transaction) {
+        for (hashes, previous) in transaction.changes.into_iter().rev() {
+            match previous {
+                Some(db_ref) => self.upsert_ref(&hashes, db_ref),
+                None => self.remove_ref(&hashes),
+            }
+        }
+    }
+
+    /// Removes post reference from `refs`, `reply_refs`, `ordered`, `deleted` and `free`
+    fn remove_ref(&mut self, hashes: &PostHashes) {
+        let hash = &hashes.hash;
+        let removed = match self.refs.remove(hash) {
+            Some(removed) => removed,
+            None => return,
+        };
+
+        if let Some(replies) = self.reply_refs.get_mut(&hashes.parent) {
+            replies.retain(|reply| reply != hash);
+            if replies.is_empty() {
+                self.reply_refs.remove(&hashes.parent);
+                self.missing_parents.remove(&hashes.parent);
+            }
+        }
+        if self.reply_refs.contains_key(hash) {
+            self.missing_parents.insert(hash.clone());
+        }
+        if let Some(position) = self.ordered.iter().rposition(|ordered| ordered == hash) {
+            self.ordered.remove(position);
+        }
+        self.deleted.remove(hash);
+        self.free.remove(hash);
+        if let (Some(settings), false) = (&removed.chunk_settings, removed.deleted) {
+            self.remove_referrer(settings);
+        }
     }
 
     /// Puts post reference to the `refs`, `reply_refs`, and `deleted` if post was deleted.
     /// If ref is already in the collection, updates it and updates diff
     fn upsert_ref(&mut self, hashes: &PostHashes, post: DbPostRef) {
         let hash_rc = &hashes.hash;
-        let parent_rc = self.get_rc(Rc::clone(&hashes.parent));
+        let parent_rc = hashes.parent.clone();
 
         let is_presented = self.refs.contains_key(hash_rc);
-        let parent_post_replies = self.reply_refs.entry(parent_rc).or_insert_with(Vec::new);
+        let parent_post_replies = self.reply_refs.entry(parent_rc.clone()).or_default();
         if !is_presented {
             parent_post_replies.push(hash_rc.clone());
             self.ordered.push(hash_rc.clone());
@@ -199,24 +659,57 @@ impl<TDiff: Diff> DbRefCollection<TDiff> {
             self.free.remove(hash_rc);
         }
 
-        self.refs.insert(hash_rc.clone(), post);
+        if let (Some(settings), false) = (&post.chunk_settings, post.deleted) {
+            *self.referrers.entry(settings.clone()).or_default() += 1;
+        }
+        let previous = self.refs.insert(hash_rc.clone(), post);
+        if !is_presented {
+            self.missing_parents.remove(hash_rc);
+            if !self.refs.contains_key(&parent_rc) {
+                self.missing_parents.insert(parent_rc);
+            }
+        }
+        if let Some(DbPostRef {
+            chunk_settings: Some(settings),
+            deleted: false,
+            ..
+        }) = previous
+        {
+            self.remove_referrer(&settings);
+        }
     }
 
-    // Todo reuse the rest of free space
-    fn find_free_ref(&self, post_bytes: &[u8]) -> Option<DbPostRefHash> {
-        let post_length = post_bytes.len();
-        let best = self.find_best_free_ref(post_length);
-
-        match best {
-            Some(hash) => {
-                let clone = Rc::clone(&hash);
-                Some(clone)
+    fn remove_referrer(&mut self, settings: &ChunkSettings) {
+        if let Some(count) = self.referrers.get_mut(settings) {
+            *count -= 1;
+            if *count == 0 {
+                self.referrers.remove(settings);
             }
-            None => None,
         }
     }
 
-    fn find_best_free_ref(&self, post_length: usize) -> Option<&DbPostRefHash> {
+    // Todo reuse the rest of free space
+    fn find_free_ref(&self, post_length: u64) -> Option<DbPostRefHash> {
+        let best = match self.fit_strategy {
+            FitStrategy::BestFit => self.find_best_free_ref(post_length),
+            FitStrategy::FirstFit => self.find_first_free_ref(post_length),
+            FitStrategy::NeverReuse => None,
+        };
+
+        best.cloned()
+    }
+
+    /// Finds free space closest to the start of the database
+    fn find_first_free_ref(&self, post_length: u64) -> Option<&DbPostRefHash> {
+        self.free
+            .iter()
+            .filter(|hash| self.refs[*hash].length >= post_length)
+            .filter_map(|hash| Some((self.refs[hash].chunk_settings.as_ref()?, hash)))
+            .min_by_key(|(settings, _)| (settings.chunk_index, settings.offset))
+            .map(|(_, hash)| hash)
+    }
+
+    fn find_best_free_ref(&self, post_length: u64) -> Option<&DbPostRefHash> {
         let mut min = u64::MAX;
         let mut best: Option<&DbPostRefHash> = None;
         for hash in self
@@ -225,8 +718,8 @@ impl<TDiff: Diff> DbRefCollection<TDiff> {
             .filter(|p| self.refs[*p].chunk_settings.is_some())
         {
             let free_item = &self.refs[hash];
-            if free_item.length >= post_length as u64 {
-                let diff = free_item.length - post_length as u64;
+            if free_item.length >= post_length {
+                let diff = free_item.length - post_length;
 
                 if diff < min {
                     min = diff;
@@ -238,15 +731,6 @@ impl<TDiff: Diff> DbRefCollection<TDiff> {
         best
     }
 
-    fn get_rc(&self, parent: DbPostRefHash) -> DbPostRefHash {
-        let kv = self.refs.get_key_value(&parent);
-
-        match kv {
-            Some((key, _)) => Rc::clone(key),
-            None => parent,
-        }
-    }
-
     fn apply_serialized_posts(&mut self, posts: Vec<DbPostRefSerialized>) {
         for ser_post in posts {
             let (raw_hashes, data) = ser_post.split();
@@ -269,5 +753,43 @@ impl<TDiff: Diff> DbRefCollection<TDiff> {
     }
 }
 
+/// Diff of the collection which is being loaded, the references read from the index are not persisted again
+struct LoadingDiff;
+
+impl Diff for LoadingDiff {
+    fn append(&mut self, _hashes: &PostHashes, _db_ref: &DbPostRef) -> DiffResult<()> {
+        Ok(())
+    }
+
+    fn append_batch(&mut self, _refs: &[DbPostRefSerialized]) -> DiffResult<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> DiffResult<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> DiffResult<u64> {
+        Ok(0)
+    }
+
+    fn drain() -> DiffResult<(Self, Vec<DbPostRefSerialized>)> {
+        Ok((LoadingDiff, Vec::new()))
+    }
+}
+
+/// Puts the references read by [index_file::read_index] into the collection
+struct IndexLoader<'a>(&'a mut DbRefCollection<LoadingDiff>);
+
+impl IndexVisitor for IndexLoader<'_> {
+    fn reserve(&mut self, refs: usize) {
+        self.0.reserve(refs);
+    }
+
+    fn visit(&mut self, hashes: PostHashes, db_ref: DbPostRef) {
+        self.0.upsert_ref(&hashes, db_ref);
+    }
+}
+
 #[cfg(test)]
 mod tests;
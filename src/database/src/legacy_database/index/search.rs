@@ -0,0 +1,372 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::legacy_database::crypto::{Cipher, CryptoError};
+
+pub const SEARCH_INDEX_FILENAME: &str = "search-3.json";
+const SEARCH_INDEX_TMP_FILENAME: &str = "search-3.json.tmp";
+
+#[derive(Debug, Error)]
+pub enum SearchIndexError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+
+    #[error("Error (de)serializing search index")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("Error decrypting search index")]
+    CryptoError(#[from] CryptoError),
+}
+
+pub type SearchIndexResult<T> = Result<T, SearchIndexError>;
+
+/// Inverted index of post messages, stored in `search-3.json` next to the `index-3.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    /// `Key` is a token, `value` maps post hashes to positions of the token in the post message
+    terms: BTreeMap<String, HashMap<String, Vec<u32>>>,
+
+    /// `Key` is post hash, `value` is list of distinct tokens of the post message
+    posts: HashMap<String, Vec<String>>,
+}
+
+/// Part of the search query which must match for the post to be found
+#[derive(Debug, PartialEq, Eq)]
+enum QueryClause {
+    /// Whole token, e.g. `word`
+    Term(String),
+
+    /// Any token starting with the prefix, e.g. `wor*`
+    Prefix(String),
+
+    /// Tokens following each other, e.g. `"some words"`
+    Phrase(Vec<String>),
+}
+
+impl SearchIndex {
+    /// Loads the index from `search-3.json`. Returns an empty index if the file doesn't exist.
+    pub fn load() -> SearchIndexResult<Self> {
+        let path = Path::new(SEARCH_INDEX_FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Same as [SearchIndex::load] for the index written by [SearchIndex::save_encrypted]
+    pub fn load_encrypted(cipher: &Cipher) -> SearchIndexResult<Self> {
+        let path = Path::new(SEARCH_INDEX_FILENAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let plain = cipher.open(&fs::read(path)?, SEARCH_INDEX_FILENAME.as_bytes())?;
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    /// Writes the index into `search-3.json`. The file is replaced atomically.
+    pub fn save(&self) -> SearchIndexResult<()> {
+        Self::write_file(&serde_json::to_vec(self)?)
+    }
+
+    /// Same as [SearchIndex::save], but the whole file is sealed by the cipher
+    pub fn save_encrypted(&self, cipher: &Cipher) -> SearchIndexResult<()> {
+        let plain = serde_json::to_vec(self)?;
+        Self::write_file(&cipher.seal(&plain, SEARCH_INDEX_FILENAME.as_bytes()))
+    }
+
+    fn write_file(contents: &[u8]) -> SearchIndexResult<()> {
+        let mut writer = BufWriter::new(File::create(SEARCH_INDEX_TMP_FILENAME)?);
+        writer.write_all(contents)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        fs::rename(SEARCH_INDEX_TMP_FILENAME, SEARCH_INDEX_FILENAME)?;
+
+        Ok(())
+    }
+
+    /// Indexes post message. If the post is already indexed, its previous message is replaced.
+    pub fn insert(&mut self, hash: &str, message: &str) {
+        self.remove(hash);
+
+        let mut post_terms: Vec<String> = Vec::new();
+        for (position, token) in tokenize(message).into_iter().enumerate() {
+            let positions = self
+                .terms
+                .entry(token.clone())
+                .or_default()
+                .entry(hash.to_string())
+                .or_default();
+            if positions.is_empty() {
+                post_terms.push(token);
+            }
+            positions.push(position as u32);
+        }
+
+        self.posts.insert(hash.to_string(), post_terms);
+    }
+
+    pub fn remove(&mut self, hash: &str) {
+        let post_terms = match self.posts.remove(hash) {
+            Some(terms) => terms,
+            None => return,
+        };
+
+        for term in post_terms {
+            if let Some(postings) = self.terms.get_mut(&term) {
+                postings.remove(hash);
+                if postings.is_empty() {
+                    self.terms.remove(&term);
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.posts.contains_key(hash)
+    }
+
+    /// Hashes of the indexed posts
+    pub fn hashes(&self) -> impl Iterator<Item = &String> {
+        self.posts.keys()
+    }
+
+    /// Finds posts matching every part of the query.
+    ///
+    /// Query consists of whitespace separated words (`word`), prefixes (`wor*`) and quoted phrases (`"some words"`).
+    /// Matching is case insensitive.
+    /// # Returns
+    /// Up to `limit` post hashes, the ones with most matches first
+    pub fn search(&self, query: &str, limit: usize) -> Vec<String> {
+        let clauses = parse_query(query);
+        if clauses.is_empty() {
+            return Vec::new();
+        }
+
+        let mut scores: Option<HashMap<&str, usize>> = None;
+        for clause in &clauses {
+            let matches = self.match_clause(clause);
+            scores = Some(match scores {
+                None => matches,
+                Some(scores) => scores
+                    .into_iter()
+                    .filter_map(|(hash, score)| matches.get(hash).map(|m| (hash, score + m)))
+                    .collect(),
+            });
+        }
+
+        let mut found: Vec<(&str, usize)> = scores.unwrap_or_default().into_iter().collect();
+        found.sort_by(|(hash_a, score_a), (hash_b, score_b)| {
+            score_b.cmp(score_a).then_with(|| hash_a.cmp(hash_b))
+        });
+
+        found
+            .into_iter()
+            .take(limit)
+            .map(|(hash, _)| hash.to_string())
+            .collect()
+    }
+
+    /// Returns hashes of the posts matching the clause with number of matches in each post
+    fn match_clause(&self, clause: &QueryClause) -> HashMap<&str, usize> {
+        let mut matches: HashMap<&str, usize> = HashMap::new();
+        match clause {
+            QueryClause::Term(term) => {
+                for (hash, positions) in self.terms.get(term).into_iter().flatten() {
+                    *matches.entry(hash).or_default() += positions.len();
+                }
+            }
+            QueryClause::Prefix(prefix) => {
+                let postings = self
+                    .terms
+                    .range(prefix.clone()..)
+                    .take_while(|(term, _)| term.starts_with(prefix.as_str()));
+                for (_, posts) in postings {
+                    for (hash, positions) in posts {
+                        *matches.entry(hash).or_default() += positions.len();
+                    }
+                }
+            }
+            QueryClause::Phrase(terms) => {
+                let postings: Option<Vec<_>> = terms.iter().map(|t| self.terms.get(t)).collect();
+                let postings = match postings {
+                    Some(postings) => postings,
+                    None => return matches,
+                };
+
+                for (hash, first_positions) in postings[0] {
+                    let count = first_positions
+                        .iter()
+                        .filter(|start| {
+                            postings.iter().enumerate().skip(1).all(|(offset, posts)| {
+                                posts.get(hash).is_some_and(|positions| {
+                                    positions.contains(&(**start + offset as u32))
+                                })
+                            })
+                        })
+                        .count();
+                    if count > 0 {
+                        matches.insert(hash, count);
+                    }
+                }
+            }
+        }
+
+        matches
+    }
+}
+
+/// Splits text into lowercase alphanumeric tokens
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+fn parse_query(query: &str) -> Vec<QueryClause> {
+    let mut clauses = Vec::new();
+    for (index, part) in query.split('"').enumerate() {
+        // Odd parts are inside of the quotes
+        if index % 2 == 1 {
+            let tokens = tokenize(part);
+            match tokens.len() {
+                0 => {}
+                1 => clauses.extend(tokens.into_iter().map(QueryClause::Term)),
+                _ => clauses.push(QueryClause::Phrase(tokens)),
+            }
+            continue;
+        }
+
+        for word in part.split_whitespace() {
+            let is_prefix = word.ends_with('*');
+            let mut tokens = tokenize(word);
+            let last = match tokens.pop() {
+                Some(last) => last,
+                None => continue,
+            };
+
+            clauses.extend(tokens.into_iter().map(QueryClause::Term));
+            clauses.push(if is_prefix {
+                QueryClause::Prefix(last)
+            } else {
+                QueryClause::Term(last)
+            });
+        }
+    }
+
+    clauses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_temp_dir;
+    use rusty_fork::rusty_fork_test;
+
+    #[test]
+    fn search_should_find_posts_containing_all_terms() {
+        let index = index();
+
+        assert_eq!(index.search("brown fox", 10), vec!["1", "2"]);
+        assert_eq!(index.search("lazy FOX", 10), vec!["1"]);
+        assert!(index.search("cat", 10).is_empty());
+    }
+
+    #[test]
+    fn search_should_match_phrases() {
+        let index = index();
+
+        assert_eq!(index.search("\"brown fox\"", 10), vec!["1"]);
+        assert_eq!(index.search("\"fox brown\"", 10), vec!["2"]);
+    }
+
+    #[test]
+    fn search_should_match_prefixes() {
+        let index = index();
+
+        assert_eq!(index.search("qui*", 10), vec!["1", "3"]);
+        assert!(index.search("qui", 10).is_empty());
+    }
+
+    #[test]
+    fn search_should_rank_by_matches_and_respect_limit() {
+        let mut index = index();
+        index.insert("4", "fox fox fox");
+
+        assert_eq!(index.search("fox", 2), vec!["4", "1"]);
+    }
+
+    #[test]
+    fn remove_should_exclude_post_from_results() {
+        let mut index = index();
+
+        index.remove("1");
+
+        assert_eq!(index.search("fox", 10), vec!["2"]);
+        assert!(!index.terms.contains_key("lazy"));
+    }
+
+    #[test]
+    fn parse_query_should_recognize_clauses() {
+        let clauses = parse_query("word pre* \"some phrase\"");
+
+        assert_eq!(
+            clauses,
+            vec![
+                QueryClause::Term("word".to_string()),
+                QueryClause::Prefix("pre".to_string()),
+                QueryClause::Phrase(vec!["some".to_string(), "phrase".to_string()]),
+            ]
+        );
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn save_and_load_should_restore_index() {
+            in_temp_dir!({
+                index().save().unwrap();
+
+                let loaded = SearchIndex::load().unwrap();
+
+                assert_eq!(loaded.search("\"brown fox\"", 10), vec!["1"]);
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn encrypted_index_should_be_loaded_with_the_same_key_only() {
+            in_temp_dir!({
+                let cipher = Cipher::from_key([1; 32]);
+                index().save_encrypted(&cipher).unwrap();
+
+                let contents = fs::read(SEARCH_INDEX_FILENAME).unwrap();
+                assert!(!contents.windows(5).any(|word| word == b"brown"));
+                assert!(matches!(
+                    SearchIndex::load_encrypted(&Cipher::from_key([2; 32])),
+                    Err(SearchIndexError::CryptoError(CryptoError::Decryption))
+                ));
+                let loaded = SearchIndex::load_encrypted(&cipher).unwrap();
+                assert_eq!(loaded.search("fox", 10).len(), 2);
+            });
+        }
+    }
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::default();
+        index.insert("1", "The quick brown fox jumps over the lazy dog");
+        index.insert("2", "Fox, brown and red");
+        index.insert("3", "Quiet!");
+        index
+    }
+}
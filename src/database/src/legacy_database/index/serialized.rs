@@ -2,12 +2,22 @@ use std::{fs::File, io::BufReader};
 
 use serde::{Deserialize, Serialize};
 
-use crate::legacy_database::chunk::{chunk_index_to_name, chunk_name_to_index};
+use crate::legacy_database::{
+    chunk::{chunk_index_to_name, chunk_name_to_index},
+    crypto::{Cipher, CryptoResult},
+};
+
+use super::{
+    db_post_ref::{ChunkSettings, DbPostRef, DbPostRefHash},
+    index_file::{open_index, seal_index},
+};
+
+/// File with the [IndexCollection]
+pub const INDEX_FILENAME: &str = "index-3.json";
 
-use super::db_post_ref::{ChunkSettings, DbPostRef, DbPostRefHash};
 /// Reference of post messages, which are stored in chunks. This struct is serialized and written into
 /// `index-3.json` to save message positions inside chunks.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct DbPostRefSerialized {
     /// Post hash
     #[serde(rename = "h")]
@@ -21,7 +31,7 @@ pub struct DbPostRefSerialized {
     #[serde(rename = "o")]
     pub offset: u64,
 
-    /// Length of the post message in bytes
+    /// Length of the stored post message in bytes, compressed if the database is compressed
     #[serde(rename = "l")]
     pub length: u64,
 
@@ -40,6 +50,7 @@ pub struct IndexCollection {
     pub indexes: Vec<DbPostRefSerialized>,
 }
 
+#[derive(Clone)]
 pub struct PostHashes {
     pub parent: DbPostRefHash,
     pub hash: DbPostRefHash,
@@ -49,6 +60,18 @@ impl IndexCollection {
     pub fn from_file(file: File) -> serde_json::Result<Self> {
         serde_json::from_reader(BufReader::new(file))
     }
+
+    /// Reads the collection sealed by [IndexCollection::to_encrypted]
+    pub fn from_encrypted(sealed: &[u8], cipher: &Cipher) -> CryptoResult<Self> {
+        let plain = open_index(sealed, cipher)?;
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    /// Serializes and seals the collection to be written into `index-3.json`
+    pub fn to_encrypted(&self, cipher: &Cipher) -> serde_json::Result<Vec<u8>> {
+        let plain = serde_json::to_vec(self)?;
+        Ok(seal_index(&plain, cipher))
+    }
 }
 
 impl DbPostRefSerialized {
@@ -56,8 +79,8 @@ impl DbPostRefSerialized {
         let hash = self.hash;
         let parent = self.reply_to;
         let hashes = PostHashes {
-            parent: DbPostRefHash::new(parent),
-            hash: DbPostRefHash::new(hash),
+            parent: DbPostRefHash::new(&parent),
+            hash: DbPostRefHash::new(&hash),
         };
         let chunk_idx = self.chunk_name.map(chunk_name_to_index);
         let chunk_settings = match chunk_idx {
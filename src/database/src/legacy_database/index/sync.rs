@@ -0,0 +1,167 @@
+use std::convert::TryInto;
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use thiserror::Error;
+
+/// Share of the hashes a peer lacks which [SyncSummary::contains] reports as present by mistake
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Upper bound of the number of hash functions, more of them make the filter slower but barely more precise
+const MAX_HASH_COUNT: u8 = 16;
+
+#[derive(Debug, Error)]
+pub enum SyncSummaryError {
+    #[error("Summary is malformed")]
+    Malformed,
+}
+
+pub type SyncSummaryResult<T> = Result<T, SyncSummaryError>;
+
+/// Compact summary of the post hashes known to a node, exchanged to find the posts the other node is missing.
+///
+/// It's a bloom filter: a hash which was inserted is always reported as present,
+/// but a hash which was not may be reported as present too, with the chance set on creation.
+/// Every summary mixes its own random seed into the digests, so such posts are most likely not the same ones
+/// in the next exchange and are offered then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncSummary {
+    bits: Vec<u8>,
+    hash_count: u8,
+    seed: u64,
+}
+
+impl SyncSummary {
+    /// Creates an empty summary sized for `items` hashes
+    /// with the chance of a false positive not exceeding `false_positive_rate`
+    pub fn with_capacity(items: usize, false_positive_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let bit_count = (-items * rate.ln() / (2f64.ln() * 2f64.ln()))
+            .ceil()
+            .max(8.0);
+        let hash_count = (bit_count / items * 2f64.ln()).round() as u8;
+
+        SyncSummary {
+            bits: vec![0; (bit_count as usize).div_ceil(8)],
+            hash_count: hash_count.clamp(1, MAX_HASH_COUNT),
+            seed: OsRng.next_u64(),
+        }
+    }
+
+    pub fn insert(&mut self, hash: &str) {
+        for bit in self.bit_indexes(hash) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns `false` only if the hash was never inserted
+    pub fn contains(&self, hash: &str) -> bool {
+        self.bit_indexes(hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Serializes the summary to be sent to the peer: the number of hash functions, the seed and the bits
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.bits.len() + SEED_LEN + 1);
+        bytes.push(self.hash_count);
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.bits);
+        bytes
+    }
+
+    /// Reads the summary made by [SyncSummary::to_bytes]
+    /// # Errors
+    /// [SyncSummaryError::Malformed] if the summary has no seed or bits, or an impossible number of hash functions
+    pub fn from_bytes(bytes: &[u8]) -> SyncSummaryResult<Self> {
+        match bytes {
+            [hash_count, rest @ ..]
+                if (1..=MAX_HASH_COUNT).contains(hash_count) && rest.len() > SEED_LEN =>
+            {
+                let (seed, bits) = rest.split_at(SEED_LEN);
+                Ok(SyncSummary {
+                    bits: bits.to_vec(),
+                    hash_count: *hash_count,
+                    seed: u64::from_le_bytes(seed.try_into().unwrap()),
+                })
+            }
+            _ => Err(SyncSummaryError::Malformed),
+        }
+    }
+
+    /// Positions of the bits of the hash, derived from two independent digests (Kirsch-Mitzenmacher).
+    /// The first digest starts from the seed of the summary, so the positions differ between summaries.
+    fn bit_indexes(&self, hash: &str) -> impl Iterator<Item = usize> {
+        let bit_count = self.bits.len() as u64 * 8;
+        let first = fnv1a(hash.as_bytes(), FNV_OFFSET_BASIS ^ self.seed);
+        // Zero step would put every index of the hash into the same bit
+        let second = fnv1a(hash.as_bytes(), first) | 1;
+
+        (0..self.hash_count as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bit_count) as usize)
+    }
+}
+
+/// Length of the serialized seed, in bytes
+const SEED_LEN: usize = 8;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Digest which is the same on every platform and build, unlike the std hasher, so summaries can be exchanged
+fn fnv1a(bytes: &[u8], seed: u64) -> u64 {
+    bytes.iter().fold(seed, |digest, byte| {
+        (digest ^ *byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_should_contain_inserted_hashes() {
+        let mut summary = SyncSummary::with_capacity(100, DEFAULT_FALSE_POSITIVE_RATE);
+        let hashes: Vec<String> = (0..100).map(|i| format!("hash{}", i)).collect();
+
+        hashes.iter().for_each(|hash| summary.insert(hash));
+
+        assert!(hashes.iter().all(|hash| summary.contains(hash)));
+    }
+
+    #[test]
+    fn summary_false_positives_should_stay_near_the_rate() {
+        let mut summary = SyncSummary::with_capacity(1000, DEFAULT_FALSE_POSITIVE_RATE);
+        (0..1000).for_each(|i| summary.insert(&format!("present{}", i)));
+
+        let false_positives = (0..10000)
+            .filter(|i| summary.contains(&format!("absent{}", i)))
+            .count();
+
+        assert!(false_positives < 300, "{}", false_positives);
+    }
+
+    #[test]
+    fn from_bytes_should_read_serialized_summary() {
+        let mut summary = SyncSummary::with_capacity(10, DEFAULT_FALSE_POSITIVE_RATE);
+        summary.insert("1");
+
+        let read = SyncSummary::from_bytes(&summary.to_bytes()).unwrap();
+
+        assert_eq!(read, summary);
+        assert!(SyncSummary::from_bytes(&[0; 10]).is_err());
+        assert!(SyncSummary::from_bytes(&[3; 9]).is_err());
+    }
+
+    #[test]
+    fn summaries_should_have_different_false_positives() {
+        let false_positives = || {
+            let mut summary = SyncSummary::with_capacity(1000, DEFAULT_FALSE_POSITIVE_RATE);
+            (0..1000).for_each(|i| summary.insert(&format!("present{}", i)));
+            (0..10000)
+                .filter(|i| summary.contains(&format!("absent{}", i)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_ne!(false_positives(), false_positives());
+    }
+}
@@ -0,0 +1,78 @@
+use pretty_assertions::assert_eq;
+
+use crate::legacy_database::index::db_post_ref::ChunkSettings;
+
+use crate::tests::test_utils::*;
+
+#[test]
+fn shared_extent_should_be_freed_by_last_deleted_referrer() {
+    let mut coll = collection(vec![some_raw_ref("1", "0", 4)]);
+    let settings = shared_settings();
+    coll.put_stored_post(some_post("2", "0", "Test"), 4, Some(settings.clone()))
+        .unwrap();
+    assert_eq!(coll.extent_referrers(&settings), 2);
+
+    coll.mark_post_as_deleted("1").unwrap();
+
+    assert_eq!(coll.extent_referrers(&settings), 1);
+    assert!(coll.free.is_empty());
+    let detached = coll.get_ref("1").unwrap();
+    assert_eq!(detached.chunk_settings, None);
+    assert_eq!(detached.length, 0);
+
+    coll.mark_post_as_deleted("2").unwrap();
+
+    assert_eq!(coll.extent_referrers(&settings), 0);
+    assert!(coll.free.contains(&rc("2")));
+}
+
+#[test]
+fn rollback_should_restore_referrers() {
+    let mut coll = collection(vec![some_raw_ref("1", "0", 4)]);
+    let settings = shared_settings();
+
+    coll.begin_transaction().unwrap();
+    coll.put_stored_post(some_post("2", "0", "Test"), 4, Some(settings.clone()))
+        .unwrap();
+    coll.mark_post_as_deleted("1").unwrap();
+    coll.rollback_transaction().unwrap();
+
+    assert_eq!(coll.extent_referrers(&settings), 1);
+    assert_eq!(coll.get_ref("1").unwrap().chunk_settings, Some(settings));
+}
+
+#[test]
+fn set_chunk_settings_should_count_referrer() {
+    let mut coll = collection_with_diff(vec![]);
+    coll.put_post(some_post("20", "1", "Test")).unwrap();
+
+    let settings = ChunkSettings {
+        chunk_index: 5,
+        offset: 7,
+    };
+
+    coll.set_chunk_settings("20", settings.clone()).unwrap();
+
+    assert_eq!(coll.extent_referrers(&settings), 1);
+    assert_eq!(coll.diff.data.last().unwrap().offset, 7);
+}
+
+#[test]
+fn live_extents_should_skip_deleted_posts() {
+    let coll = collection(vec![
+        some_raw_ref("1", "0", 4),
+        some_raw_deleted_ref("2", "0", 8),
+    ]);
+
+    let extents: Vec<_> = coll.live_extents().collect();
+
+    assert_eq!(extents, vec![(shared_settings(), 4)]);
+}
+
+/// Extent of the [some_raw_ref]
+fn shared_settings() -> ChunkSettings {
+    ChunkSettings {
+        chunk_index: 0,
+        offset: 1,
+    }
+}
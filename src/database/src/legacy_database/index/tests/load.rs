@@ -0,0 +1,85 @@
+use pretty_assertions::assert_eq;
+
+use crate::{
+    legacy_database::{
+        config::IndexFormat,
+        index::{
+            db_post_ref::DbPostRef,
+            diff::{Diff, DiffFileError},
+            serialized::IndexCollection,
+            DbRefCollection,
+        },
+    },
+    tests::test_utils::*,
+};
+
+fn refs_of<T: Diff>(coll: &DbRefCollection<T>) -> Vec<(String, DbPostRef)> {
+    coll.iter()
+        .map(|(hash, db_ref)| (hash.to_string(), db_ref.clone()))
+        .collect()
+}
+
+#[test]
+fn from_reader_should_read_same_refs_as_new() {
+    let raw = vec![
+        some_raw_ref("bdd4b5fc1b3a933367bc6830fef72a35", "0", 5),
+        some_raw_ref("2", "bdd4b5fc1b3a933367bc6830fef72a35", 5),
+        some_raw_deleted_ref("3", "2", 10),
+        some_raw_removed_ref("4", "2"),
+    ];
+    let index = serde_json::to_vec(&IndexCollection {
+        indexes: raw.clone(),
+    })
+    .unwrap();
+
+    let read: DbRefCollection<DummyDiff> = DbRefCollection::from_reader(index.as_slice()).unwrap();
+
+    assert_eq!(refs_of(&read), refs_of(&collection(raw)));
+    assert_eq!(read.free_bytes(), 10);
+    assert!(read.is_missing_parent("0"));
+}
+
+#[test]
+fn written_index_should_be_loaded_in_both_formats() {
+    let coll = collection(vec![
+        some_raw_ref("1", "0", 5),
+        some_raw_ref("2", "1", 5),
+        some_raw_deleted_ref("3", "1", 10),
+    ]);
+
+    for format in [IndexFormat::Json, IndexFormat::Binary] {
+        let mut index = Vec::new();
+        assert_eq!(coll.write_index(&mut index, format).unwrap(), 3);
+
+        let read: DbRefCollection<DummyDiff> =
+            DbRefCollection::from_reader(index.as_slice()).unwrap();
+
+        assert_eq!(refs_of(&read), refs_of(&coll), "{:?}", format);
+    }
+}
+
+#[test]
+fn load_should_apply_diff_after_index() {
+    let mut index = Vec::new();
+    collection(vec![some_raw_ref("1", "0", 5)])
+        .write_index(&mut index, IndexFormat::Binary)
+        .unwrap();
+
+    let read: DbRefCollection<DummyDiff> = DbRefCollection::load(index.as_slice(), || {
+        Ok((DummyDiff, vec![some_raw_deleted_ref("1", "0", 5)]))
+    })
+    .unwrap();
+
+    assert!(read.ref_deleted("1"));
+}
+
+#[test]
+fn load_should_not_drain_diff_if_index_is_broken() {
+    let index = br#"{"indexes":[{"h":"1","r":"0""#;
+
+    let read = DbRefCollection::<DummyDiff>::load(&index[..], || -> Result<_, DiffFileError> {
+        panic!("Diff must not be drained")
+    });
+
+    assert!(read.is_err());
+}
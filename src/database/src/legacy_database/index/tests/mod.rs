@@ -1,2 +1,8 @@
+mod dedup;
+mod load;
 mod new;
+mod orphans;
 mod put;
+mod subtree;
+mod sync;
+mod transaction;
@@ -18,8 +18,8 @@ fn when_passed_index_collection_should_create_valid_reference() {
     assert_eq!(reference.reply_refs[&rc("0")], vec![rc("1")]);
     assert_eq!(reference.reply_refs[&rc("1")], vec![rc("2"), rc("3")]);
 
-    assert!(reference.reply_refs.contains_key(&rc("2")) == false);
-    assert!(reference.reply_refs.contains_key(&rc("3")) == false);
+    assert!(!reference.reply_refs.contains_key(&rc("2")));
+    assert!(!reference.reply_refs.contains_key(&rc("3")));
 }
 
 #[test]
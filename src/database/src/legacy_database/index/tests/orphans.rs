@@ -0,0 +1,50 @@
+use pretty_assertions::assert_eq;
+
+use crate::tests::test_utils::*;
+
+#[test]
+fn replies_to_unknown_parent_should_be_orphans() {
+    let coll = collection(vec![
+        some_raw_ref("2", "1", 4),
+        some_raw_ref("3", "2", 4),
+        some_raw_ref("4", "1", 4),
+    ]);
+
+    assert_eq!(coll.list_orphans(), vec![rc("2"), rc("4")]);
+    assert_eq!(coll.missing_parents().collect::<Vec<_>>(), vec![&rc("1")]);
+}
+
+#[test]
+fn arrived_parent_should_resolve_orphans() {
+    let mut coll = collection(vec![some_raw_ref("2", "1", 4)]);
+    assert!(coll.is_missing_parent("1"));
+
+    coll.put_post(some_post("1", "0", "Test")).unwrap();
+
+    assert!(!coll.is_missing_parent("1"));
+    assert_eq!(coll.list_orphans(), vec![rc("1")]);
+    assert_eq!(coll.replies("1"), &[rc("2")]);
+}
+
+#[test]
+fn parent_arriving_later_in_index_should_not_be_missing() {
+    let coll = collection(vec![some_raw_ref("2", "1", 4), some_raw_ref("1", "0", 4)]);
+
+    assert!(!coll.is_missing_parent("1"));
+    assert_eq!(coll.list_orphans(), vec![rc("1")]);
+}
+
+#[test]
+fn rollback_should_restore_missing_parents() {
+    let mut coll = collection(vec![some_raw_ref("2", "1", 4)]);
+
+    coll.begin_transaction().unwrap();
+    coll.put_post(some_post("1", "0", "Test")).unwrap();
+    coll.put_post(some_post("5", "4", "Test")).unwrap();
+    coll.rollback_transaction().unwrap();
+
+    assert!(coll.is_missing_parent("1"));
+    assert!(!coll.is_missing_parent("4"));
+    assert!(!coll.is_missing_parent("0"));
+    assert_eq!(coll.list_orphans(), vec![rc("2")]);
+}
@@ -2,10 +2,13 @@ use pretty_assertions::assert_eq;
 
 use crate::{
     assert_err, assert_ok,
-    legacy_database::index::{
-        db_post_ref::{ChunkSettings, DbPostRef},
-        serialized::PostHashes,
-        DbRefCollectionError,
+    legacy_database::{
+        config::FitStrategy,
+        index::{
+            db_post_ref::{ChunkSettings, DbPostRef},
+            serialized::PostHashes,
+            DbRefCollectionError,
+        },
     },
     post::{Post, PostMessage},
 };
@@ -23,7 +26,7 @@ fn find_free_ref_should_not_return_when_chunk_settings_are_none() {
 
     let collection = collection(vec![ref_1, ref_2, deleted_ref]);
 
-    let free_ref_hash = collection.find_free_ref(&[1, 2, 3, 4, 5]);
+    let free_ref_hash = collection.find_free_ref(5);
 
     assert!(free_ref_hash.is_none());
 }
@@ -36,7 +39,7 @@ fn find_free_ref_should_return_valid_ref_when_there_is_enough_space() {
 
     let collection = collection(vec![ref_1, deleted_ref, removed_ref]);
 
-    let free_hash = collection.find_free_ref(&[1, 2, 3, 4]);
+    let free_hash = collection.find_free_ref(4);
 
     assert!(free_hash.is_some());
     assert_eq!(free_hash.unwrap(), rc("2"));
@@ -50,12 +53,37 @@ fn find_free_ref_should_find_most_suitable() {
 
     let col = collection(vec![ref_1, deleted_1, deleted_2]);
 
-    let free_hash = col.find_free_ref(&[1, 2]);
+    let free_hash = col.find_free_ref(2);
 
     assert!(free_hash.is_some());
     assert_eq!(free_hash.unwrap(), rc("3"));
 }
 
+#[test]
+fn find_free_ref_with_first_fit_should_find_closest_to_start() {
+    let mut deleted_1 = some_raw_deleted_ref("1", "0", 10);
+    deleted_1.offset = 20;
+    let mut deleted_2 = some_raw_deleted_ref("2", "0", 3);
+    deleted_2.offset = 5;
+    let mut deleted_3 = some_raw_deleted_ref("3", "0", 10);
+    deleted_3.offset = 40;
+
+    let mut col = collection(vec![deleted_1, deleted_2, deleted_3]);
+    col.set_fit_strategy(FitStrategy::FirstFit);
+
+    assert_eq!(col.find_free_ref(4).unwrap(), rc("1"));
+}
+
+#[test]
+fn find_free_ref_with_never_reuse_should_not_return() {
+    let deleted_ref = some_raw_deleted_ref("1", "0", 10);
+
+    let mut col = collection(vec![deleted_ref]);
+    col.set_fit_strategy(FitStrategy::NeverReuse);
+
+    assert!(col.find_free_ref(4).is_none());
+}
+
 #[test]
 fn put_post_should_return_empty_chunk_if_no_free_space_was_found() {
     let ref_1 = some_raw_ref("1", "0", 10);
@@ -129,9 +157,9 @@ fn put_post_when_inserts_into_free_space_should_remove_chunk_data_from_free_post
         reply_to: "0".to_string(),
     };
 
-    col.put_post(post);
+    col.put_post(post).unwrap();
 
-    matches!(col.refs[&rc("2")].chunk_settings, None);
+    assert!(col.refs[&rc("2")].chunk_settings.is_none());
     assert_eq!(col.refs[&rc("2")].length, 0);
 }
 
@@ -145,7 +173,7 @@ fn put_post_should_update_diff() {
     expected_ref.length = 4;
     expected_ref.chunk_name = None;
 
-    coll.put_post(post);
+    coll.put_post(post).unwrap();
 
     assert_eq!(coll.diff.data[0], expected_ref)
 }
@@ -0,0 +1,33 @@
+use pretty_assertions::assert_eq;
+
+use crate::tests::test_utils::*;
+
+#[test]
+fn subtree_should_return_post_and_all_nested_replies() {
+    let coll = collection(vec![
+        some_raw_ref("1", "0", 1),
+        some_raw_ref("2", "1", 1),
+        some_raw_ref("3", "2", 1),
+        some_raw_deleted_ref("4", "1", 1),
+        some_raw_ref("5", "0", 1),
+    ]);
+
+    let mut subtree = coll.subtree("1");
+    subtree[1..].sort();
+
+    assert_eq!(subtree, vec![rc("1"), rc("2"), rc("3"), rc("4")]);
+}
+
+#[test]
+fn subtree_should_not_loop_on_cycles() {
+    let coll = collection(vec![some_raw_ref("1", "2", 1), some_raw_ref("2", "1", 1)]);
+
+    assert_eq!(coll.subtree("1"), vec![rc("1"), rc("2")]);
+}
+
+#[test]
+fn subtree_if_post_doesnt_exist_should_be_empty() {
+    let coll = collection(vec![some_raw_ref("2", "1", 1)]);
+
+    assert!(coll.subtree("1").is_empty());
+}
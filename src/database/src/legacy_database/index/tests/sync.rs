@@ -0,0 +1,36 @@
+use pretty_assertions::assert_eq;
+
+use crate::legacy_database::index::sync::{SyncSummary, DEFAULT_FALSE_POSITIVE_RATE};
+
+use crate::tests::test_utils::*;
+
+#[test]
+fn missing_from_should_list_live_posts_peer_lacks() {
+    let coll = collection(vec![
+        some_raw_ref("1", "0", 4),
+        some_raw_ref("2", "1", 4),
+        some_raw_deleted_ref("3", "1", 4),
+        some_raw_ref("4", "1", 4),
+    ]);
+    let peer = collection(vec![some_raw_ref("2", "1", 4)]);
+
+    let missing = coll.missing_from(&peer.sync_summary());
+
+    assert_eq!(missing, vec![rc("1"), rc("4")]);
+}
+
+#[test]
+fn missing_from_should_skip_posts_deleted_by_peer() {
+    let coll = collection(vec![some_raw_ref("1", "0", 4)]);
+    let peer = collection(vec![some_raw_removed_ref("1", "0")]);
+
+    assert!(coll.missing_from(&peer.sync_summary()).is_empty());
+}
+
+#[test]
+fn missing_from_empty_summary_should_list_all_live_posts() {
+    let coll = collection(vec![some_raw_ref("1", "0", 4), some_raw_ref("2", "1", 4)]);
+    let peer = SyncSummary::with_capacity(0, DEFAULT_FALSE_POSITIVE_RATE);
+
+    assert_eq!(coll.missing_from(&peer), vec![rc("1"), rc("2")]);
+}
@@ -0,0 +1,82 @@
+use pretty_assertions::assert_eq;
+
+use crate::{
+    assert_err,
+    legacy_database::index::{db_post_ref::ChunkSettings, DbRefCollectionError},
+};
+
+use crate::tests::test_utils::*;
+
+#[test]
+fn changes_should_not_be_written_into_diff_before_commit() {
+    let mut coll = collection_with_diff(vec![]);
+
+    coll.begin_transaction().unwrap();
+    coll.put_post(some_post("20", "1", "Test")).unwrap();
+    coll.mark_post_as_deleted("1").unwrap();
+
+    assert!(coll.diff.data.is_empty());
+}
+
+#[test]
+fn commit_should_write_current_state_of_changed_refs() {
+    let mut coll = collection_with_diff(vec![]);
+    let settings = ChunkSettings {
+        chunk_index: 3,
+        offset: 42,
+    };
+
+    coll.begin_transaction().unwrap();
+    coll.put_post(some_post("20", "1", "Test")).unwrap();
+    coll.get_ref_mut("20").unwrap().chunk_settings = Some(settings);
+    coll.commit_transaction().unwrap();
+
+    let mut expected_ref = some_raw_ref("20", "1", 4);
+    expected_ref.chunk_name = Some("3.db3".to_string());
+    expected_ref.offset = 42;
+    assert_eq!(coll.diff.data, vec![expected_ref]);
+}
+
+#[test]
+fn rollback_should_remove_inserted_refs() {
+    let mut coll = collection(vec![some_raw_ref("1", "0", 10)]);
+
+    coll.begin_transaction().unwrap();
+    coll.put_post(some_post("2", "1", "Test")).unwrap();
+    coll.rollback_transaction().unwrap();
+
+    assert!(!coll.ref_exists("2"));
+    assert_eq!(coll.ordered, vec![rc("1")]);
+    assert!(!coll.reply_refs.contains_key(&rc("1")));
+}
+
+#[test]
+fn rollback_should_restore_reused_free_ref() {
+    let deleted_ref = some_raw_deleted_ref("1", "0", 10);
+    let mut coll = collection(vec![deleted_ref]);
+    let original = coll.get_ref("1").unwrap().clone();
+
+    coll.begin_transaction().unwrap();
+    coll.put_post(some_post("2", "0", "Test")).unwrap();
+    coll.rollback_transaction().unwrap();
+
+    assert_eq!(coll.get_ref("1").unwrap(), &original);
+    assert!(coll.free.contains(&rc("1")));
+}
+
+#[test]
+fn commit_without_transaction_should_return_error() {
+    let mut coll = collection(vec![]);
+
+    let result = coll.commit_transaction();
+    assert_err!(result, DbRefCollectionError::NoActiveTransaction)
+}
+
+#[test]
+fn begin_transaction_twice_should_return_error() {
+    let mut coll = collection(vec![]);
+    coll.begin_transaction().unwrap();
+
+    let result = coll.begin_transaction();
+    assert_err!(result, DbRefCollectionError::TransactionAlreadyStarted)
+}
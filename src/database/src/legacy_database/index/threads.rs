@@ -0,0 +1,379 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+};
+
+use crate::legacy_database::database::check::ROOT_PARENT_HASH;
+
+/// Tag which makes the reply not bump its thread
+pub const SAGE_TAG: &str = "[sage]";
+
+/// Bump time of the thread: time of its last bumping reply, with the number of the reply to order equal times
+type BumpKey = (u64, u64);
+
+/// Threads of the categories in bump order, kept up to date on every put and delete.
+///
+/// Category is a well-known root post, thread is a direct reply to it, and every post under the thread bumps it,
+/// unless its message carries the [SAGE_TAG]. Posts outside of the categories are ignored.
+#[derive(Debug, Default)]
+pub struct ThreadIndex {
+    categories: HashSet<String>,
+
+    /// Posts which have no parent by design, in addition to the replies to [ROOT_PARENT_HASH]
+    roots: HashSet<String>,
+
+    /// `Key` is a category, `value` is its threads, last bumped first
+    bump_order: HashMap<String, BTreeSet<(Reverse<BumpKey>, String)>>,
+
+    /// `Key` is a thread hash
+    threads: HashMap<String, Thread>,
+
+    /// `Key` is a hash of a post under a category, `value` is the thread it belongs to
+    post_threads: HashMap<String, String>,
+
+    /// Replies inserted before their parent, attached to the thread once the parent is inserted.
+    /// `Key` is the parent hash. Replies are dropped once they're known to be outside of the categories,
+    /// or when they're removed, so only replies to the posts which are missing from the database stay here.
+    pending: HashMap<String, Vec<PendingReply>>,
+
+    /// `Key` is a hash of a pending reply, `value` is its parent
+    waiting: HashMap<String, String>,
+
+    /// Number of the posts inserted so far
+    sequence: u64,
+}
+
+#[derive(Debug)]
+struct Thread {
+    category: String,
+    bumped_at: BumpKey,
+
+    /// Unset for removed threads, which are still bumped by replies in case they are restored
+    listed: bool,
+}
+
+#[derive(Debug)]
+struct PendingReply {
+    hash: String,
+
+    /// `None` if the reply doesn't bump its thread
+    bump: Option<BumpKey>,
+}
+
+impl ThreadIndex {
+    pub fn new(categories: impl IntoIterator<Item = String>) -> Self {
+        ThreadIndex {
+            categories: categories.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the posts which have no parent by design, e.g. [`LegacyDatabaseConfig::roots`].
+    /// They start trees outside of the categories, so replies to them are not kept waiting.
+    ///
+    /// [`LegacyDatabaseConfig::roots`]: crate::legacy_database::config::LegacyDatabaseConfig::roots
+    pub fn with_roots(mut self, roots: impl IntoIterator<Item = String>) -> Self {
+        self.roots = roots.into_iter().collect();
+        self
+    }
+
+    pub fn is_category(&self, hash: &str) -> bool {
+        self.categories.contains(hash)
+    }
+
+    /// Whether the post is under a category or waits for its parent
+    pub fn is_indexed(&self, hash: &str) -> bool {
+        self.post_threads.contains_key(hash) || self.waiting.contains_key(hash)
+    }
+
+    /// Number of the replies waiting for their parent
+    pub fn waiting_replies(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Adds the post received at `time`, bumping its thread if `bump` is set.
+    /// Inserting a thread again, e.g. when it's restored, puts it back into its category with its last bump time.
+    ///
+    /// Reply whose parent is not inserted yet waits for it, then it's attached and bumps the thread with its own time.
+    pub fn insert(&mut self, hash: &str, parent: &str, time: u64, bump: bool) {
+        self.sequence += 1;
+        let key = (time, self.sequence);
+        if self.is_category(parent) {
+            self.post_threads.insert(hash.to_string(), hash.to_string());
+            self.unlist(hash);
+            let thread = self.threads.entry(hash.to_string()).or_insert(Thread {
+                category: parent.to_string(),
+                bumped_at: key,
+                listed: true,
+            });
+            thread.listed = true;
+            self.list(hash);
+            self.attach_pending(hash);
+            return;
+        }
+
+        if parent == ROOT_PARENT_HASH || self.roots.contains(hash) {
+            self.skip(hash);
+            return;
+        }
+
+        let bump = bump.then_some(key);
+        match self.post_threads.get(parent).cloned() {
+            Some(thread) => {
+                self.attach(hash, &thread, bump);
+                self.attach_pending(hash);
+            }
+            None => {
+                self.waiting.insert(hash.to_string(), parent.to_string());
+                self.pending
+                    .entry(parent.to_string())
+                    .or_default()
+                    .push(PendingReply {
+                        hash: hash.to_string(),
+                        bump,
+                    })
+            }
+        }
+    }
+
+    /// Ignores the post which is known to be outside of the categories, e.g. a reply to a post which is in the
+    /// database, but not in the index. Replies waiting for it are outside too, so they're dropped.
+    pub fn skip(&mut self, hash: &str) {
+        let mut parents = vec![hash.to_string()];
+        while let Some(parent) = parents.pop() {
+            for reply in self.pending.remove(&parent).unwrap_or_default() {
+                self.waiting.remove(&reply.hash);
+                parents.push(reply.hash);
+            }
+        }
+    }
+
+    /// Removes the thread from its category. Replies are kept, so the thread is bumped by them if it's restored.
+    /// Removed pending reply is dropped along with the replies waiting for it, it's inserted again if it's restored.
+    pub fn remove(&mut self, hash: &str) {
+        if let Some(parent) = self.waiting.remove(hash) {
+            if let Some(replies) = self.pending.get_mut(&parent) {
+                replies.retain(|reply| reply.hash != hash);
+                if replies.is_empty() {
+                    self.pending.remove(&parent);
+                }
+            }
+            self.skip(hash);
+            return;
+        }
+
+        if self.unlist(hash) {
+            self.threads.get_mut(hash).unwrap().listed = false;
+        }
+    }
+
+    /// Returns threads of the category, last bumped first
+    pub fn threads(&self, category: &str) -> Vec<String> {
+        self.bump_order
+            .get(category)
+            .into_iter()
+            .flatten()
+            .map(|(_, hash)| hash.clone())
+            .collect()
+    }
+
+    /// Returns threads of all categories in no particular order
+    pub fn all_threads(&self) -> impl Iterator<Item = &String> {
+        self.bump_order.values().flatten().map(|(_, hash)| hash)
+    }
+
+    /// Returns the thread the post belongs to, which is the post itself for threads
+    pub fn thread_of(&self, hash: &str) -> Option<&str> {
+        self.post_threads.get(hash).map(String::as_str)
+    }
+
+    /// Adds the reply to the thread, bumping it if the reply is newer than the last bump
+    fn attach(&mut self, hash: &str, thread: &str, bump: Option<BumpKey>) {
+        self.post_threads
+            .insert(hash.to_string(), thread.to_string());
+        let key = match bump {
+            Some(key) => key,
+            None => return,
+        };
+        if self.threads.get(thread).is_none_or(|t| t.bumped_at >= key) {
+            return;
+        }
+
+        let listed = self.unlist(thread);
+        self.threads.get_mut(thread).unwrap().bumped_at = key;
+        if listed {
+            self.list(thread);
+        }
+    }
+
+    /// Attaches the replies which were waiting for the post, recursively
+    fn attach_pending(&mut self, hash: &str) {
+        let thread = self.post_threads[hash].clone();
+        let mut parents = vec![hash.to_string()];
+        while let Some(parent) = parents.pop() {
+            for reply in self.pending.remove(&parent).unwrap_or_default() {
+                self.waiting.remove(&reply.hash);
+                self.attach(&reply.hash, &thread, reply.bump);
+                parents.push(reply.hash);
+            }
+        }
+    }
+
+    fn list(&mut self, hash: &str) {
+        let thread = &self.threads[hash];
+        self.bump_order
+            .entry(thread.category.clone())
+            .or_default()
+            .insert((Reverse(thread.bumped_at), hash.to_string()));
+    }
+
+    /// Takes the thread out of the bump order
+    /// # Returns
+    /// `true` if the thread was listed
+    fn unlist(&mut self, hash: &str) -> bool {
+        match self.threads.get(hash) {
+            Some(thread) if thread.listed => {
+                if let Some(order) = self.bump_order.get_mut(&thread.category) {
+                    order.remove(&(Reverse(thread.bumped_at), hash.to_string()));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Checks if the reply must not bump its thread
+pub fn is_sage(message: &str) -> bool {
+    message.contains(SAGE_TAG)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> ThreadIndex {
+        ThreadIndex::new(vec!["b".to_string(), "d".to_string()])
+    }
+
+    #[test]
+    fn threads_should_be_ordered_by_last_bump() {
+        let mut index = index();
+        index.insert("1", "b", 10, true);
+        index.insert("2", "b", 20, true);
+        index.insert("3", "d", 30, true);
+
+        index.insert("4", "1", 40, true);
+
+        assert_eq!(index.threads("b"), vec!["1", "2"]);
+        assert_eq!(index.threads("d"), vec!["3"]);
+    }
+
+    #[test]
+    fn sage_reply_should_not_bump_thread() {
+        let mut index = index();
+        index.insert("1", "b", 10, true);
+        index.insert("2", "b", 20, true);
+
+        index.insert("3", "1", 30, false);
+        index.insert("4", "3", 40, true);
+
+        assert_eq!(index.threads("b"), vec!["1", "2"]);
+        assert_eq!(index.thread_of("4"), Some("1"));
+    }
+
+    #[test]
+    fn older_reply_should_not_move_thread_back() {
+        let mut index = index();
+        index.insert("1", "b", 30, true);
+        index.insert("2", "b", 20, true);
+
+        index.insert("3", "1", 10, true);
+
+        assert_eq!(index.threads("b"), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn removed_thread_should_be_bumped_by_replies_when_restored() {
+        let mut index = index();
+        index.insert("1", "b", 10, true);
+        index.insert("2", "b", 20, true);
+        index.insert("3", "1", 30, true);
+
+        index.remove("1");
+        assert_eq!(index.threads("b"), vec!["2"]);
+        index.insert("1", "b", 10, true);
+
+        assert_eq!(index.threads("b"), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn replies_received_before_parent_should_bump_thread_once_it_arrives() {
+        let mut index = index();
+        index.insert("1", "b", 10, true);
+        index.insert("2", "b", 20, true);
+        index.insert("4", "3", 40, true);
+        index.insert("5", "4", 50, false);
+        assert_eq!(index.thread_of("4"), None);
+
+        index.insert("3", "1", 30, false);
+
+        assert_eq!(index.threads("b"), vec!["1", "2"]);
+        assert_eq!(index.thread_of("5"), Some("1"));
+        assert!(index.pending.is_empty());
+    }
+
+    #[test]
+    fn posts_outside_of_categories_should_be_ignored() {
+        let mut index = index();
+
+        index.insert("1", "x", 10, true);
+        index.insert("2", "1", 20, true);
+
+        assert!(index.threads("x").is_empty());
+        assert_eq!(index.thread_of("2"), None);
+    }
+
+    #[test]
+    fn replies_to_roots_should_not_wait() {
+        let mut index = index().with_roots(vec!["r".to_string()]);
+        index.insert("2", "1", 20, true);
+        index.insert("3", "r", 30, true);
+
+        index.insert("1", ROOT_PARENT_HASH, 10, true);
+        index.insert("r", "x", 5, true);
+
+        assert_eq!(index.waiting_replies(), 0);
+        assert!(index.pending.is_empty());
+    }
+
+    #[test]
+    fn removed_pending_reply_should_be_dropped_with_its_replies() {
+        let mut index = index();
+        index.insert("1", "b", 10, true);
+        index.insert("3", "2", 30, true);
+        index.insert("4", "3", 40, true);
+        index.insert("5", "2", 50, true);
+
+        index.remove("3");
+        assert_eq!(index.waiting_replies(), 1);
+        index.insert("2", "1", 20, false);
+
+        assert_eq!(index.thread_of("4"), None);
+        assert_eq!(index.thread_of("5"), Some("1"));
+        assert!(index.pending.is_empty());
+    }
+
+    #[test]
+    fn skip_should_drop_replies_waiting_for_the_post() {
+        let mut index = index();
+        index.insert("2", "1", 20, true);
+        index.insert("3", "2", 30, true);
+
+        index.skip("1");
+
+        assert!(!index.is_indexed("2"));
+        assert!(!index.is_indexed("3"));
+        assert!(index.pending.is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+use std::{collections::HashMap, io, string::FromUtf8Error};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::json_lines::{JsonLinesError, JsonLinesFile};
+use crate::{
+    legacy_database::crypto::Cipher,
+    post::{Post, PostMessage},
+};
+
+pub const TRASH_FILENAME: &str = "trash-3.list";
+
+#[derive(Debug, Error)]
+pub enum TrashError {
+    #[error("IO error")]
+    IoError(#[from] io::Error),
+
+    #[error("Error (de)serializing trash entry")]
+    SerdeError(#[from] serde_json::Error),
+
+    #[error("Trash line {line_no} is corrupt")]
+    Corrupt { line_no: usize },
+
+    #[error("Message of the trashed post is not valid UTF-8")]
+    InvalidMessage(#[from] FromUtf8Error),
+}
+
+impl From<JsonLinesError> for TrashError {
+    fn from(err: JsonLinesError) -> Self {
+        match err {
+            JsonLinesError::IoError(err) => Self::IoError(err),
+            JsonLinesError::SerdeError(err) => Self::SerdeError(err),
+            JsonLinesError::Corrupt { line_no } => Self::Corrupt { line_no },
+        }
+    }
+}
+
+pub type TrashResult<T> = Result<T, TrashError>;
+
+/// Deleted post kept in the trash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub hash: String,
+
+    /// Hash of the parent post
+    pub reply_to: String,
+
+    /// Message bytes as they were read from the chunk, kept as is even if they're not valid UTF-8
+    pub message: Vec<u8>,
+
+    /// Unix time in seconds when the post was deleted
+    pub deleted_at: u64,
+}
+
+impl TrashEntry {
+    /// Post the entry is restored as
+    /// # Errors
+    /// [TrashError::InvalidMessage] if the message is not valid UTF-8, e.g. it was damaged in the chunk
+    pub fn to_post(&self) -> TrashResult<Post> {
+        Ok(Post {
+            hash: self.hash.clone(),
+            reply_to: self.reply_to.clone(),
+            message: PostMessage::from_bytes(self.message.clone())?,
+        })
+    }
+}
+
+/// Line of the trash file. Line without message removes the post from the trash.
+#[derive(Serialize, Deserialize, Debug)]
+struct TrashEntrySerialized {
+    /// Post hash
+    #[serde(rename = "h")]
+    hash: String,
+
+    /// Hash of the parent post
+    #[serde(rename = "r", default, skip_serializing_if = "String::is_empty")]
+    reply_to: String,
+
+    /// Unix time in seconds when the post was deleted
+    #[serde(rename = "t", default)]
+    deleted_at: u64,
+
+    /// Base64 encoded message
+    #[serde(
+        rename = "m",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "base64_message"
+    )]
+    message: Option<Vec<u8>>,
+}
+
+/// Messages of deleted posts, persisted as an append-only list of JSON lines in `trash-3.list`.
+/// Trash created with [`Default`] is kept in memory only.
+#[derive(Debug, Default)]
+pub struct Trash {
+    entries: HashMap<String, TrashEntry>,
+    lines: JsonLinesFile<TrashEntrySerialized>,
+}
+
+impl Trash {
+    /// Reads `trash-3.list`, creating it if it doesn't exist.
+    /// A torn last line, left by an interrupted write, is ignored.
+    /// # Errors
+    /// [TrashError::Corrupt] if any other line can't be parsed
+    pub fn open() -> TrashResult<Self> {
+        Self::open_with(None)
+    }
+
+    /// Same as [Trash::open], but every line is sealed by the cipher and stored base64 encoded.
+    /// Line which can't be decrypted is corrupt.
+    pub fn open_encrypted(cipher: Cipher) -> TrashResult<Self> {
+        Self::open_with(Some(cipher))
+    }
+
+    fn open_with(cipher: Option<Cipher>) -> TrashResult<Self> {
+        let (lines, serialized): (_, Vec<TrashEntrySerialized>) =
+            JsonLinesFile::open(TRASH_FILENAME, cipher)?;
+        let mut entries = HashMap::new();
+        for serialized in serialized {
+            match serialized.split() {
+                (hash, Some(entry)) => entries.insert(hash, entry),
+                (hash, None) => entries.remove(&hash),
+            };
+        }
+
+        Ok(Self { entries, lines })
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&TrashEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Hashes of the posts in the trash
+    pub fn hashes(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    /// Puts several posts into the trash with a single write.
+    /// If the write fails, posts are still in the trash in memory and they're written by the next change or flush.
+    pub fn append(&mut self, entries: Vec<TrashEntry>) -> TrashResult<()> {
+        let serialized: Vec<TrashEntrySerialized> = entries
+            .iter()
+            .map(|entry| TrashEntrySerialized::new(entry.hash.clone(), Some(entry)))
+            .collect();
+        self.entries
+            .extend(entries.into_iter().map(|entry| (entry.hash.clone(), entry)));
+        Ok(self.lines.append(serialized)?)
+    }
+
+    /// Removes the post from the trash. Failed write is retried like in [Trash::append].
+    pub fn take(&mut self, hash: &str) -> TrashResult<Option<TrashEntry>> {
+        let entry = match self.entries.remove(hash) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        self.lines
+            .append([TrashEntrySerialized::new(hash.to_string(), None)])?;
+        Ok(Some(entry))
+    }
+
+    /// Removes posts deleted before `deleted_before` (unix time in seconds) and rewrites the trash file.
+    /// # Returns
+    /// Hashes of the removed posts
+    pub fn purge(&mut self, deleted_before: u64) -> TrashResult<Vec<String>> {
+        let mut purged: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.deleted_at < deleted_before)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        purged.sort();
+
+        for hash in &purged {
+            self.entries.remove(hash);
+        }
+        let serialized = self
+            .entries
+            .iter()
+            .map(|(hash, entry)| TrashEntrySerialized::new(hash.clone(), Some(entry)));
+        self.lines.compact(serialized)?;
+
+        Ok(purged)
+    }
+
+    pub fn sync(&mut self) -> TrashResult<()> {
+        Ok(self.lines.sync()?)
+    }
+
+    /// Writes changes which failed to be written before
+    pub fn flush(&mut self) -> TrashResult<()> {
+        Ok(self.lines.flush()?)
+    }
+}
+
+impl TrashEntrySerialized {
+    fn new(hash: String, entry: Option<&TrashEntry>) -> Self {
+        match entry {
+            Some(entry) => Self {
+                hash,
+                reply_to: entry.reply_to.clone(),
+                deleted_at: entry.deleted_at,
+                message: Some(entry.message.clone()),
+            },
+            None => Self {
+                hash,
+                reply_to: String::new(),
+                deleted_at: 0,
+                message: None,
+            },
+        }
+    }
+
+    fn split(self) -> (String, Option<TrashEntry>) {
+        let message = match self.message {
+            Some(message) => message,
+            None => return (self.hash, None),
+        };
+
+        let entry = TrashEntry {
+            hash: self.hash.clone(),
+            reply_to: self.reply_to,
+            message,
+            deleted_at: self.deleted_at,
+        };
+        (self.hash, Some(entry))
+    }
+}
+
+/// (De)serializes the message as a base64 string
+mod base64_message {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        message: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match message {
+            Some(message) => serializer.serialize_some(&base64::encode(message)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|message| base64::decode(message).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::in_temp_dir;
+    use rusty_fork::rusty_fork_test;
+    use std::fs;
+
+    #[test]
+    fn purge_should_remove_old_entries_only() {
+        let mut trash = Trash::default();
+        trash
+            .append(vec![entry("1", 10), entry("2", 20), entry("3", 30)])
+            .unwrap();
+
+        let purged = trash.purge(25).unwrap();
+
+        assert_eq!(purged, vec!["1".to_string(), "2".to_string()]);
+        assert!(trash.get("1").is_none());
+        assert_eq!(trash.get("3"), Some(&entry("3", 30)));
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn open_should_restore_entries_which_were_not_taken() {
+            in_temp_dir!({
+                let mut trash = Trash::open().unwrap();
+                trash.append(vec![entry("1", 10), entry("2", 20)]).unwrap();
+                assert_eq!(trash.take("1").unwrap(), Some(entry("1", 10)));
+
+                let trash = Trash::open().unwrap();
+
+                assert!(trash.get("1").is_none());
+                assert_eq!(trash.get("2"), Some(&entry("2", 20)));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn purge_should_rewrite_trash_file() {
+            in_temp_dir!({
+                let mut trash = Trash::open().unwrap();
+                trash.append(vec![entry("1", 10), entry("2", 20)]).unwrap();
+
+                trash.purge(15).unwrap();
+                trash.append(vec![entry("3", 30)]).unwrap();
+
+                let contents = fs::read_to_string(TRASH_FILENAME).unwrap();
+                assert_eq!(contents.lines().count(), 2);
+                let trash = Trash::open().unwrap();
+                assert_eq!(trash.get("3"), Some(&entry("3", 30)));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn encrypted_trash_should_be_read_with_the_same_key_only() {
+            in_temp_dir!({
+                let cipher = Cipher::from_key([1; 32]);
+                let mut trash = Trash::open_encrypted(cipher.clone()).unwrap();
+                trash.append(vec![entry("1", 10), entry("2", 20)]).unwrap();
+
+                let contents = fs::read_to_string(TRASH_FILENAME).unwrap();
+                assert!(!contents.contains("\"1\""));
+                assert!(matches!(
+                    Trash::open_encrypted(Cipher::from_key([2; 32])),
+                    Err(TrashError::Corrupt { line_no: 1 })
+                ));
+                let trash = Trash::open_encrypted(cipher).unwrap();
+                assert_eq!(trash.get("2"), Some(&entry("2", 20)));
+            });
+        }
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn open_should_keep_messages_which_are_not_utf8() {
+            in_temp_dir!({
+                let mut damaged = entry("1", 10);
+                damaged.message = vec![0xc3, 0x28];
+                Trash::open().unwrap().append(vec![damaged.clone()]).unwrap();
+
+                let trash = Trash::open().unwrap();
+
+                assert_eq!(trash.get("1"), Some(&damaged));
+                assert!(matches!(
+                    damaged.to_post(),
+                    Err(TrashError::InvalidMessage(_))
+                ));
+            });
+        }
+    }
+
+    fn entry(hash: &str, deleted_at: u64) -> TrashEntry {
+        TrashEntry {
+            hash: hash.to_string(),
+            reply_to: "0".to_string(),
+            message: b"message".to_vec(),
+            deleted_at,
+        }
+    }
+}
@@ -1,3 +1,5 @@
 pub mod chunk;
+pub mod config;
+pub mod crypto;
 pub mod database;
 pub mod index;
@@ -1,16 +1,56 @@
-use std::string::FromUtf8Error;
+use std::{
+    collections::BTreeSet,
+    string::FromUtf8Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Clone)]
+use thiserror::Error;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Post {
     pub hash: String,
     pub reply_to: String,
-    /// Base64 encoded post message
     pub message: PostMessage,
 }
 
+/// Post as it's stored in the database
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PostEntry {
+    Live(Post),
+
+    /// Post was deleted locally, its message is gone. Shown as a tombstone and never put into containers.
+    Deleted {
+        hash: String,
+        parent: String,
+    },
+}
+
+/// Post message text. Stored as is, base64 is used only when the message is (de)serialized.
 #[derive(Clone, PartialEq, Eq, Debug, Hash)]
 pub struct PostMessage(String);
 
+/// Locally known information about a post, which is not a part of the post itself
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PostMetadata {
+    /// Unix time in seconds when the post was first seen locally
+    pub received_at: u64,
+
+    /// Container (e.g. image file name) the post was extracted from
+    pub source: Option<String>,
+
+    /// Local flags set by the moderators, e.g. `pinned`
+    pub flags: BTreeSet<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum PostMessageError {
+    #[error("Message is not a valid base64 string")]
+    Base64Error(#[from] base64::DecodeError),
+
+    #[error("Message is not a valid UTF-8 string")]
+    Utf8Error(#[from] FromUtf8Error),
+}
+
 impl Post {
     pub fn new(hash: String, reply_to: String, raw_message: String) -> Self {
         Self {
@@ -20,14 +60,42 @@ impl Post {
         }
     }
 
-    pub fn get_message_bytes(&self) -> Vec<u8> {
-        self.message.get_bytes()
+    pub fn get_message_bytes(&self) -> &[u8] {
+        self.message.as_bytes()
+    }
+}
+
+impl PostEntry {
+    pub fn hash(&self) -> &str {
+        match self {
+            PostEntry::Live(post) => &post.hash,
+            PostEntry::Deleted { hash, .. } => hash,
+        }
+    }
+
+    pub fn parent(&self) -> &str {
+        match self {
+            PostEntry::Live(post) => &post.reply_to,
+            PostEntry::Deleted { parent, .. } => parent,
+        }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        matches!(self, PostEntry::Deleted { .. })
+    }
+
+    /// Returns the post if it's not deleted
+    pub fn into_live(self) -> Option<Post> {
+        match self {
+            PostEntry::Live(post) => Some(post),
+            PostEntry::Deleted { .. } => None,
+        }
     }
 }
 
 impl PostMessage {
     pub fn new(raw_message: String) -> Self {
-        PostMessage(base64::encode(raw_message))
+        PostMessage(raw_message)
     }
 
     pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, FromUtf8Error> {
@@ -35,7 +103,68 @@ impl PostMessage {
         Ok(Self::new(utf8))
     }
 
-    pub fn get_bytes(&self) -> Vec<u8> {
-        base64::decode(&self.0).unwrap()
+    pub fn from_base64(encoded: &str) -> Result<Self, PostMessageError> {
+        let bytes = base64::decode(encoded)?;
+        Ok(Self::from_bytes(bytes)?)
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl PostMetadata {
+    /// Metadata of the post received right now
+    pub fn received_now() -> Self {
+        Self {
+            received_at: unix_time_now(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Current unix time in seconds
+pub(crate) fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_should_roundtrip_message() {
+        let message = PostMessage::new("[g]Привет[/g]".to_string());
+
+        let decoded = PostMessage::from_base64(&message.to_base64()).unwrap();
+
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn from_base64_should_fail_on_invalid_input() {
+        assert!(matches!(
+            PostMessage::from_base64("not base64!"),
+            Err(PostMessageError::Base64Error(_))
+        ));
+        assert!(matches!(
+            PostMessage::from_base64(&base64::encode([0xc3, 0x28])),
+            Err(PostMessageError::Utf8Error(_))
+        ));
     }
 }
@@ -1,11 +1,59 @@
-use crate::post::Post;
+use crate::post::{Post, PostEntry, PostMetadata};
 use std::error::Error;
 
+/// Outcome of [`Database::put_posts`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PutPostsReport {
+    /// Hashes of the posts which were written into the database, in batch order
+    pub inserted: Vec<String>,
+
+    /// Hashes of the posts which were skipped because they already exist in the database
+    /// (or were already seen earlier in the same batch)
+    pub duplicates: Vec<String>,
+
+    /// Hashes of the posts which were skipped because they are banned
+    pub banned: Vec<String>,
+}
+
 pub trait Database {
     type Error: Error;
 
     fn put_post(&mut self, post: Post) -> Result<(), Self::Error>;
+
+    /// Inserts a batch of posts as a single unit.
+    /// Either every non-duplicate post is inserted, or, if an error is returned, none of them are.
+    fn put_posts(&mut self, posts: Vec<Post>) -> Result<PutPostsReport, Self::Error>;
+
+    /// Same as [`Database::put_posts`], but `source`, e.g. the container the posts were extracted from,
+    /// is recorded in the metadata of the inserted posts along with them.
+    fn put_posts_from(
+        &mut self,
+        posts: Vec<Post>,
+        source: &str,
+    ) -> Result<PutPostsReport, Self::Error>;
+
     fn update_post(&mut self, post: Post) -> Result<(), Self::Error>;
-    fn get_post(&self, hash: String) -> Result<Option<Post>, Self::Error>;
+
+    /// Returns the post, or its tombstone if it was deleted. `None` if the post is unknown.
+    fn get_post(&self, hash: String) -> Result<Option<PostEntry>, Self::Error>;
     fn delete_post(&mut self, hash: String) -> Result<(), Self::Error>;
+
+    /// Deletes the post and all of its replies, recursively, as a single unit.
+    /// # Returns
+    /// Hashes of the posts which were deleted, already deleted ones are not included
+    fn delete_thread(&mut self, root_hash: String) -> Result<Vec<String>, Self::Error>;
+
+    /// Restores the deleted post, if its message is still kept locally.
+    fn restore_post(&mut self, hash: String) -> Result<(), Self::Error>;
+
+    /// Returns locally known information about the post, `None` if there is none.
+    /// Receive time is recorded automatically when the post is inserted.
+    fn get_post_metadata(&self, hash: String) -> Result<Option<PostMetadata>, Self::Error>;
+
+    /// Replaces metadata of the existing post, e.g. to record its source container or set flags.
+    fn set_post_metadata(
+        &mut self,
+        hash: String,
+        metadata: PostMetadata,
+    ) -> Result<(), Self::Error>;
 }
@@ -4,14 +4,14 @@ use crate::tests::test_utils::*;
 use crate::{
     legacy_database::{
         self,
-        chunk::{chunk_processor::ChunkCollectionProcessor, ChunkError},
+        chunk::{chunk_processor::ChunkCollectionProcessor, ChunkError, ChunkIndex},
         index::{
             db_post_ref::{ChunkSettings, DbPostRef},
             diff::Diff,
             serialized::{DbPostRefSerialized, PostHashes},
         },
     },
-    post::{Post, PostMessage},
+    post::PostMessage,
 };
 
 pub struct CollectingDiffWithData {
@@ -28,6 +28,26 @@ impl Diff for CollectingDiffWithData {
         Ok(())
     }
 
+    fn append_batch(
+        &mut self,
+        refs: &[DbPostRefSerialized],
+    ) -> legacy_database::index::diff::DiffResult<()> {
+        self.data.extend_from_slice(refs);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> legacy_database::index::diff::DiffResult<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> legacy_database::index::diff::DiffResult<u64> {
+        let mut size = 0;
+        for db_ref in &self.data {
+            size += db_ref.serialize()?.len() as u64 + 1;
+        }
+        Ok(size)
+    }
+
     fn drain() -> legacy_database::index::diff::DiffResult<(Self, Vec<DbPostRefSerialized>)> {
         let ref_1 = some_raw_ref("1", "0", 10);
         let ref_2 = some_raw_ref("2", "1", 5);
@@ -41,20 +61,33 @@ impl Diff for CollectingDiffWithData {
 }
 
 pub struct CollectingChunkProcessor {
-    pub data: HashMap<ChunkSettings, PostMessage>,
+    pub data: HashMap<ChunkSettings, Vec<u8>>,
     pub offset: u64,
+
+    /// If set, `insert` fails once this many messages are collected
+    pub capacity: Option<usize>,
+
+    /// How many times `sync` was called
+    pub syncs: usize,
+
+    /// Sizes returned by `chunk_size`, chunks which are not listed don't exist
+    pub chunk_sizes: HashMap<ChunkIndex, u64>,
 }
 
 impl ChunkCollectionProcessor for CollectingChunkProcessor {
     type Error = ChunkError;
 
-    fn insert(&mut self, post: &PostMessage) -> Result<ChunkSettings, Self::Error> {
+    fn insert(&mut self, post: &[u8]) -> Result<ChunkSettings, Self::Error> {
+        if self.capacity.is_some_and(|cap| self.data.len() >= cap) {
+            return Err(ChunkError::ChunkTooLarge);
+        }
+
         let sets = ChunkSettings {
             chunk_index: 0,
             offset: self.offset,
         };
-        self.data.insert(sets.clone(), post.clone());
-        self.offset += post.get_bytes().len() as u64;
+        self.data.insert(sets.clone(), post.to_vec());
+        self.offset += post.len() as u64;
 
         Ok(sets)
     }
@@ -62,18 +95,50 @@ impl ChunkCollectionProcessor for CollectingChunkProcessor {
     fn insert_into_existing(
         &mut self,
         chunk: &ChunkSettings,
-        post: &PostMessage,
+        post: &[u8],
     ) -> Result<(), Self::Error> {
-        self.data.insert(chunk.clone(), post.clone());
+        self.data.insert(chunk.clone(), post.to_vec());
         Ok(())
     }
 
     fn get_message(&self, chunk: &ChunkSettings, _len: u64) -> Result<PostMessage, Self::Error> {
-        Ok(self.data.get(&chunk).unwrap().clone())
+        Ok(PostMessage::from_bytes(self.data.get(chunk).unwrap().clone()).unwrap())
+    }
+
+    fn get_bytes(&self, chunk: &ChunkSettings, len: u64) -> Result<Vec<u8>, Self::Error> {
+        match self.data.get(chunk) {
+            Some(bytes) => Ok(bytes.clone()),
+            None => Ok(vec![0; len as usize]),
+        }
+    }
+
+    fn chunk_size(&self, chunk_index: ChunkIndex) -> Result<Option<u64>, Self::Error> {
+        Ok(self.chunk_sizes.get(&chunk_index).copied())
+    }
+
+    fn read_range(
+        &self,
+        chunk_index: ChunkIndex,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Self::Error> {
+        let settings = ChunkSettings {
+            chunk_index,
+            offset,
+        };
+        match self.data.get(&settings) {
+            Some(bytes) => Ok(bytes.clone()),
+            None => Ok(vec![0; length as usize]),
+        }
     }
 
     fn remove(&mut self, chunk: &ChunkSettings, _len: u64) -> Result<(), Self::Error> {
         self.data.remove(chunk);
         Ok(())
     }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        self.syncs += 1;
+        Ok(())
+    }
 }
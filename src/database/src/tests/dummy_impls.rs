@@ -3,7 +3,7 @@ use thiserror::Error;
 use crate::{
     legacy_database::{
         self,
-        chunk::chunk_processor::ChunkCollectionProcessor,
+        chunk::{chunk_processor::ChunkCollectionProcessor, ChunkIndex},
         database::LegacyDatabaseError,
         index::{
             db_post_ref::{ChunkSettings, DbPostRef},
@@ -20,7 +20,7 @@ pub struct DummyChunkProcessor;
 impl ChunkCollectionProcessor for DummyChunkProcessor {
     type Error = DummyChunkProcessorError;
 
-    fn insert(&mut self, _post: &PostMessage) -> Result<ChunkSettings, Self::Error> {
+    fn insert(&mut self, _post: &[u8]) -> Result<ChunkSettings, Self::Error> {
         Ok(ChunkSettings {
             chunk_index: 0,
             offset: 0,
@@ -30,7 +30,7 @@ impl ChunkCollectionProcessor for DummyChunkProcessor {
     fn insert_into_existing(
         &mut self,
         _chunk: &ChunkSettings,
-        _post: &PostMessage,
+        _post: &[u8],
     ) -> Result<(), Self::Error> {
         Ok(())
     }
@@ -39,7 +39,28 @@ impl ChunkCollectionProcessor for DummyChunkProcessor {
         Ok(PostMessage::new("Msg".to_string()))
     }
 
-    fn remove(&mut self, chunk: &ChunkSettings, len: u64) -> Result<(), Self::Error> {
+    fn get_bytes(&self, _chunk: &ChunkSettings, _len: u64) -> Result<Vec<u8>, Self::Error> {
+        Ok(b"Msg".to_vec())
+    }
+
+    fn chunk_size(&self, _chunk_index: ChunkIndex) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+
+    fn read_range(
+        &self,
+        _chunk_index: ChunkIndex,
+        _offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, Self::Error> {
+        Ok(vec![0; length as usize])
+    }
+
+    fn remove(&mut self, _chunk: &ChunkSettings, _len: u64) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
         Ok(())
     }
 }
@@ -60,6 +81,52 @@ impl Diff for DummyDiff {
         Ok(())
     }
 
+    fn append_batch(
+        &mut self,
+        _refs: &[DbPostRefSerialized],
+    ) -> legacy_database::index::diff::DiffResult<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> legacy_database::index::diff::DiffResult<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> legacy_database::index::diff::DiffResult<u64> {
+        Ok(0)
+    }
+
+    fn drain() -> legacy_database::index::diff::DiffResult<(Self, Vec<DbPostRefSerialized>)> {
+        Ok((Self, Vec::new()))
+    }
+}
+
+/// Diff whose lines are written, but never synced to the disk
+pub struct UnsyncableDiff;
+impl Diff for UnsyncableDiff {
+    fn append(
+        &mut self,
+        _hashes: &legacy_database::index::serialized::PostHashes,
+        _db_ref: &DbPostRef,
+    ) -> legacy_database::index::diff::DiffResult<()> {
+        Ok(())
+    }
+
+    fn append_batch(
+        &mut self,
+        _refs: &[DbPostRefSerialized],
+    ) -> legacy_database::index::diff::DiffResult<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> legacy_database::index::diff::DiffResult<()> {
+        Err(std::io::Error::other("sync failed").into())
+    }
+
+    fn size(&self) -> legacy_database::index::diff::DiffResult<u64> {
+        Ok(0)
+    }
+
     fn drain() -> legacy_database::index::diff::DiffResult<(Self, Vec<DbPostRefSerialized>)> {
         Ok((Self, Vec::new()))
     }
@@ -45,7 +45,7 @@ macro_rules! assert_none {
     };
 }
 
-use std::{collections::HashMap, default, rc::Rc};
+use std::collections::HashMap;
 
 use crate::{
     legacy_database::index::{
@@ -60,7 +60,7 @@ pub use super::collecting_impls::*;
 pub use super::dummy_impls::*;
 
 pub fn rc(hash: &str) -> DbPostRefHash {
-    Rc::new(hash.to_string())
+    DbPostRefHash::new(hash)
 }
 
 pub fn some_ref(length: u64, parent: &str) -> DbPostRef {
@@ -136,5 +136,8 @@ pub fn collecting_chunk_processor() -> CollectingChunkProcessor {
     CollectingChunkProcessor {
         data: HashMap::new(),
         offset: 0,
+        capacity: None,
+        syncs: 0,
+        chunk_sizes: HashMap::new(),
     }
 }
@@ -5,7 +5,7 @@
 mod consts;
 mod converters;
 
-use std::{convert::TryInto, num::TryFromIntError, usize};
+use std::{convert::TryInto, num::TryFromIntError};
 use thiserror::Error;
 
 use consts::*;
@@ -34,12 +34,11 @@ pub type PngStegoResult<T> = Result<T, PngStegoError>;
 
 /// Allows you to hide byte data inside a provided image.
 /// **Warning!** According to the hiding algorithm, one pixel of the image can store 3 bits of data.
-
+///
 /// If your data can't fit into image, a [`PngStegoError`] will be returned.
 /// # Arguments
 /// * `img` - An RGB image.
 /// * `bytes` - Byte data which you need to hide
-
 pub fn hide_bytes(mut img: RgbImage, bytes: Vec<u8>) -> PngStegoResult<RgbImage> {
     let max_size = img.width() * img.height() * COLORS_COUNT;
     let data_size = (bytes.len() as u32 + BYTES_IN_I32) * 8;
@@ -54,8 +53,7 @@ pub fn hide_bytes(mut img: RgbImage, bytes: Vec<u8>) -> PngStegoResult<RgbImage>
 
     let pixels = img
         .pixels_mut()
-        .map(|p| &mut p.0)
-        .flatten()
+        .flat_map(|p| &mut p.0)
         .enumerate()
         .take_while(|(i, _)| i < &bits_length);
 
@@ -74,7 +72,7 @@ pub fn hide_bytes(mut img: RgbImage, bytes: Vec<u8>) -> PngStegoResult<RgbImage>
 /// # Arguments
 /// * `encoded_img` - An image with data.
 pub fn read_hidden_bytes(encoded_img: RgbImage) -> PngStegoResult<Vec<u8>> {
-    let pixels: Vec<&u8> = encoded_img.pixels().map(|p| &p.0).flatten().collect();
+    let pixels: Vec<&u8> = encoded_img.pixels().flat_map(|p| &p.0).collect();
     let encoded_data_length = get_encoded_data_length(&pixels)?;
     let encoded_data_bits = pixels
         .iter()